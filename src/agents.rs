@@ -1,14 +1,18 @@
 use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
 
 use crate::capabilities::Manifest;
-use crate::planner::{self, Plan};
-
-/// Trait for all agents in the system.
-pub trait Agent {
-    fn name(&self) -> &str;
-}
+use crate::models::TaskType;
+use crate::planner::{self, Action, Plan};
+use crate::runner::CommandRunner;
+use crate::task_ui::{TaskStatus, TaskUpdate};
+use crate::{fsutil, llm, pty};
 
 /// Planner agent chats with the user and produces a plan.
 pub struct PlannerAgent {
@@ -16,12 +20,12 @@ pub struct PlannerAgent {
 }
 
 impl PlannerAgent {
-    pub fn new(model: String) -> Self {
-        Self { model }
-    }
-
-    pub fn default() -> Self {
-        let model = std::env::var("MODEL_ID").unwrap_or_default();
+    /// A planner on the reasoning model (see `llm::model_for_task`), else
+    /// the session's.
+    pub fn new() -> Self {
+        let model = llm::model_for_task(TaskType::Reasoning)
+            .or_else(llm::model_id)
+            .unwrap_or_default();
         Self { model }
     }
 
@@ -31,30 +35,327 @@ impl PlannerAgent {
         user: &str,
         manifest: &Manifest,
     ) -> Result<Plan> {
-        planner::plan_changes(root, user, manifest).await
+        let model = Some(self.model.as_str()).filter(|m| !m.is_empty());
+        planner::plan_changes_with(root, user, manifest, model).await
     }
-}
 
-impl Agent for PlannerAgent {
-    fn name(&self) -> &str {
-        "planner"
+    /// Ask the code model (see `llm::model_for_task`, else this agent's model)
+    /// for a minimal unified diff fixing `error_msg`, given the working tree's
+    /// current `git diff`.
+    pub async fn generate_fix(&self, error_msg: &str) -> Result<String> {
+        let diff = Command::new("git")
+            .arg("diff")
+            .output()
+            .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+            .unwrap_or_default();
+        let model = llm::model_for_task(TaskType::Code)
+            .or_else(|| Some(self.model.clone()).filter(|m| !m.is_empty()));
+        llm::propose_patch_with(model.as_deref(), error_msg, &diff).await
     }
 }
 
-/// Worker agent placeholder.
-pub struct WorkerAgent {
-    pub model: String,
-    pub tools: Vec<String>,
+/// How long a single run action may take before it is killed.
+const ACTION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How much of an action's output is kept in its outcome.
+const ACTION_OUTPUT_BYTES: usize = 8 * 1024;
+
+/// What happened when a worker ran one plan action.
+#[derive(Debug, Clone)]
+pub struct ActionOutcome {
+    /// The command line, for display and transcripts.
+    pub command: String,
+    pub success: bool,
+    /// Exit code of the last attempt, when the command ran to completion.
+    pub exit_code: Option<i32>,
+    /// Tail of the last attempt's output (or the reason it couldn't run).
+    pub output: String,
+    pub attempts: u32,
+    /// Whether the action was cancelled before or while it ran.
+    pub cancelled: bool,
+}
+
+impl ActionOutcome {
+    /// Outcome of an action cancelled before it started.
+    fn cancelled(command: String) -> Self {
+        Self {
+            command,
+            success: false,
+            exit_code: None,
+            output: String::new(),
+            attempts: 0,
+            cancelled: true,
+        }
+    }
 }
 
+/// Worker agent executes the commands of a plan.
+pub struct WorkerAgent;
+
 impl WorkerAgent {
-    pub fn new(model: String, tools: Vec<String>) -> Self {
-        Self { model, tools }
+    /// Run each `run` and `shell` action in order, returning one outcome per
+    /// executed action; file actions are applied by the caller and skipped.
+    ///
+    /// `run` actions go through a PTY in their `workdir` (relative to `root`),
+    /// retried up to `retries` times with doubling `backoff_ms`. `shell`
+    /// actions go through `CommandRunner` in `root`.
+    ///
+    /// Reports `(index in actions, status, error)` on `updates` as each
+    /// action starts and finishes, e.g. for `task_ui::task_dashboard`. A
+    /// failed action carries its output as the error.
+    ///
+    /// `cancel[i]`, when present, cancels action `i`: it is skipped if it
+    /// hasn't started, and a running `run` action's process is killed. Shell
+    /// actions only honour it before they start. Cancelled actions still get
+    /// an outcome, marked `cancelled`.
+    pub fn run_with_updates(
+        &self,
+        root: &Path,
+        actions: &[Action],
+        updates: &Sender<TaskUpdate>,
+        cancel: &[Arc<AtomicBool>],
+    ) -> Result<Vec<ActionOutcome>> {
+        let mut outcomes = Vec::new();
+        for (i, action) in actions.iter().enumerate() {
+            let flag = cancel.get(i).map(Arc::as_ref);
+            let cancelled_early = flag.is_some_and(|f| f.load(Ordering::SeqCst));
+            let outcome = match action {
+                Action::Run { program, args, .. } if cancelled_early => {
+                    ActionOutcome::cancelled(command_line(program, args))
+                }
+                Action::Shell { command } if cancelled_early => {
+                    ActionOutcome::cancelled(command.clone())
+                }
+                Action::Run {
+                    program,
+                    args,
+                    workdir,
+                    retries,
+                    backoff_ms,
+                    ..
+                } => {
+                    // The receiver may be gone (dashboard closed); keep working.
+                    let _ = updates.send((i, TaskStatus::Running, None));
+                    let dir = match workdir {
+                        Some(w) => fsutil::resolve_within(root, w),
+                        None => Ok(root.to_path_buf()),
+                    };
+                    match dir {
+                        Ok(dir) => {
+                            let log_dir = root.join(".agent").join("logs");
+                            run_program(program, args, &dir, &log_dir, *retries, *backoff_ms, flag)
+                        }
+                        // Report it like any failed action so the dashboard
+                        // doesn't leave it running.
+                        Err(e) => ActionOutcome {
+                            command: command_line(program, args),
+                            success: false,
+                            exit_code: None,
+                            output: format!("{e:#}"),
+                            attempts: 0,
+                            cancelled: false,
+                        },
+                    }
+                }
+                Action::Shell { command } => {
+                    let _ = updates.send((i, TaskStatus::Running, None));
+                    let result = CommandRunner::new(0, 0).run_in(command, root);
+                    ActionOutcome {
+                        command: command.clone(),
+                        success: result.is_ok(),
+                        exit_code: None,
+                        output: result.unwrap_or_else(|e| e.to_string()),
+                        attempts: 1,
+                        cancelled: false,
+                    }
+                }
+                Action::Create { .. } | Action::Move { .. } => continue,
+            };
+            let update = if outcome.cancelled {
+                (i, TaskStatus::Cancelled, None)
+            } else if outcome.success {
+                (i, TaskStatus::Succeeded, None)
+            } else {
+                (i, TaskStatus::Failed, Some(outcome.output.clone()))
+            };
+            let _ = updates.send(update);
+            outcomes.push(outcome);
+        }
+        Ok(outcomes)
+    }
+}
+
+/// `program` and `args` as one command line, for display.
+fn command_line(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run `program` in a PTY, logging to `log_dir/<program>.log` and retrying failures with exponential back-off.
+/// Setting `cancel` kills the running attempt and stops further retries.
+fn run_program(
+    program: &str,
+    args: &[String],
+    dir: &Path,
+    log_dir: &Path,
+    retries: u32,
+    backoff_ms: u64,
+    cancel: Option<&AtomicBool>,
+) -> ActionOutcome {
+    let mut outcome = ActionOutcome {
+        command: command_line(program, args),
+        success: false,
+        exit_code: None,
+        output: String::new(),
+        attempts: 0,
+        cancelled: false,
+    };
+    let _ = std::fs::create_dir_all(log_dir);
+    let name = Path::new(program)
+        .file_name()
+        .map_or(program.into(), |n| n.to_string_lossy());
+    let log_path = log_dir.join(format!("{name}.log"));
+    loop {
+        outcome.attempts += 1;
+        let opts = pty::PtyOptions {
+            timeout: ACTION_TIMEOUT,
+            max_output_bytes: ACTION_OUTPUT_BYTES,
+            cancel,
+            ..pty::PtyOptions::new(&log_path)
+        };
+        let result = pty::run_with_pty(program, args, dir, &opts);
+        match result {
+            Ok(r) => {
+                outcome.cancelled = r.cancelled;
+                outcome.success = r.success();
+                outcome.exit_code = r.exit_code();
+                outcome.output = r.last_output_plain;
+                // e.g. a PTY read error that cut the output short.
+                if let Some(error) = r.error {
+                    outcome.output.push_str(&format!("\n[{error}]"));
+                }
+            }
+            // Refused by the PTY guardrails or couldn't start: retrying won't help.
+            Err(e) => {
+                outcome.output = format!("{e:#}");
+                return outcome;
+            }
+        }
+        if outcome.success || outcome.cancelled || outcome.attempts > retries {
+            return outcome;
+        }
+        let exp = (outcome.attempts - 1).min(16);
+        std::thread::sleep(Duration::from_millis(backoff_ms.saturating_mul(1 << exp)));
     }
 }
 
-impl Agent for WorkerAgent {
-    fn name(&self) -> &str {
-        "worker"
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{self, Reply, Server};
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn run_action(program: &str, args: &[&str]) -> Action {
+        Action::Run {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            workdir: None,
+            log_hint: None,
+            retries: 0,
+            backoff_ms: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn generate_fix_returns_the_proposed_patch() {
+        let patch =
+            "--- a/src/lib.rs\n+++ b/src/lib.rs\n@@ -1 +1 @@\n-fn f() {}\n+fn f() -> u8 { 0 }\n";
+        let server = Server::start(vec![Reply::stream(&["```diff\n", patch, "```"])]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let fix = PlannerAgent::new()
+            .generate_fix("error[E0308]: mismatched types")
+            .await
+            .unwrap();
+
+        assert_eq!(fix.trim_end(), patch.trim_end());
+        let sent = server.requests();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].body.contains("E0308"), "{}", sent[0].body);
+    }
+
+    #[test]
+    fn run_actions_report_success_and_their_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let agent = WorkerAgent;
+        let actions = [run_action("echo", &["hello from echo"])];
+
+        let outcomes = agent
+            .run_with_updates(dir.path(), &actions, &tx, &[])
+            .unwrap();
+        drop(tx);
+
+        assert_eq!(outcomes.len(), 1);
+        assert!(outcomes[0].success, "{:?}", outcomes[0]);
+        assert_eq!(outcomes[0].exit_code, Some(0));
+        assert!(outcomes[0].output.contains("hello from echo"));
+        let statuses: Vec<_> = rx.iter().map(|(_, status, _)| status).collect();
+        assert_eq!(statuses, [TaskStatus::Running, TaskStatus::Succeeded]);
+    }
+
+    #[test]
+    fn a_workdir_outside_the_root_fails_the_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let agent = WorkerAgent;
+        let mut action = run_action("echo", &["hi"]);
+        if let Action::Run { workdir, .. } = &mut action {
+            *workdir = Some("../elsewhere".into());
+        }
+
+        let outcomes = agent
+            .run_with_updates(dir.path(), &[action], &tx, &[])
+            .unwrap();
+        drop(tx);
+
+        assert!(!outcomes[0].success);
+        let statuses: Vec<_> = rx.iter().map(|(_, status, _)| status).collect();
+        assert_eq!(statuses, [TaskStatus::Running, TaskStatus::Failed]);
+    }
+
+    #[test]
+    fn cancelling_a_running_action_kills_its_process() {
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = mpsc::channel();
+        let flag = cancel.clone();
+        let watcher = std::thread::spawn(move || {
+            let mut statuses = Vec::new();
+            for (_, status, _) in rx {
+                if status == TaskStatus::Running {
+                    std::thread::sleep(Duration::from_millis(200));
+                    flag.store(true, Ordering::SeqCst);
+                }
+                statuses.push(status);
+            }
+            statuses
+        });
+
+        let start = Instant::now();
+        let agent = WorkerAgent;
+        let actions = [run_action("bash", &["-c", "sleep 30"])];
+        let outcomes = agent
+            .run_with_updates(dir.path(), &actions, &tx, &[cancel])
+            .unwrap();
+        drop(tx);
+
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(outcomes[0].cancelled);
+        assert!(!outcomes[0].success);
+        let statuses = watcher.join().unwrap();
+        assert_eq!(statuses.last(), Some(&TaskStatus::Cancelled));
     }
 }
@@ -1,6 +1,19 @@
+use console::style;
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use which::which;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config;
+
+/// Look `program` up on PATH, honoring a PATH set with `/env`.
+fn which(program: &str) -> which::Result<PathBuf> {
+    which::which_in(program, config::env_var("PATH").ok(), ".")
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Providers {
@@ -33,20 +46,289 @@ pub struct Tools {
     pub clippy: bool,
 }
 
+impl Providers {
+    /// Each provider with whether its credentials are present.
+    pub fn entries(&self) -> [(&'static str, bool); 4] {
+        [
+            ("openai", self.openai),
+            ("groq", self.groq),
+            ("anthropic", self.anthropic),
+            ("local", self.local),
+        ]
+    }
+}
+
+impl Tools {
+    /// Each tool with whether it was found.
+    pub fn entries(&self) -> [(&'static str, bool); 17] {
+        [
+            ("fs", self.fs),
+            ("cargo", self.cargo),
+            ("npm", self.npm),
+            ("bun", self.bun),
+            ("pnpm", self.pnpm),
+            ("yarn", self.yarn),
+            ("pytest", self.pytest),
+            ("go", self.go),
+            ("mvn", self.mvn),
+            ("git", self.git),
+            ("github", self.github),
+            ("rg", self.rg),
+            ("grep", self.grep),
+            ("prettier", self.prettier),
+            ("eslint", self.eslint),
+            ("rustfmt", self.rustfmt),
+            ("clippy", self.clippy),
+        ]
+    }
+
+    /// The flag for the tool called `name` in `entries` (`gh` is accepted for
+    /// `github`).
+    fn flag_mut(&mut self, name: &str) -> Option<&mut bool> {
+        Some(match name {
+            "fs" => &mut self.fs,
+            "cargo" => &mut self.cargo,
+            "npm" => &mut self.npm,
+            "bun" => &mut self.bun,
+            "pnpm" => &mut self.pnpm,
+            "yarn" => &mut self.yarn,
+            "pytest" => &mut self.pytest,
+            "go" => &mut self.go,
+            "mvn" => &mut self.mvn,
+            "git" => &mut self.git,
+            "github" | "gh" => &mut self.github,
+            "rg" => &mut self.rg,
+            "grep" => &mut self.grep,
+            "prettier" => &mut self.prettier,
+            "eslint" => &mut self.eslint,
+            "rustfmt" => &mut self.rustfmt,
+            "clippy" => &mut self.clippy,
+            _ => return None,
+        })
+    }
+}
+
+/// Comma-separated tools forced on (`SHELLCRAFT_TOOLS_ON`) or off
+/// (`SHELLCRAFT_TOOLS_OFF`) whatever detection found.
+const TOOLS_ON_ENV: &str = "SHELLCRAFT_TOOLS_ON";
+const TOOLS_OFF_ENV: &str = "SHELLCRAFT_TOOLS_OFF";
+
+/// Apply `SHELLCRAFT_TOOLS_ON` and then `SHELLCRAFT_TOOLS_OFF` to detected
+/// `tools`, so a tool listed in both ends up off. Unknown names are ignored.
+fn apply_tool_overrides(tools: &mut Tools) {
+    for (var, on) in [(TOOLS_ON_ENV, true), (TOOLS_OFF_ENV, false)] {
+        let Ok(list) = config::env_var(var) else {
+            continue;
+        };
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if let Some(flag) = tools.flag_mut(&name.to_ascii_lowercase()) {
+                *flag = on;
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Manifest {
     pub providers: Providers,
     pub tools: Tools,
+    /// Lockfiles found in the project root, one per ecosystem.
+    #[serde(default)]
+    pub lockfiles: Vec<LockfileStatus>,
+    /// Version reported by each detected tool, keyed by tool name.
+    #[serde(default)]
+    pub versions: BTreeMap<String, String>,
+}
+
+/// `--version` output per program, probed at most once per process.
+static VERSION_CACHE: Lazy<Mutex<HashMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static VERSION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\d+\.\d+(?:\.\d+)?(?:-[0-9A-Za-z.]+)?").unwrap());
+
+/// First semver-looking token in `text`, e.g. `1.78.0` from
+/// `cargo 1.78.0 (54d8815d0 2024-03-26)`.
+pub fn parse_version(text: &str) -> Option<String> {
+    VERSION_RE.find(text).map(|m| m.as_str().to_string())
+}
+
+/// Run `program arg` (normally `--version`) and parse the version it prints.
+/// Results are cached, so each program is spawned at most once.
+pub fn tool_version(program: &str, arg: &str) -> Option<String> {
+    if let Some(cached) = VERSION_CACHE.lock().unwrap().get(program) {
+        return cached.clone();
+    }
+    let version = Command::new(program)
+        .arg(arg)
+        .envs(config::env_overrides())
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| {
+            parse_version(&String::from_utf8_lossy(&out.stdout))
+                .or_else(|| parse_version(&String::from_utf8_lossy(&out.stderr)))
+        });
+    VERSION_CACHE
+        .lock()
+        .unwrap()
+        .insert(program.to_string(), version.clone());
+    version
+}
+
+/// Program and argument that print a tool's version; `None` for tools that
+/// aren't programs.
+fn version_probe(tool: &str) -> Option<(&str, &str)> {
+    match tool {
+        "fs" => None,
+        "github" => Some(("gh", "--version")),
+        "clippy" => Some(("cargo-clippy", "--version")),
+        "go" => Some(("go", "version")),
+        other => Some((other, "--version")),
+    }
+}
+
+/// Whether an ecosystem's lockfile has kept up with its manifest.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LockfileStatus {
+    pub ecosystem: String,
+    pub manifest: String,
+    pub lockfile: String,
+    /// The manifest was modified after the lockfile, so dependencies likely
+    /// need refreshing.
+    pub lockfile_stale: bool,
+}
+
+/// (ecosystem, manifest, lockfile, refresh command).
+const LOCKFILES: &[(&str, &str, &str, &str)] = &[
+    ("cargo", "Cargo.toml", "Cargo.lock", "cargo update"),
+    ("npm", "package.json", "package-lock.json", "npm install"),
+    ("pnpm", "package.json", "pnpm-lock.yaml", "pnpm install"),
+    ("yarn", "package.json", "yarn.lock", "yarn install"),
+    ("bun", "package.json", "bun.lockb", "bun install"),
+    ("go", "go.mod", "go.sum", "go mod tidy"),
+    ("poetry", "pyproject.toml", "poetry.lock", "poetry lock"),
+];
+
+/// Compare manifest and lockfile modification times for every ecosystem whose
+/// files are both present in `root`.
+pub fn lockfile_statuses(root: &Path) -> Vec<LockfileStatus> {
+    let mtime = |name: &str| root.join(name).metadata().and_then(|m| m.modified()).ok();
+    LOCKFILES
+        .iter()
+        .filter_map(|&(ecosystem, manifest, lockfile, _)| {
+            let (m, l) = (mtime(manifest)?, mtime(lockfile)?);
+            Some(LockfileStatus {
+                ecosystem: ecosystem.into(),
+                manifest: manifest.into(),
+                lockfile: lockfile.into(),
+                lockfile_stale: m > l,
+            })
+        })
+        .collect()
+}
+
+/// Whether `root/pyproject.toml` configures pytest (`[tool.pytest.ini_options]`)
+/// or is a Poetry project (`[tool.poetry]`), whose tests usually run with it.
+pub fn pyproject_uses_pytest(root: &Path) -> bool {
+    let Ok(text) = std::fs::read_to_string(root.join("pyproject.toml")) else {
+        return false;
+    };
+    let Ok(doc) = text.parse::<toml::Table>() else {
+        return false;
+    };
+    let tool = doc.get("tool").and_then(|t| t.as_table());
+    tool.is_some_and(|t| {
+        t.contains_key("poetry")
+            || t.get("pytest")
+                .and_then(|p| p.as_table())
+                .is_some_and(|p| p.contains_key("ini_options"))
+    })
+}
+
+/// How long detected tools are reused before `build_manifest` probes PATH again.
+const MANIFEST_CACHE_TTL: Duration = Duration::from_secs(60);
+
+struct CachedTools {
+    at: Instant,
+    tools: Tools,
+    versions: BTreeMap<String, String>,
+}
+
+/// Detected tools and versions per canonicalized project root.
+static MANIFEST_CACHE: Lazy<Mutex<HashMap<PathBuf, CachedTools>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Forget cached tool detection (and tool versions), e.g. after PATH changes.
+pub fn invalidate_manifest_cache() {
+    MANIFEST_CACHE.lock().unwrap().clear();
+    VERSION_CACHE.lock().unwrap().clear();
+}
+
+fn cached_tools(root: &Path) -> (Tools, BTreeMap<String, String>) {
+    let key = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    if let Some(hit) = MANIFEST_CACHE.lock().unwrap().get(&key) {
+        if hit.at.elapsed() < MANIFEST_CACHE_TTL {
+            return (hit.tools.clone(), hit.versions.clone());
+        }
+    }
+    let (tools, versions) = detect_tools(root);
+    MANIFEST_CACHE.lock().unwrap().insert(
+        key,
+        CachedTools {
+            at: Instant::now(),
+            tools: tools.clone(),
+            versions: versions.clone(),
+        },
+    );
+    (tools, versions)
 }
 
-pub fn build_manifest(_root: &Path) -> Manifest {
-    let openai = std::env::var("OPENAI_API_KEY").is_ok();
-    let groq = std::env::var("GROQ_API_KEY").is_ok();
-    let anthropic = std::env::var("ANTHROPIC_API_KEY").is_ok();
-    let local = std::env::var("LOCAL_MODEL").is_ok();
+fn detect_tools(root: &Path) -> (Tools, BTreeMap<String, String>) {
+    let tools = Tools {
+        fs: true,
+        cargo: which("cargo").is_ok(),
+        npm: which("npm").is_ok(),
+        pnpm: which("pnpm").is_ok(),
+        yarn: which("yarn").is_ok(),
+        // Poetry projects run pytest inside their venv, off PATH.
+        pytest: which("pytest").is_ok() || (pyproject_uses_pytest(root) && which("poetry").is_ok()),
+        go: which("go").is_ok(),
+        mvn: which("mvn").is_ok(),
+        git: which("git").is_ok(),
+        github: which("gh").is_ok(),
+        rg: which("rg").is_ok(),
+        grep: which("grep").is_ok(),
+        prettier: which("prettier").is_ok(),
+        eslint: which("eslint").is_ok(),
+        rustfmt: which("rustfmt").is_ok(),
+        clippy: which("cargo-clippy").is_ok(),
+        bun: which("bun").is_ok(),
+    };
+    let versions = tools
+        .entries()
+        .into_iter()
+        .filter(|(_, found)| *found)
+        .filter_map(|(name, _)| {
+            let (program, arg) = version_probe(name)?;
+            Some((name.to_string(), tool_version(program, arg)?))
+        })
+        .collect();
+    (tools, versions)
+}
 
-    let base_url = std::env::var("OPENAI_BASE_URL")
-        .or_else(|_| std::env::var("GROQ_BASE_URL"))
+/// Detect providers and tools for `root`. Tool detection is reused for
+/// `MANIFEST_CACHE_TTL`; see `invalidate_manifest_cache`. The
+/// `SHELLCRAFT_TOOLS_ON`/`SHELLCRAFT_TOOLS_OFF` overrides are read on every
+/// call.
+pub fn build_manifest(root: &Path) -> Manifest {
+    let openai = config::env_var("OPENAI_API_KEY").is_ok();
+    let groq = config::env_var("GROQ_API_KEY").is_ok();
+    let anthropic = config::env_var("ANTHROPIC_API_KEY").is_ok();
+    let local = config::env_var("LOCAL_MODEL").is_ok();
+
+    let base_url = config::env_var("OPENAI_BASE_URL")
+        .or_else(|_| config::env_var("GROQ_BASE_URL"))
         .unwrap_or_else(|_| {
             if groq {
                 "https://api.groq.com/openai/v1".to_string()
@@ -60,7 +342,11 @@ pub fn build_manifest(_root: &Path) -> Manifest {
     } else {
         "gpt-4o-mini"
     };
-    let model = std::env::var("MODEL_ID").unwrap_or_else(|_| default_model.to_string());
+    let model = crate::llm::model_id().unwrap_or_else(|| default_model.to_string());
+
+    let (mut tools, mut versions) = cached_tools(root);
+    apply_tool_overrides(&mut tools);
+    versions.retain(|name, _| tools.entries().iter().any(|(n, on)| n == name && *on));
 
     Manifest {
         providers: Providers {
@@ -71,25 +357,9 @@ pub fn build_manifest(_root: &Path) -> Manifest {
             model,
             base_url,
         },
-        tools: Tools {
-            fs: true,
-            cargo: which("cargo").is_ok(),
-            npm: which("npm").is_ok(),
-            pnpm: which("pnpm").is_ok(),
-            yarn: which("yarn").is_ok(),
-            pytest: which("pytest").is_ok(),
-            go: which("go").is_ok(),
-            mvn: which("mvn").is_ok(),
-            git: which("git").is_ok(),
-            github: which("gh").is_ok(),
-            rg: which("rg").is_ok(),
-            grep: which("grep").is_ok(),
-            prettier: which("prettier").is_ok(),
-            eslint: which("eslint").is_ok(),
-            rustfmt: which("rustfmt").is_ok(),
-            clippy: which("cargo-clippy").is_ok(),
-            bun: which("bun").is_ok(),
-        },
+        tools,
+        lockfiles: lockfile_statuses(root),
+        versions,
     }
 }
 
@@ -124,7 +394,6 @@ pub fn can_run(manifest: &Manifest, program: &str) -> (bool, Option<String>) {
 
 /// A short text the planner sees as capabilities preamble.
 pub fn system_preamble(manifest: &Manifest) -> String {
-    let t = &manifest.tools;
     let mut lines: Vec<String> = vec![
         "A file index listing project files is provided for a birds-eye view.".into(),
         "Use the `fs` capability for file operations:".into(),
@@ -134,28 +403,32 @@ pub fn system_preamble(manifest: &Manifest) -> String {
         "".into(),
         "You can also request actions to run other tools.\nEnabled tools:".into(),
     ];
-    let mut add = |name: &str, ok: bool| {
+    for (name, ok) in manifest.tools.entries() {
         if ok {
-            lines.push(format!("- {}", name));
+            match manifest.versions.get(name) {
+                Some(version) => lines.push(format!("- {} {}", name, version)),
+                None => lines.push(format!("- {}", name)),
+            }
         }
-    };
-    add("fs", t.fs);
-    add("cargo", t.cargo);
-    add("npm", t.npm);
-    add("bun", t.bun);
-    add("pnpm", t.pnpm);
-    add("yarn", t.yarn);
-    add("pytest", t.pytest);
-    add("go", t.go);
-    add("mvn", t.mvn);
-    add("git", t.git);
-    add("github", t.github);
-    add("rg", t.rg);
-    add("grep", t.grep);
-    add("prettier", t.prettier);
-    add("eslint", t.eslint);
-    add("rustfmt", t.rustfmt);
-    add("clippy", t.clippy);
+    }
+
+    if !manifest.lockfiles.is_empty() {
+        lines.push("\nLockfiles:".into());
+        for lock in &manifest.lockfiles {
+            if lock.lockfile_stale {
+                let refresh = LOCKFILES
+                    .iter()
+                    .find(|(eco, ..)| *eco == lock.ecosystem)
+                    .map_or("a dependency refresh", |(.., cmd)| *cmd);
+                lines.push(format!(
+                    "- {} is older than {}; suggest `{}` if dependencies matter",
+                    lock.lockfile, lock.manifest, refresh
+                ));
+            } else {
+                lines.push(format!("- {} is up to date", lock.lockfile));
+            }
+        }
+    }
 
     lines.push(format!(
         "\nLLM provider base_url = {}, model = {}",
@@ -163,3 +436,188 @@ pub fn system_preamble(manifest: &Manifest) -> String {
     ));
     lines.join("\n")
 }
+
+/// Machine-readable manifest for `/capabilities --json` and the `capabilities`
+/// RPC: providers, tools, lockfiles and detected versions, as serialized.
+pub fn to_json(manifest: &Manifest) -> serde_json::Value {
+    serde_json::to_value(manifest).unwrap_or(serde_json::Value::Null)
+}
+
+/// Human-readable manifest for `/capabilities`: providers and tools, with the
+/// missing ones dimmed.
+pub fn render(manifest: &Manifest) -> String {
+    let mut out = String::new();
+    let mut section = |title: &str, entries: &[(&str, bool)]| {
+        out.push_str(&format!("{}\n", style(title).bold()));
+        for (name, ok) in entries {
+            if *ok {
+                let label = match manifest.versions.get(*name) {
+                    Some(version) => format!("{name} {version}"),
+                    None => name.to_string(),
+                };
+                out.push_str(&format!("  {} {}\n", style("✔").green(), label));
+            } else {
+                out.push_str(&format!(
+                    "  {}\n",
+                    style(format!("✘ {name} (missing)")).dim()
+                ));
+            }
+        }
+    };
+    section("Providers", &manifest.providers.entries());
+    section("Tools", &manifest.tools.entries());
+    out.push_str(&format!(
+        "{} {} via {}\n",
+        style("Model:").bold(),
+        manifest.providers.model,
+        manifest.providers.base_url
+    ));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_reuse_cached_tools_until_invalidated() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().canonicalize().unwrap();
+        // Seed the cache with detection results no real probe would give, so
+        // only a cache hit can return them.
+        let versions = BTreeMap::from([("cargo".to_string(), "0.0.0-cached".to_string())]);
+        let tools = Tools {
+            cargo: true,
+            ..Tools::default()
+        };
+        MANIFEST_CACHE.lock().unwrap().insert(
+            key.clone(),
+            CachedTools {
+                at: Instant::now(),
+                tools,
+                versions,
+            },
+        );
+
+        let first = build_manifest(dir.path());
+        let second = build_manifest(dir.path());
+        assert_eq!(first.versions.get("cargo").unwrap(), "0.0.0-cached");
+        assert_eq!(second.versions, first.versions);
+
+        invalidate_manifest_cache();
+        assert!(!MANIFEST_CACHE.lock().unwrap().contains_key(&key));
+        let fresh = build_manifest(dir.path());
+        assert_ne!(
+            fresh.versions.get("cargo").map(String::as_str),
+            Some("0.0.0-cached")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tool_versions_are_parsed_from_the_tools_output_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let tool = dir.path().join("faketool");
+        let calls = dir.path().join("calls");
+        std::fs::write(
+            &tool,
+            format!(
+                "#!/bin/sh\necho run >> '{}'\necho 'faketool version 2.31.4 (build 7)'\n",
+                calls.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let probe = || tool_version(tool.to_str().unwrap(), "--version");
+
+        assert_eq!(probe().as_deref(), Some("2.31.4"));
+        assert_eq!(probe().as_deref(), Some("2.31.4"));
+        assert_eq!(std::fs::read_to_string(&calls).unwrap(), "run\n");
+    }
+
+    #[tokio::test]
+    async fn tool_overrides_win_over_detection_and_off_beats_on() {
+        let _lock = crate::llm::mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        config::set_env_override(TOOLS_ON_ENV, "cargo, Bun,no-such-tool");
+        config::set_env_override(TOOLS_OFF_ENV, "git,bun");
+        let manifest = build_manifest(dir.path());
+        config::set_env_override(TOOLS_ON_ENV, "");
+        config::set_env_override(TOOLS_OFF_ENV, "");
+
+        assert!(manifest.tools.cargo);
+        assert!(!manifest.tools.bun);
+        assert!(!manifest.tools.git);
+        assert!(!can_run(&manifest, "git").0);
+    }
+
+    #[test]
+    fn json_manifests_report_tools_as_booleans() {
+        for cargo in [true, false] {
+            let manifest = Manifest {
+                tools: Tools {
+                    cargo,
+                    ..Tools::default()
+                },
+                versions: BTreeMap::from([("cargo".to_string(), "1.80.0".to_string())]),
+                ..Manifest::default()
+            };
+
+            let json = to_json(&manifest);
+
+            assert_eq!(json["tools"]["cargo"], serde_json::Value::Bool(cargo));
+            assert_eq!(json["versions"]["cargo"], "1.80.0");
+        }
+    }
+
+    #[test]
+    fn lockfiles_older_than_their_manifest_are_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let touch = |name: &str, secs_ago: u64| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, "").unwrap();
+            let at = std::time::SystemTime::now() - std::time::Duration::from_secs(secs_ago);
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(at)
+                .unwrap();
+        };
+        let cargo_stale = || {
+            let statuses = lockfile_statuses(dir.path());
+            assert_eq!(statuses.len(), 1);
+            assert_eq!(statuses[0].ecosystem, "cargo");
+            statuses[0].lockfile_stale
+        };
+
+        touch("Cargo.lock", 60);
+        touch("Cargo.toml", 0);
+        assert!(cargo_stale());
+
+        touch("Cargo.toml", 120);
+        assert!(!cargo_stale());
+    }
+
+    #[test]
+    fn rendered_manifests_list_tools_under_their_section() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("Cargo.toml"), "[package]\n").unwrap();
+        let manifest = build_manifest(dir.path());
+
+        let shown = console::strip_ansi_codes(&render(&manifest)).into_owned();
+
+        let providers = shown.find("Providers").unwrap();
+        let tools = shown.find("Tools").unwrap();
+        let cargo = shown.find("cargo").unwrap();
+        assert!(providers < tools && tools < cargo, "{shown}");
+        let mark = if manifest.tools.cargo {
+            "✔ cargo"
+        } else {
+            "✘ cargo (missing)"
+        };
+        assert!(shown.contains(mark), "{shown}");
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+use crate::ui::ColorChoice;
+
+/// Startup options parsed from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Read requests from stdin and run each one in sequence.
+    pub batch: bool,
+    /// Emit machine-readable output (NDJSON results in batch mode).
+    pub json: bool,
+    /// Abort a batch at the first failing request.
+    pub stop_on_error: bool,
+    /// Limit how deep the file index recurses.
+    pub max_depth: Option<usize>,
+    /// Leave binary files out of the file index (`--skip-binary`).
+    pub skip_binary: bool,
+    /// Paths or globs the session is confined to (`--scope`, repeatable).
+    pub scope: Vec<String>,
+    /// Stop making LLM calls after this many tokens (`--token-budget`).
+    pub token_budget: Option<u64>,
+    /// When to color output (`--color=auto|always|never`).
+    pub color: ColorChoice,
+    /// Reuse LLM replies to identical prompts (`--cache`).
+    pub cache: bool,
+    /// Run this one request and exit (single-shot mode).
+    pub request: Option<String>,
+    /// Append a JSON record of every turn to this file (`--transcript`).
+    pub transcript: Option<PathBuf>,
+    /// Let plans change `.shellcraft/` and `models.json`.
+    pub allow_config_edits: bool,
+    /// Show each proposed file change and ask before writing it.
+    pub confirm: bool,
+    /// Show proposed changes as two columns (`--side-by-side`).
+    pub side_by_side: bool,
+    /// Don't back up files before overwriting them.
+    pub no_backup: bool,
+    /// Show what plans would change without writing files or running
+    /// commands (`--read-only`).
+    pub read_only: bool,
+    /// Answer JSON-RPC requests on stdin instead of starting the REPL.
+    pub serve: bool,
+    /// Refuse to start when `models.json` is invalid (`--strict-config`).
+    pub strict_config: bool,
+    /// Planner and pipeline commands to rerun, self-healing, whenever the
+    /// working tree changes (`--autonomous PLANNER PIPELINE`).
+    pub autonomous: Option<(String, String)>,
+    /// Ask before applying each self-healing patch (`--supervised`).
+    pub supervised: bool,
+    /// With `--autonomous`, compare file contents rather than modification
+    /// times to notice changes (`--watch-content`).
+    pub watch_content: bool,
+    /// Write runner logs under `.agent/logs` as JSON lines (`--log-json`).
+    pub log_json: bool,
+    /// Rotate a runner log once it reaches this many bytes (`--log-max-bytes`).
+    pub log_max_bytes: Option<u64>,
+    /// Rotated runner logs kept per task (`--log-keep`).
+    pub log_keep: Option<usize>,
+    /// With `--autonomous`, run nothing and write what would have run to
+    /// this file as JSON (`--dry-run-report`).
+    pub dry_run_report: Option<PathBuf>,
+    /// Extra programs the runner may start without confirmation (`--allow`,
+    /// repeatable).
+    pub allow: Vec<String>,
+}
+
+impl Options {
+    /// Parse flags from `args` (without the program name).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut opts = Options::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--batch" => opts.batch = true,
+                "--serve" => opts.serve = true,
+                "--json" => opts.json = true,
+                "--stop-on-error" => opts.stop_on_error = true,
+                "--cache" => opts.cache = true,
+                "--confirm" => opts.confirm = true,
+                "--side-by-side" => opts.side_by_side = true,
+                "--no-backup" => opts.no_backup = true,
+                "--read-only" => opts.read_only = true,
+                "--allow-config-edits" => opts.allow_config_edits = true,
+                "--strict-config" => opts.strict_config = true,
+                "--supervised" => opts.supervised = true,
+                "--watch-content" => opts.watch_content = true,
+                "--skip-binary" => opts.skip_binary = true,
+                "--log-json" => opts.log_json = true,
+                "--autonomous" => match (args.next(), args.next()) {
+                    (Some(planner), Some(pipeline)) => opts.autonomous = Some((planner, pipeline)),
+                    _ => bail!("--autonomous expects a planner and a pipeline command"),
+                },
+                "--max-depth" => {
+                    let v = args.next().unwrap_or_default();
+                    match v.parse() {
+                        Ok(n) => opts.max_depth = Some(n),
+                        Err(_) => bail!("--max-depth expects a number, got `{}`", v),
+                    }
+                }
+                "--log-max-bytes" => {
+                    let v = args.next().unwrap_or_default();
+                    match v.parse() {
+                        Ok(n) => opts.log_max_bytes = Some(n),
+                        Err(_) => bail!("--log-max-bytes expects a number, got `{}`", v),
+                    }
+                }
+                "--log-keep" => {
+                    let v = args.next().unwrap_or_default();
+                    match v.parse() {
+                        Ok(n) => opts.log_keep = Some(n),
+                        Err(_) => bail!("--log-keep expects a number, got `{}`", v),
+                    }
+                }
+                "--color" => opts.color = args.next().unwrap_or_default().parse()?,
+                s if s.starts_with("--color=") => opts.color = s["--color=".len()..].parse()?,
+                "--token-budget" => {
+                    let v = args.next().unwrap_or_default();
+                    match v.parse() {
+                        Ok(n) => opts.token_budget = Some(n),
+                        Err(_) => bail!("--token-budget expects a number, got `{}`", v),
+                    }
+                }
+                "--dry-run-report" => match args.next() {
+                    Some(v) if !v.is_empty() => opts.dry_run_report = Some(PathBuf::from(v)),
+                    _ => bail!("--dry-run-report expects a file path"),
+                },
+                "--transcript" => match args.next() {
+                    Some(v) if !v.is_empty() => opts.transcript = Some(PathBuf::from(v)),
+                    _ => bail!("--transcript expects a file path"),
+                },
+                "--allow" => match args.next() {
+                    Some(v) if !v.is_empty() => opts.allow.push(v),
+                    _ => bail!("--allow expects a program name"),
+                },
+                "--scope" => match args.next() {
+                    Some(v) if !v.is_empty() => opts.scope.push(v),
+                    _ => bail!("--scope expects a path or glob"),
+                },
+                other if other.starts_with('-') => {
+                    bail!("unknown argument `{}`\n{}", other, USAGE)
+                }
+                _ => match &mut opts.request {
+                    Some(request) => {
+                        request.push(' ');
+                        request.push_str(&arg);
+                    }
+                    None => opts.request = Some(arg),
+                },
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// Split batch input into requests. Requests are one per line, unless the
+/// input contains blank lines, in which case blank lines separate multi-line
+/// requests.
+pub fn parse_batch(input: &str) -> Vec<String> {
+    let paragraphs = input.trim().lines().any(|l| l.trim().is_empty());
+    if !paragraphs {
+        return input
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    let mut out = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for line in input.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                out.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line.trim_end());
+        }
+    }
+    if !current.is_empty() {
+        out.push(current.join("\n"));
+    }
+    out
+}
+
+pub const USAGE: &str = r#"Usage: shellcraft [options] [REQUEST]
+  REQUEST           run this one request and exit (exit codes: see README)
+  --batch           read requests from stdin (one per line, or blank-line separated)
+  --serve           speak JSON-RPC over stdin/stdout for editor integrations
+  --json            print one JSON result per request in batch mode
+  --stop-on-error   stop the batch at the first failing request
+  --confirm         ask before applying each proposed file change
+  --side-by-side    show proposed changes in two columns instead of a unified diff
+  --no-backup       don't keep prior versions in .shellcraft/backups
+  --read-only       propose and show changes, but never write files or run commands
+  --cache           reuse replies to identical prompts (kept in .shellcraft/cache)
+  --allow-config-edits  let plans change .shellcraft/ and models.json
+  --strict-config   exit if models.json names unknown providers or models
+  --autonomous PLANNER PIPELINE  rerun both commands on every change, fixing failures with LLM patches
+  --supervised      with --autonomous, ask before applying each patch
+  --watch-content   with --autonomous, rerun only when file contents change, not just mtimes
+  --log-json        write runner logs in .agent/logs as JSON lines (or SHELLCRAFT_LOG_JSON=1)
+  --log-max-bytes N rotate a runner log to <task>.log.1 once it reaches N bytes (default 5 MiB)
+  --log-keep N      keep N rotated logs per task (default 3)
+  --dry-run-report FILE  with --autonomous, run nothing and write what would have run to FILE as JSON
+  --allow PROGRAM   let tools and --autonomous run PROGRAM like the built-in allowlist (repeatable)
+  --max-depth N     limit how deep the file index recurses (default 32)
+  --skip-binary     leave binary files (images, archives, objects) out of the file index
+  --color WHEN      color output: auto (default; honors NO_COLOR), always, never
+  --token-budget N  refuse further LLM calls once N tokens are spent
+  --transcript FILE append a JSON record of each turn to FILE (see /replay)
+  --scope PATH      only plan, read and edit within PATH (a path or glob; repeatable)"#;
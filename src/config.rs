@@ -0,0 +1,294 @@
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env::VarError;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use crate::fsutil::{self, glob_match};
+use crate::llm::PriceTable;
+use crate::models;
+use crate::planner::{DEFAULT_MAX_ACTION_ARGS, DEFAULT_MAX_ACTION_ARG_BYTES};
+
+/// Project settings read from `.shellcraft/config.toml`. Every section and key
+/// is optional; anything missing keeps its default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub edit: EditConfig,
+    /// `[prices."<model id>"]` — `input_per_1k`/`output_per_1k` in USD, used to
+    /// estimate spend.
+    pub prices: PriceTable,
+    pub actions: ActionsConfig,
+}
+
+/// `[actions]` — limits on the commands a plan may run.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ActionsConfig {
+    /// Actions with more arguments than this are dropped.
+    pub max_args: usize,
+    /// Actions whose arguments total more bytes than this are dropped.
+    pub max_arg_bytes: usize,
+}
+
+impl Default for ActionsConfig {
+    fn default() -> Self {
+        Self {
+            max_args: DEFAULT_MAX_ACTION_ARGS,
+            max_arg_bytes: DEFAULT_MAX_ACTION_ARG_BYTES,
+        }
+    }
+}
+
+/// `[edit]` — which files the model is allowed to rewrite, and how it samples
+/// the rewritten content.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EditConfig {
+    /// Glob patterns of editable files. Empty means "anything not denied".
+    pub allow: Vec<String>,
+    /// Glob patterns of files that are never edited.
+    pub deny: Vec<String>,
+    /// Sampling temperature for edits; a per-request value takes precedence.
+    pub temperature: Option<f32>,
+    /// Nucleus sampling cutoff for edits; a per-request value takes precedence.
+    pub top_p: Option<f32>,
+    /// How many edits of one plan are proposed by the model at once.
+    pub concurrency: usize,
+    /// Files with more lines than this are edited one region at a time (0 = never).
+    pub chunk_lines: usize,
+}
+
+impl Default for EditConfig {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec!["*.lock".into(), "*.min.*".into(), "*.map".into()],
+            temperature: None,
+            top_p: None,
+            concurrency: 4,
+            chunk_lines: 400,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(root: &Path) -> Self {
+        let path = root.join(".shellcraft").join("config.toml");
+        let Ok(data) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        toml::from_str(&data).unwrap_or_else(|e| {
+            eprintln!("warning: ignoring invalid {}: {e}", path.display());
+            Self::default()
+        })
+    }
+}
+
+static ALLOW_CONFIG_EDITS: AtomicBool = AtomicBool::new(false);
+
+/// Let plans change shellcraft's own control files (`--allow-config-edits`).
+pub fn set_allow_config_edits(allow: bool) {
+    ALLOW_CONFIG_EDITS.store(allow, Ordering::Relaxed);
+}
+
+/// Whether `path` is one of shellcraft's own control files: anything under
+/// `.shellcraft/`, `models.json`, or the file named by `MODEL_CONFIG`.
+fn is_control_file(path: &str) -> bool {
+    let path = path.trim_start_matches("./");
+    let model_config = models::config_path();
+    path == ".shellcraft"
+        || path.starts_with(".shellcraft/")
+        || path == "models.json"
+        || path == model_config.trim_start_matches("./")
+}
+
+/// Why `path` may not be changed or deleted because it's part of shellcraft's
+/// configuration, or `None` if it isn't (or config edits are allowed).
+pub fn protection(path: &str) -> Option<String> {
+    if ALLOW_CONFIG_EDITS.load(Ordering::Relaxed) || !is_control_file(path) {
+        return None;
+    }
+    Some("is shellcraft's own configuration (pass --allow-config-edits to change it)".into())
+}
+
+impl EditConfig {
+    /// Why `path` may not be edited, or `None` if edits are allowed.
+    pub fn denial(&self, path: &str) -> Option<String> {
+        if let Some(why) = protection(path) {
+            return Some(why);
+        }
+        if let Some(pat) = self.deny.iter().find(|p| glob_match(p, path)) {
+            return Some(format!("matches edit deny pattern `{}`", pat));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|p| glob_match(p, path)) {
+            return Some("not in the edit allowlist".into());
+        }
+        None
+    }
+}
+
+/// Variables saved with `/env`, one `KEY=VAL` per line.
+fn env_file(root: &Path) -> std::path::PathBuf {
+    root.join(".shellcraft").join("env")
+}
+
+/// Parse `KEY=VAL` as typed after `/env`.
+pub fn parse_env_assignment(input: &str) -> Result<(String, String)> {
+    let Some((key, value)) = input.split_once('=') else {
+        bail!("expected KEY=VAL, e.g. `/env MODEL_ID=gpt-4o-mini`");
+    };
+    let key = key.trim();
+    let valid = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!("`{}` is not a valid variable name", key);
+    }
+    Ok((key.to_string(), value.trim().to_string()))
+}
+
+/// Variables set with `/env` or loaded from `.shellcraft/env`. They are kept
+/// here rather than in the process environment, which other threads may be
+/// reading at the same time.
+static ENV_OVERRIDES: Lazy<RwLock<BTreeMap<String, String>>> = Lazy::new(Default::default);
+
+/// `key` as set with `/env`, else from the environment. Use this instead of
+/// `std::env::var` for anything `/env` may set.
+pub fn env_var(key: &str) -> Result<String, VarError> {
+    match ENV_OVERRIDES.read().unwrap().get(key) {
+        Some(value) => Ok(value.clone()),
+        None => std::env::var(key),
+    }
+}
+
+/// Every variable set with `/env`, for the environment of child processes.
+pub fn env_overrides() -> Vec<(String, String)> {
+    let overrides = ENV_OVERRIDES.read().unwrap();
+    overrides
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// Set `key` for this session (and its child processes) without saving it.
+pub fn set_env_override(key: &str, value: &str) {
+    ENV_OVERRIDES
+        .write()
+        .unwrap()
+        .insert(key.to_string(), value.to_string());
+}
+
+/// Whether `key` names a credential, whose value is better kept out of files.
+pub fn is_secret_env(key: &str) -> bool {
+    key.ends_with("_API_KEY")
+}
+
+/// Set `key` for this session and save it to `.shellcraft/env`, replacing any
+/// earlier value. The file is readable by its owner only.
+pub fn persist_env(root: &Path, key: &str, value: &str) -> Result<()> {
+    let path = env_file(root);
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let mut lines: Vec<String> = existing
+        .lines()
+        .filter(|l| l.split_once('=').map(|(k, _)| k.trim()) != Some(key))
+        .map(str::to_string)
+        .collect();
+    lines.push(format!("{key}={value}"));
+    fsutil::atomic_write(&path, &(lines.join("\n") + "\n"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    }
+    set_env_override(key, value);
+    Ok(())
+}
+
+/// Apply the variables saved in `.shellcraft/env`. Variables already set in
+/// the environment win.
+pub fn load_env(root: &Path) {
+    let Ok(data) = fs::read_to_string(env_file(root)) else {
+        return;
+    };
+    let mut overrides = ENV_OVERRIDES.write().unwrap();
+    for line in data.lines().filter(|l| !l.trim().is_empty()) {
+        match parse_env_assignment(line) {
+            Ok((key, value)) if std::env::var_os(&key).is_none() => {
+                overrides.insert(key, value);
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("warning: ignoring `{line}` in .shellcraft/env: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_assignments_are_parsed() {
+        let (key, value) = parse_env_assignment(" MODEL_ID = gpt-4o-mini ").unwrap();
+        assert_eq!((key.as_str(), value.as_str()), ("MODEL_ID", "gpt-4o-mini"));
+        let (_, value) = parse_env_assignment("URL=http://x/?a=b").unwrap();
+        assert_eq!(value, "http://x/?a=b");
+
+        let err = parse_env_assignment("MODEL_ID").unwrap_err();
+        assert!(err.to_string().contains("expected KEY=VAL"), "{err}");
+        assert!(parse_env_assignment("1ABC=x").is_err());
+        assert!(parse_env_assignment("A-B=x").is_err());
+        assert!(parse_env_assignment("=x").is_err());
+    }
+
+    #[test]
+    fn persisted_variables_are_applied_and_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = "SHELLCRAFT_TEST_PERSISTED";
+        persist_env(dir.path(), key, "one").unwrap();
+        persist_env(dir.path(), key, "two").unwrap();
+
+        assert_eq!(env_var(key).unwrap(), "two");
+        assert!(std::env::var_os(key).is_none());
+        assert!(env_overrides().contains(&(key.to_string(), "two".to_string())));
+        let saved = fs::read_to_string(env_file(dir.path())).unwrap();
+        assert_eq!(saved, format!("{key}=two\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(env_file(dir.path()))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn secrets_are_recognized() {
+        assert!(is_secret_env("OPENAI_API_KEY"));
+        assert!(!is_secret_env("MODEL_ID"));
+    }
+
+    #[test]
+    fn config_edits_are_refused_unless_allowed() {
+        let edit = EditConfig::default();
+        for path in [
+            "models.json",
+            ".shellcraft/config.toml",
+            "./.shellcraft/env",
+        ] {
+            let why = edit.denial(path).unwrap_or_default();
+            assert!(why.contains("--allow-config-edits"), "{path}: {why}");
+        }
+        assert_eq!(edit.denial("src/models.rs"), None);
+
+        set_allow_config_edits(true);
+        let allowed = edit.denial("models.json");
+        set_allow_config_edits(false);
+        assert_eq!(allowed, None);
+    }
+}
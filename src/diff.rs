@@ -1,9 +1,19 @@
 use console::style;
-use similar::{ChangeTag, TextDiff};
+use similar::{ChangeTag, DiffTag, TextDiff};
 
 /// Render a unified, colorized diff between `old` and `new` for display in the
 /// terminal. `rel_path` is only used in the header lines.
 pub fn unified_colored(old: &str, new: &str, rel_path: &str) -> String {
+    unified_colored_with_context(old, new, rel_path, 3)
+}
+
+/// `unified_colored` showing `context` unchanged lines around each change.
+pub fn unified_colored_with_context(
+    old: &str,
+    new: &str,
+    rel_path: &str,
+    context: usize,
+) -> String {
     let diff = TextDiff::from_lines(old, new);
     let mut out = String::new();
 
@@ -11,15 +21,16 @@ pub fn unified_colored(old: &str, new: &str, rel_path: &str) -> String {
     out.push_str(&format!("{}--- a/{}\n", style(" ").on_blue(), rel_path));
     out.push_str(&format!("{}+++ b/{}\n", style(" ").on_green(), rel_path));
 
-    for block in diff.grouped_ops(3) {
-        // Compute a rough hunk header based on new ranges without moving `block`
-        let (mut min, mut max) = (usize::MAX, 0usize);
-        for op in &block {
-            min = min.min(op.new_range().start);
-            max = max.max(op.new_range().end);
-        }
-        let len = max.saturating_sub(min);
-        out.push_str(&format!("@@ -{},{} +{},{} @@{}\n", min, len, min, len, style(" ").on_magenta()));
+    for block in diff.grouped_ops(context) {
+        // Ops in a group are contiguous, so each side's range runs from the
+        // first op's start to the last op's end.
+        let (first, last) = (&block[0], &block[block.len() - 1]);
+        let old_range = hunk_range(first.old_range().start, last.old_range().end);
+        let new_range = hunk_range(first.new_range().start, last.new_range().end);
+        out.push_str(&format!(
+            "@@ -{old_range} +{new_range} @@{}\n",
+            style(" ").on_magenta()
+        ));
 
         // Iterate again by reference so we don't move `block`
         for op in &block {
@@ -72,3 +83,167 @@ pub fn unified_colored(old: &str, new: &str, rel_path: &str) -> String {
 
     out
 }
+
+/// `start,len` for a hunk header from a 0-based line range. Lines count from
+/// 1, except that an empty range names the line before it.
+fn hunk_range(start: usize, end: usize) -> String {
+    let len = end - start;
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+/// Render the changes between `old` and `new` as two aligned columns, each
+/// `width` characters wide: old lines on the left, new lines on the right.
+/// Deletions are red, insertions green, and long lines wrap within their column.
+pub fn side_by_side(old: &str, new: &str, rel_path: &str, width: usize) -> String {
+    let width = width.max(8);
+    let diff = TextDiff::from_lines(old, new);
+    let (old_lines, new_lines) = (diff.old_slices(), diff.new_slices());
+
+    let left_title = truncate_chars(&format!("--- a/{rel_path}"), width);
+    let mut out = format!(
+        "{} │ {}\n",
+        style(format!("{left_title:<width$}")).bold(),
+        style(truncate_chars(&format!("+++ b/{rel_path}"), width)).bold(),
+    );
+    for (i, group) in diff.grouped_ops(3).iter().enumerate() {
+        if i > 0 {
+            out.push_str(&format!("{}\n", style(format!("{:<width$} ┊", "┈")).dim()));
+        }
+        for op in group {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            let rows = old_range.len().max(new_range.len());
+            for row in 0..rows {
+                let left = (row < old_range.len()).then(|| old_lines[old_range.start + row]);
+                let right = (row < new_range.len()).then(|| new_lines[new_range.start + row]);
+                push_row(&mut out, left, right, tag, width);
+            }
+        }
+    }
+    out
+}
+
+/// Append one line pair, wrapped to `width`. A missing side stays blank.
+fn push_row(out: &mut String, left: Option<&str>, right: Option<&str>, tag: DiffTag, width: usize) {
+    let wrap = |line: Option<&str>| -> Vec<String> {
+        let line = line.unwrap_or("").trim_end_matches(['\r', '\n']);
+        let line = line.replace('\t', "    ");
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            return vec![String::new()];
+        }
+        chars.chunks(width).map(|c| c.iter().collect()).collect()
+    };
+    let (l, r) = (wrap(left), wrap(right));
+    for i in 0..l.len().max(r.len()) {
+        let a = format!("{:<width$}", l.get(i).map(String::as_str).unwrap_or(""));
+        let b = r.get(i).map(String::as_str).unwrap_or("");
+        let (a, b) = match tag {
+            DiffTag::Equal => (style(a), style(b.to_string())),
+            _ => (style(a).red(), style(b.to_string()).green()),
+        };
+        out.push_str(&format!("{a} │ {b}\n"));
+    }
+}
+
+/// Lay out two texts in columns `width` characters wide, each headed by its
+/// title. Long lines wrap within their column.
+pub fn side_by_side_text(
+    left_title: &str,
+    left: &str,
+    right_title: &str,
+    right: &str,
+    width: usize,
+) -> String {
+    let width = width.max(8);
+    let wrap = |text: &str| -> Vec<String> {
+        let mut rows = Vec::new();
+        for line in text.lines() {
+            let chars: Vec<char> = line.chars().collect();
+            if chars.is_empty() {
+                rows.push(String::new());
+            }
+            for chunk in chars.chunks(width) {
+                rows.push(chunk.iter().collect());
+            }
+        }
+        rows
+    };
+    let (l, r) = (wrap(left), wrap(right));
+
+    let mut out = format!(
+        "{} │ {}\n{}─┼─{}\n",
+        style(format!("{:<width$}", truncate_chars(left_title, width))).bold(),
+        style(truncate_chars(right_title, width)).bold(),
+        "─".repeat(width),
+        "─".repeat(width),
+    );
+    for i in 0..l.len().max(r.len()) {
+        let a = l.get(i).map(String::as_str).unwrap_or("");
+        let b = r.get(i).map(String::as_str).unwrap_or("");
+        out.push_str(&format!("{:<width$} │ {}\n", a, b));
+    }
+    out
+}
+
+fn truncate_chars(s: &str, max: usize) -> String {
+    s.chars().take(max).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(lines: usize) -> String {
+        (1..=lines).map(|n| format!("line {n}\n")).collect()
+    }
+
+    fn plain_diff(old: &str, new: &str, context: usize) -> String {
+        let out = unified_colored_with_context(old, new, "a.txt", context);
+        console::strip_ansi_codes(&out).into_owned()
+    }
+
+    #[test]
+    fn context_sets_the_unchanged_lines_around_a_change() {
+        let old = numbered(20);
+        let new = old.replace("line 10\n", "changed\n");
+
+        let narrow = plain_diff(&old, &new, 1);
+        let wide = plain_diff(&old, &new, 5);
+
+        // Two file headers, one hunk header, the change, then the context.
+        assert_eq!(narrow.lines().count(), 3 + 2 + 2, "{narrow}");
+        assert_eq!(wide.lines().count(), 3 + 2 + 10, "{wide}");
+    }
+
+    #[test]
+    fn hunk_headers_give_each_sides_own_range() {
+        let old = numbered(20);
+        let new = old.replace("line 10\n", "line 10\ninserted\n");
+
+        let out = plain_diff(&old, &new, 3);
+
+        assert!(out.contains("@@ -8,6 +8,7 @@"), "{out}");
+    }
+
+    #[test]
+    fn side_by_side_pairs_changed_lines_within_the_width() {
+        let old = "same\nold value\n";
+        let new = "same\nnew value that is long enough to wrap\nadded\n";
+        let out = console::strip_ansi_codes(&side_by_side(old, new, "a.txt", 12)).to_string();
+        let rows: Vec<(&str, &str)> = out
+            .lines()
+            .filter_map(|line| line.split_once(" │ "))
+            .collect();
+
+        assert!(rows.contains(&("old value   ", "new value th")));
+        assert!(rows.contains(&("            ", "added")));
+        for (left, right) in &rows {
+            assert_eq!(left.chars().count(), 12, "{left:?}");
+            assert!(right.chars().count() <= 12, "{right:?}");
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use anyhow::{bail, Context, Result};
-use std::{env, fs, io::Write, process::Command, thread, time::Duration};
+use std::{env, fs, io::Write, path::Path, process::Command, time::Duration};
 use tempfile::NamedTempFile;
 use which::which;
 
@@ -61,9 +61,8 @@ pub fn open_in_editor(initial: &str, _hint_name: &str) -> Result<String> {
     Ok(edited)
 }
 
-/// `/ignore` support — appends patterns to .gitignore (deduplicated).
-#[allow(dead_code)]
-pub fn handle_ignore_command(arg_str: &str) -> Result<()> {
+/// `/ignore` support — appends patterns to `root`'s .gitignore (deduplicated).
+pub async fn handle_ignore_command(root: &Path, arg_str: &str) -> Result<()> {
     if is_dry_run() {
         return Ok(());
     }
@@ -72,22 +71,70 @@ pub fn handle_ignore_command(arg_str: &str) -> Result<()> {
         bail!("no ignore patterns provided");
     }
     let refs: Vec<&str> = patterns.iter().map(|s| s.as_str()).collect();
-    fsutil::merge_ignore_patterns(&refs);
-    Ok(())
+    fsutil::merge_ignore_patterns(root, &refs).await
+}
+
+/// Interpreted languages `execute_code` can run: (names, program + leading
+/// args, source file extension).
+const INTERPRETERS: &[(&[&str], &[&str], &str)] = &[
+    (&["python", "python3", "py"], &["python3"], "py"),
+    (&["node", "javascript", "js"], &["node"], "js"),
+    (&["go", "golang"], &["go", "run"], "go"),
+    (&["ruby", "rb"], &["ruby"], "rb"),
+    (&["bash"], &["bash"], "sh"),
+    (&["sh", "shell"], &["sh"], "sh"),
+];
+
+/// Language named by a first-line marker such as `// lang: python` or
+/// `# lang: ruby`.
+fn lang_marker(first_line: &str) -> Option<&str> {
+    let rest = first_line
+        .trim()
+        .strip_prefix("//")
+        .or_else(|| first_line.trim().strip_prefix('#'))?;
+    let lang = rest.trim().strip_prefix("lang:")?.trim();
+    (!lang.is_empty()).then_some(lang)
 }
 
-/// Execute ad-hoc code snippets or files (shebang or quick Rust).
-pub fn execute_code(code: &str) -> Result<String, std::io::Error> {
+/// Execute ad-hoc code snippets or files and return combined stdout/stderr.
+///
+/// The language comes from `lang`, else a first-line `lang:` marker, else a
+/// shebang line; anything else is compiled as Rust with `rustc`.
+pub fn execute_code(code: &str, lang: Option<&str>) -> Result<String, std::io::Error> {
     if is_dry_run() {
         return Ok(String::new());
     }
 
     let dir = tempfile::tempdir()?;
+    let first_line = code.lines().next().unwrap_or("");
+
+    let lang = lang
+        .or_else(|| lang_marker(first_line))
+        .map(|l| l.to_ascii_lowercase());
+    if let Some(lang) = lang.as_deref().filter(|l| !matches!(*l, "rust" | "rs")) {
+        let Some((_, command, ext)) = INTERPRETERS
+            .iter()
+            .find(|(names, _, _)| names.contains(&lang))
+        else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unsupported language: {lang}"),
+            ));
+        };
+        let src_path = dir.path().join(format!("code.{ext}"));
+        fs::write(&src_path, code)?;
+        let output = std::process::Command::new(command[0])
+            .args(&command[1..])
+            .arg(&src_path)
+            .current_dir(dir.path())
+            .output()?;
+        return Ok(combined_output(&output));
+    }
+
     let src_path = dir.path().join("code.tmp");
     fs::write(&src_path, code)?;
 
-    let first_line = code.lines().next().unwrap_or("");
-    if first_line.starts_with("#!") {
+    if lang.is_none() && first_line.starts_with("#!") {
         let interpreter_line = first_line[2..].trim();
         let mut parts = interpreter_line.split_whitespace();
         let interpreter = match parts.next() {
@@ -101,10 +148,7 @@ pub fn execute_code(code: &str) -> Result<String, std::io::Error> {
             .arg(&src_path)
             .output()?;
 
-        let mut combined = String::new();
-        combined.push_str(&String::from_utf8_lossy(&output.stdout));
-        combined.push_str(&String::from_utf8_lossy(&output.stderr));
-        return Ok(combined);
+        return Ok(combined_output(&output));
     }
 
     let bin_name = if cfg!(windows) { "code_bin.exe" } else { "code_bin" };
@@ -117,49 +161,98 @@ pub fn execute_code(code: &str) -> Result<String, std::io::Error> {
         .output()?;
 
     if !compile_output.status.success() {
-        let mut combined = String::new();
-        combined.push_str(&String::from_utf8_lossy(&compile_output.stdout));
-        combined.push_str(&String::from_utf8_lossy(&compile_output.stderr));
-        return Ok(combined);
+        return Ok(combined_output(&compile_output));
     }
 
     let run_output = std::process::Command::new(&bin_path).output()?;
+    Ok(combined_output(&run_output))
+}
+
+fn combined_output(output: &std::process::Output) -> String {
     let mut combined = String::new();
-    combined.push_str(&String::from_utf8_lossy(&run_output.stdout));
-    combined.push_str(&String::from_utf8_lossy(&run_output.stderr));
-    Ok(combined)
+    combined.push_str(&String::from_utf8_lossy(&output.stdout));
+    combined.push_str(&String::from_utf8_lossy(&output.stderr));
+    combined
 }
 
-/// Apply a unified diff patch using the `patch` command.
+/// Apply a unified diff patch using the `patch` command, falling back to
+/// `git apply` when `patch` is missing or rejects the diff.
 pub fn apply_patch(patch: &str) -> Result<()> {
     if is_dry_run() {
         return Ok(());
     }
+    let cwd = env::current_dir().context("reading current directory")?;
+    apply_patch_in(patch, &cwd, |program| which(program).is_ok())
+}
 
+/// `apply_patch` to the files under `cwd`, using only the programs for which
+/// `installed` returns true.
+fn apply_patch_in(patch: &str, cwd: &Path, installed: impl Fn(&str) -> bool) -> Result<()> {
     let mut tmp = NamedTempFile::new().context("creating temporary file for patch")?;
     tmp.write_all(patch.as_bytes())
         .context("writing patch to temporary file")?;
     let patch_path = tmp.path();
+    let strip = format!("-p{}", patch_strip_level(patch));
 
-    let status = Command::new("patch")
-        .arg("-p0")
-        .arg("-i")
-        .arg(patch_path)
-        .status()
-        .context("executing patch command")?;
+    let mut failures = Vec::new();
+    if installed("patch") {
+        let status = Command::new("patch")
+            .current_dir(cwd)
+            .arg(&strip)
+            .arg("-i")
+            .arg(patch_path)
+            .status()
+            .context("executing patch command")?;
+        if status.success() {
+            return Ok(());
+        }
+        failures.push(format!("patch failed with status: {}", status));
+    }
+    if installed("git") {
+        let status = Command::new("git")
+            .current_dir(cwd)
+            .arg("apply")
+            .arg(&strip)
+            .arg(patch_path)
+            .status()
+            .context("executing git apply")?;
+        if status.success() {
+            return Ok(());
+        }
+        failures.push(format!("git apply failed with status: {}", status));
+    }
 
-    if !status.success() {
-        bail!("patch command failed with status: {}", status);
+    if failures.is_empty() {
+        bail!("cannot apply patch: neither `patch` nor `git` is installed");
     }
-    Ok(())
+    bail!("{}", failures.join("; "))
 }
 
-/// Simple self-healing loop that retries a task, optionally applying patches in-between.
-#[allow(dead_code)]
-pub fn run_with_fixer<F, G>(mut task: F, max_attempts: usize, propose_patch: G) -> anyhow::Result<()>
+/// `-p` level for a diff: 1 for git-style `a/` `b/` paths, otherwise 0.
+fn patch_strip_level(patch: &str) -> usize {
+    let git_style = patch.lines().any(|l| {
+        l.starts_with("diff --git ") || l.starts_with("--- a/") || l.starts_with("+++ b/")
+    });
+    if git_style {
+        1
+    } else {
+        0
+    }
+}
+
+/// Self-healing loop: retries `task` up to `max_attempts` times, applying the
+/// patch `propose_patch` suggests for each failure in between. Patches are
+/// applied off the async worker threads.
+pub async fn run_with_fixer_async<F, Fut, G, GFut>(
+    mut task: F,
+    max_attempts: usize,
+    propose_patch: G,
+) -> anyhow::Result<()>
 where
-    F: FnMut() -> anyhow::Result<()>,
-    G: Fn(&str) -> Option<String>,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+    G: Fn(String) -> GFut,
+    GFut: std::future::Future<Output = Option<String>>,
 {
     if max_attempts == 0 {
         anyhow::bail!("max_attempts must be greater than zero");
@@ -168,18 +261,101 @@ where
     let mut attempt = 0usize;
     loop {
         attempt += 1;
-        match task() {
+        match task().await {
             Ok(_) => return Ok(()),
             Err(err) => {
                 if attempt >= max_attempts {
                     anyhow::bail!("Exhausted after {attempt} attempts: {err}");
                 }
                 eprintln!("Attempt {attempt} failed: {err}");
-                if let Some(patch) = propose_patch(&format!("{err}")) {
-                    let _ = apply_patch(&patch);
+                if let Some(patch) = propose_patch(format!("{err}")).await {
+                    let _ = tokio::task::spawn_blocking(move || apply_patch(&patch)).await;
                 }
-                thread::sleep(Duration::from_millis(300));
+                tokio::time::sleep(Duration::from_millis(300)).await;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATCH: &str = "--- a/notes.txt\n+++ b/notes.txt\n@@ -1,2 +1,2 @@\n keep\n-old\n+new\n";
+
+    fn notes_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("notes.txt"), "keep\nold\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn unified_diffs_are_applied_to_the_tree() {
+        if which("patch").is_err() && which("git").is_err() {
+            return;
+        }
+        let dir = notes_dir();
+        apply_patch_in(PATCH, dir.path(), |program| which(program).is_ok()).unwrap();
+        let text = fs::read_to_string(dir.path().join("notes.txt")).unwrap();
+        assert_eq!(text, "keep\nnew\n");
+    }
+
+    #[test]
+    fn git_apply_is_used_without_patch() {
+        if which("git").is_err() {
+            return;
+        }
+        let dir = notes_dir();
+        apply_patch_in(PATCH, dir.path(), |program| program == "git").unwrap();
+        let text = fs::read_to_string(dir.path().join("notes.txt")).unwrap();
+        assert_eq!(text, "keep\nnew\n");
+
+        let err = apply_patch_in(PATCH, dir.path(), |_| false).unwrap_err();
+        assert!(err.to_string().contains("neither"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn fixer_retries_until_the_task_succeeds() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let attempts = AtomicUsize::new(0);
+        let fixes = AtomicUsize::new(0);
+        let task = || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => bail!("still broken"),
+                _ => Ok(()),
+            }
+        };
+        let propose_patch = |err: String| {
+            assert_eq!(err, "still broken");
+            fixes.fetch_add(1, Ordering::SeqCst);
+            async { None }
+        };
+
+        run_with_fixer_async(task, 5, propose_patch).await.unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(fixes.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn python_snippets_run_with_python3() {
+        if which("python3").is_err() {
+            return;
+        }
+        let out = execute_code("print(6 * 7)", Some("python")).unwrap();
+        assert_eq!(out, "42\n");
+    }
+
+    #[test]
+    fn node_snippets_run_from_a_lang_marker() {
+        if which("node").is_err() {
+            return;
+        }
+        let out = execute_code(
+            "// lang: js\nconsole.log([1, 2].map(n => n * 2).join(','))",
+            None,
+        );
+        assert_eq!(out.unwrap(), "2,4\n");
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+use std::io;
+
+/// Failures that scripts may want to tell apart. In single-shot mode each one
+/// exits with its own status code; anything else exits with 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// A command or tool argument was refused by the runner's guardrails.
+    GuardrailDenied(String),
+    /// No LLM provider is configured (missing API keys or model config).
+    NoProviders(String),
+    /// The model's plan could not be parsed or referenced missing files.
+    PlanInvalid(String),
+    /// Ctrl+C stopped the request before it finished.
+    Interrupted(String),
+}
+
+impl Failure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::GuardrailDenied(_) => 3,
+            Failure::NoProviders(_) => 4,
+            Failure::PlanInvalid(_) => 5,
+            Failure::Interrupted(_) => 130,
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::GuardrailDenied(msg)
+            | Failure::NoProviders(msg)
+            | Failure::PlanInvalid(msg)
+            | Failure::Interrupted(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// A `PermissionDenied` I/O error that carries `Failure::GuardrailDenied`, for
+/// the runner's `io::Result` APIs.
+pub fn guardrail_denied(msg: impl Into<String>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        Failure::GuardrailDenied(msg.into()),
+    )
+}
+
+/// Process exit code for `err`: the code of the first `Failure` in its chain
+/// (including one wrapped in an `io::Error`), or 1.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|cause| {
+            cause.downcast_ref::<Failure>().or_else(|| {
+                cause
+                    .downcast_ref::<io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .and_then(|inner| inner.downcast_ref::<Failure>())
+            })
+        })
+        .map_or(1, Failure::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn each_failure_category_has_its_own_exit_code() {
+        let code = |failure: Failure| exit_code(&anyhow::Error::new(failure));
+        assert_eq!(code(Failure::GuardrailDenied("rm".into())), 3);
+        assert_eq!(code(Failure::NoProviders("no keys".into())), 4);
+        assert_eq!(code(Failure::PlanInvalid("bad plan".into())), 5);
+        assert_eq!(code(Failure::Interrupted("ctrl+c".into())), 130);
+
+        // Found through context and inside the runner's io::Error.
+        let denied: anyhow::Result<()> = Err(guardrail_denied("curl")).context("running actions");
+        assert_eq!(exit_code(&denied.unwrap_err()), 3);
+        assert_eq!(exit_code(&anyhow::anyhow!("file not found")), 1);
+    }
+}
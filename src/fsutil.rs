@@ -1,60 +1,482 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use walkdir::WalkDir;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileMeta {
     pub path: String,
     pub size: u64,
     pub ext: Option<String>,
+    /// Set for entries listed under `SymlinkPolicy::ListAsLink`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_link: bool,
+    /// The file looks binary (a binary extension, or a NUL byte near the
+    /// start), so it can't be meaningfully read or edited as text.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_binary: bool,
+}
+
+/// How much of a file `looks_binary` inspects.
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+/// Whether `bytes` look like binary data: a NUL byte among the first few KB.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0)
+}
+
+/// Extensions that are always treated as binary without reading the file.
+const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "pdf", "zip", "gz", "tgz", "bz2", "xz",
+    "zst", "7z", "tar", "jar", "class", "o", "a", "so", "dylib", "dll", "exe", "rlib", "wasm",
+    "bin", "pyc", "woff", "woff2", "ttf", "otf", "mp3", "mp4", "wav", "mov", "sqlite", "db",
+];
+
+/// Extensions that are always treated as text without reading the file.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "rs", "toml", "md", "txt", "json", "yaml", "yml", "js", "ts", "tsx", "jsx", "py", "go", "c",
+    "h", "cpp", "hpp", "java", "kt", "rb", "sh", "html", "css", "scss", "xml", "sql", "lock",
+    "cfg", "ini", "csv",
+];
+
+/// Whether the file at `p` looks binary. Well-known extensions decide
+/// without touching the file; anything else falls back to `is_binary` on
+/// the start of the file, and unreadable files count as text.
+pub fn looks_binary(p: &Path) -> bool {
+    use std::io::Read;
+    let ext = p
+        .extension()
+        .and_then(|s| s.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some(ext) if BINARY_EXTENSIONS.contains(&ext) => return true,
+        Some(ext) if TEXT_EXTENSIONS.contains(&ext) => return false,
+        _ => {}
+    }
+    let Ok(file) = fs::File::open(p) else {
+        return false;
+    };
+    let mut head = Vec::with_capacity(BINARY_SNIFF_BYTES);
+    file.take(BINARY_SNIFF_BYTES as u64)
+        .read_to_end(&mut head)
+        .is_ok()
+        && is_binary(&head)
+}
+
+/// Directory names that are never descended into when indexing.
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules", "dist", "build"];
+
+fn is_indexed_name(name: &str) -> bool {
+    !name.starts_with('.') && !SKIPPED_DIRS.contains(&name)
+}
+
+/// Default limit on how deep `file_inventory` recurses below the root.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+static MAX_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_DEPTH);
+
+/// Set the process-wide walk depth used by `InventoryOptions::default()`.
+pub fn set_max_depth(depth: usize) {
+    MAX_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+static SKIP_BINARY: AtomicBool = AtomicBool::new(false);
+
+/// Set whether `InventoryOptions::default()` leaves binary files out of the
+/// index.
+pub fn set_skip_binary(skip: bool) {
+    SKIP_BINARY.store(skip, Ordering::Relaxed);
+}
+
+/// How `file_inventory_with` treats symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Leave symlinks out of the index entirely.
+    #[default]
+    Skip,
+    /// List symlinks as entries flagged `is_link`, without traversing them.
+    ListAsLink,
+    /// Traverse symlinks; cycles and targets outside the root are skipped.
+    Follow,
+}
+
+/// Options controlling how `file_inventory_with` walks the tree.
+#[derive(Debug, Clone)]
+pub struct InventoryOptions {
+    /// Maximum directory depth to descend (files directly in `root` are at
+    /// depth 1).
+    pub max_depth: usize,
+    pub symlinks: SymlinkPolicy,
+    /// Leave files that look binary out of the index.
+    pub skip_binary: bool,
+}
+
+impl Default for InventoryOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: MAX_DEPTH.load(Ordering::Relaxed),
+            symlinks: SymlinkPolicy::default(),
+            skip_binary: SKIP_BINARY.load(Ordering::Relaxed),
+        }
+    }
 }
 
 pub fn file_inventory(root: &Path) -> Result<Vec<FileMeta>> {
+    file_inventory_with(root, &InventoryOptions::default())
+}
+
+pub fn file_inventory_with(root: &Path, opts: &InventoryOptions) -> Result<Vec<FileMeta>> {
+    let follow = opts.symlinks == SymlinkPolicy::Follow;
+    let canonical_root = if follow {
+        Some(root.canonicalize()?)
+    } else {
+        None
+    };
+
     let mut out = Vec::new();
+    // With `follow_links(true)`, walkdir reports symlink loops as errors,
+    // which are skipped below like any other unreadable entry.
     for entry in WalkDir::new(root)
-        .follow_links(false)
+        .follow_links(follow)
+        .max_depth(opts.max_depth)
         .into_iter()
         .filter_entry(|e| {
-            let p = e.path();
-            let name = p.file_name().and_then(|s| s.to_str()).unwrap_or("");
-            !name.starts_with('.')
-                && name != "target"
-                && name != "node_modules"
-                && name != "dist"
-                && name != "build"
+            if e.depth() == 0 {
+                return true;
+            }
+            let name = e.path().file_name().and_then(|s| s.to_str()).unwrap_or("");
+            if !is_indexed_name(name) {
+                return false;
+            }
+            if !e.path_is_symlink() {
+                return true;
+            }
+            match opts.symlinks {
+                SymlinkPolicy::Skip => false,
+                SymlinkPolicy::ListAsLink => true,
+                SymlinkPolicy::Follow => match (&canonical_root, e.path().canonicalize()) {
+                    (Some(croot), Ok(target)) => target.starts_with(croot),
+                    _ => false,
+                },
+            }
         })
     {
         let entry = match entry {
             Ok(e) => e,
             Err(_) => continue,
         };
-        let p = entry.path();
-        if p.is_file() {
-            if let Ok(md) = p.metadata() {
-                let rel = diff_paths(p, root);
-                out.push(FileMeta {
-                    path: rel.to_string_lossy().to_string(),
-                    size: md.len(),
-                    ext: p
-                        .extension()
-                        .and_then(|s| s.to_str())
-                        .map(|s| s.to_string()),
-                });
+        if entry.path_is_symlink() && opts.symlinks == SymlinkPolicy::ListAsLink {
+            out.push(link_meta(root, entry.path()));
+        } else if let Some(meta) = file_meta(root, entry.path()) {
+            if !(opts.skip_binary && meta.is_binary) {
+                out.push(meta);
             }
         }
     }
+    // Sorted by path, which is also where `apply_inventory_changes` puts
+    // added files.
+    out.sort_by(|a, b| a.path.cmp(&b.path));
     Ok(out)
 }
 
-pub fn atomic_write(path: &Path, content: &str) -> Result<()> {
-    let parent = path.parent().unwrap_or_else(|| Path::new("."));
-    std::fs::create_dir_all(parent)?;
-    let tmp = path.with_extension("tmp.write");
-    fs::write(&tmp, content)?;
-    fs::rename(&tmp, path)?;
+fn link_meta(root: &Path, p: &Path) -> FileMeta {
+    FileMeta {
+        path: diff_paths(p, root).to_string_lossy().to_string(),
+        size: fs::symlink_metadata(p).map(|md| md.len()).unwrap_or(0),
+        ext: p
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string()),
+        is_link: true,
+        is_binary: false,
+    }
+}
+
+/// Build the inventory entry for a single file, or `None` if it is not a
+/// regular file.
+pub fn file_meta(root: &Path, p: &Path) -> Option<FileMeta> {
+    if !p.is_file() {
+        return None;
+    }
+    let md = p.metadata().ok()?;
+    let rel = diff_paths(p, root);
+    Some(FileMeta {
+        path: rel.to_string_lossy().to_string(),
+        size: md.len(),
+        ext: p
+            .extension()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string()),
+        is_link: false,
+        is_binary: looks_binary(p),
+    })
+}
+
+/// A change to a single file reported by the file watcher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathChange {
+    Added(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+}
+
+/// Update a cached inventory in place from watcher-reported changes instead of
+/// rescanning `root`. Paths that `file_inventory` would skip (hidden, `target`,
+/// ...) are ignored and added files are inserted in path order, so the result
+/// matches a full rescan.
+pub fn apply_inventory_changes(inventory: &mut Vec<FileMeta>, root: &Path, changes: &[PathChange]) {
+    let max_depth = InventoryOptions::default().max_depth;
+    for change in changes {
+        let (p, removed) = match change {
+            PathChange::Added(p) | PathChange::Modified(p) => (p, false),
+            PathChange::Removed(p) => (p, true),
+        };
+        let rel = diff_paths(p, root);
+        let indexed = rel.components().count() <= max_depth
+            && rel
+                .components()
+                .all(|c| is_indexed_name(&c.as_os_str().to_string_lossy()));
+        if !indexed {
+            continue;
+        }
+        let key = rel.to_string_lossy().to_string();
+        let pos = inventory.binary_search_by(|m| m.path.as_str().cmp(&key));
+        let meta = if removed { None } else { file_meta(root, p) };
+        match (pos, meta) {
+            (Ok(i), Some(meta)) => inventory[i] = meta,
+            (Err(i), Some(meta)) => inventory.insert(i, meta),
+            (Ok(i), None) => {
+                inventory.remove(i);
+            }
+            (Err(_), None) => {}
+        }
+    }
+}
+
+/// Set for the commands `AutonomousRunner` runs: a file holding its cached
+/// inventory of the watched tree (see `save_inventory`), which the planner
+/// reads instead of rescanning.
+pub const INVENTORY_ENV: &str = "SHELLCRAFT_INVENTORY";
+
+/// An inventory as saved by `save_inventory`.
+#[derive(Serialize, Deserialize)]
+struct SavedInventory {
+    root: PathBuf,
+    files: Vec<FileMeta>,
+}
+
+/// Save `files`, the inventory of `root`, to `path`.
+pub fn save_inventory(path: &Path, root: &Path, files: &[FileMeta]) -> Result<()> {
+    let saved = SavedInventory {
+        root: root.canonicalize()?,
+        files: files.to_vec(),
+    };
+    atomic_write(path, serde_json::to_vec(&saved)?)
+}
+
+/// The inventory saved at `path`, or `None` if it was taken of a tree other
+/// than `root`.
+pub fn load_inventory(path: &Path, root: &Path) -> Result<Option<Vec<FileMeta>>> {
+    let saved: SavedInventory = serde_json::from_slice(&fs::read(path)?)?;
+    Ok((saved.root == root.canonicalize()?).then_some(saved.files))
+}
+
+/// How many recent commits count towards `git_recent_paths`.
+pub const GIT_RECENT_COMMITS: usize = 20;
+
+/// Paths (relative to `root`) the user is likely working on: uncommitted
+/// changes, untracked files, and files touched by the last few commits.
+/// Empty when `git` is missing or `root` isn't inside a repository.
+pub fn git_recent_paths(root: &Path) -> HashSet<String> {
+    let log_depth = format!("-{GIT_RECENT_COMMITS}");
+    let queries: [&[&str]; 3] = [
+        &["diff", "--name-only", "--relative", "HEAD"],
+        &["ls-files", "--others", "--exclude-standard"],
+        &[
+            "log",
+            &log_depth,
+            "--name-only",
+            "--relative",
+            "--pretty=format:",
+        ],
+    ];
+    let mut paths = HashSet::new();
+    for args in queries {
+        let Ok(out) = Command::new("git").arg("-C").arg(root).args(args).output() else {
+            return paths;
+        };
+        if !out.status.success() {
+            continue;
+        }
+        paths.extend(
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(str::to_string),
+        );
+    }
+    paths
+}
+
+/// How much of a file's start is embedded along with its path.
+const EMBED_SNIPPET_BYTES: usize = 1024;
+
+/// Text embedded for a file when ranking by similarity: its path and the
+/// start of its content.
+pub fn embedding_text(root: &Path, meta: &FileMeta) -> String {
+    let mut text = meta.path.clone();
+    if !meta.is_binary && !meta.is_link {
+        if let Ok(bytes) = fs::read(root.join(&meta.path)) {
+            let head = &bytes[..bytes.len().min(EMBED_SNIPPET_BYTES)];
+            text.push('\n');
+            text.push_str(&String::from_utf8_lossy(head));
+        }
+    }
+    text
+}
+
+/// Where `EmbeddingCache` is kept, relative to the root.
+const EMBEDDING_CACHE: &str = ".shellcraft/embeddings.json";
+
+/// File embeddings from earlier plans, reused while the file's modification
+/// time (see `modified_ns`) and the embedding model stay the same.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EmbeddingCache {
+    model: String,
+    files: HashMap<String, CachedEmbedding>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedEmbedding {
+    modified_ns: u64,
+    vector: Vec<f32>,
+}
+
+impl EmbeddingCache {
+    /// The cache saved under `root`, or an empty one if there is none or it
+    /// holds another model's vectors.
+    pub fn load(root: &Path, model: &str) -> Self {
+        fs::read_to_string(root.join(EMBEDDING_CACHE))
+            .ok()
+            .and_then(|data| serde_json::from_str::<Self>(&data).ok())
+            .filter(|cache| cache.model == model)
+            .unwrap_or_else(|| Self {
+                model: model.to_string(),
+                files: HashMap::new(),
+            })
+    }
+
+    /// The vector of `path` if it was embedded when last modified at
+    /// `modified_ns`.
+    pub fn get(&self, path: &str, modified_ns: u64) -> Option<&[f32]> {
+        self.files
+            .get(path)
+            .filter(|e| e.modified_ns == modified_ns)
+            .map(|e| e.vector.as_slice())
+    }
+
+    pub fn insert(&mut self, path: &str, modified_ns: u64, vector: Vec<f32>) {
+        let entry = CachedEmbedding {
+            modified_ns,
+            vector,
+        };
+        self.files.insert(path.to_string(), entry);
+    }
+
+    /// Forget files no longer in `index`, then save under `root`.
+    pub fn save(&mut self, root: &Path, index: &[FileMeta]) -> Result<()> {
+        let present: HashSet<&str> = index.iter().map(|m| m.path.as_str()).collect();
+        self.files.retain(|path, _| present.contains(path.as_str()));
+        atomic_write(&root.join(EMBEDDING_CACHE), serde_json::to_vec(self)?)
+    }
+}
+
+/// Modification time of `path` in nanoseconds since the epoch, if known.
+pub fn modified_ns(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let since_epoch = modified.duration_since(std::time::UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Cosine similarity of `a` and `b`; 0 when their lengths differ or either
+/// is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denom = norm(a) * norm(b);
+    if denom == 0.0 {
+        0.0
+    } else {
+        dot / denom
+    }
+}
+
+/// `files` ordered by how similar their `embeddings` (one per file, same
+/// order) are to `query`, most similar first; ties keep their order.
+pub fn rank_by_similarity(
+    query: &[f32],
+    files: Vec<FileMeta>,
+    embeddings: &[Vec<f32>],
+) -> Vec<FileMeta> {
+    let mut scored: Vec<(f32, FileMeta)> = files
+        .into_iter()
+        .zip(embeddings)
+        .map(|(file, e)| (cosine_similarity(query, e), file))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().map(|(_, file)| file).collect()
+}
+
+/// Replace `path` with `content` without leaving a partial file behind: the
+/// data is written to a temp file beside it, synced to disk, and renamed into
+/// place, after which the directory entry is synced too (on Unix). A file
+/// that is replaced keeps its permissions.
+pub fn atomic_write(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+    let parent = parent.unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(parent)?;
+    let mut tmp = tempfile::NamedTempFile::new_in(parent)?;
+    if let Ok(meta) = fs::metadata(path) {
+        tmp.as_file().set_permissions(meta.permissions())?;
+    }
+    tmp.write_all(content.as_ref())?;
+    tmp.as_file().sync_all()?;
+    if let Err(err) = tmp.persist(path) {
+        // Persisting is a rename, which fails across devices and on Windows
+        // when the target is held open. Copy instead; dropping the temp file
+        // removes it either way.
+        if let Err(copy_err) = fs::copy(err.file.path(), path) {
+            bail!(
+                "persist failed ({}) and copy fallback failed ({copy_err})",
+                err.error
+            );
+        }
+        fs::File::open(path)?.sync_all()?;
+    }
+    sync_dir(parent)
+}
+
+/// Flush a directory's entries (e.g. a rename into it) to disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Directories can't be opened for syncing on Windows; renames there are
+/// journaled by the filesystem.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
     Ok(())
 }
 
@@ -66,33 +488,313 @@ fn diff_paths(p: &Path, root: &Path) -> PathBuf {
     pathdiff::diff_paths(p, root).unwrap_or_else(|| p.to_path_buf())
 }
 
-/// Append ignore patterns to .gitignore (dedup).
-pub fn merge_ignore_patterns(patterns: &[&str]) {
-    let path = Path::new(".gitignore");
-    let mut existing = String::new();
-    if path.exists() {
-        if let Ok(s) = fs::read_to_string(path) {
-            existing = s;
+/// Append ignore patterns to `root`'s .gitignore (dedup).
+///
+/// Existing lines, comments and blank lines included, are kept as they are;
+/// new patterns go at the end and the file always ends with a newline. The
+/// file is replaced atomically under `lock_path`, so a crash never leaves it
+/// truncated and concurrent edits of it don't interleave.
+pub async fn merge_ignore_patterns(root: &Path, patterns: &[&str]) -> Result<()> {
+    let path = root.join(".gitignore");
+    let path = path.as_path();
+    let _lock = lock_path(path).await;
+    let existing = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut present: HashSet<&str> = existing.lines().map(str::trim).collect();
+    let mut content = existing.clone();
+    for pat in patterns.iter().map(|p| p.trim()) {
+        if pat.is_empty() || !present.insert(pat) {
+            continue;
+        }
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
         }
+        content.push_str(pat);
+        content.push('\n');
     }
-    let mut lines: Vec<String> = existing.lines().map(|s| s.to_string()).collect();
-    let mut changed = false;
-    for pat in patterns {
-        if !lines.iter().any(|l| l.trim() == *pat) {
-            lines.push(pat.to_string());
-            changed = true;
+    if content != existing {
+        atomic_write(path, &content)?;
+    }
+    Ok(())
+}
+
+/// Resolve the plan-relative path `rel` against `root`, following symlinks,
+/// and make sure the result stays inside `root`. Paths that don't exist yet
+/// are resolved through their nearest existing ancestor; a `..` below that
+/// ancestor cannot be resolved and is rejected.
+pub fn resolve_within(root: &Path, rel: &str) -> Result<PathBuf> {
+    let canonical_root = root.canonicalize()?;
+    let mut existing = root.join(rel);
+    let mut missing = Vec::new();
+    while fs::symlink_metadata(&existing).is_err() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => break,
         }
     }
-    if changed {
-        if let Ok(mut f) = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)
-        {
-            let _ = writeln!(f, "{}", lines.join("\n"));
+    let mut resolved = existing
+        .canonicalize()
+        .map_err(|e| anyhow!("cannot resolve `{}`: {}", rel, e))?;
+    for name in missing.iter().rev() {
+        resolved.push(name);
+    }
+    if !resolved.starts_with(&canonical_root) {
+        bail!("path `{}` escapes the project root", rel);
+    }
+    Ok(resolved)
+}
+
+/// Match a relative path against a glob. `*` and `?` stay within one path
+/// segment and `**` spans directories. Patterns without a `/` are matched
+/// against the file name only, so `*.lock` matches `sub/Cargo.lock`.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let path = path.trim_start_matches("./");
+    let target = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+
+    let mut re = String::from("^");
+    let mut chars = pattern.trim_start_matches("./").chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches zero directories.
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
         }
     }
+    re.push('$');
+    regex::Regex::new(&re)
+        .map(|r| r.is_match(target))
+        .unwrap_or(false)
+}
+
+static SCOPE: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Add paths or globs to the working scope. Planning only sees files in the
+/// scope and reads/edits outside it are refused; several scopes union.
+pub fn add_scope<I: IntoIterator<Item = String>>(patterns: I) {
+    SCOPE.lock().unwrap().extend(patterns);
+}
+
+/// Drop every scope so the whole project is in play again.
+pub fn clear_scope() {
+    SCOPE.lock().unwrap().clear();
+}
+
+pub fn scope() -> Vec<String> {
+    SCOPE.lock().unwrap().clone()
+}
+
+/// Whether the relative `path` is inside the current scope. With no scope set
+/// every path is. A scope without glob characters names a file or directory.
+pub fn in_scope(path: &str) -> bool {
+    let scope = SCOPE.lock().unwrap();
+    scope.is_empty() || scope.iter().any(|s| scope_matches(s, path))
+}
+
+fn scope_matches(scope: &str, path: &str) -> bool {
+    let path = path.trim_start_matches("./");
+    if scope.contains(['*', '?']) {
+        return glob_match(scope, path);
+    }
+    let dir = scope.trim_start_matches("./").trim_end_matches('/');
+    dir.is_empty() || dir == "." || path == dir || path.starts_with(&format!("{dir}/"))
+}
+
+/// Where `backup_before_write` keeps prior versions, relative to the root.
+pub const BACKUP_DIR: &str = ".shellcraft/backups";
+
+static BACKUPS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Analysis-only session: plans are shown but never change files or run
+/// commands.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Turn read-only mode on or off (off by default).
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::Relaxed);
+}
+
+/// Whether plan edits, deletes, moves and actions must be left unapplied.
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// Turn backups of overwritten files on or off (on by default).
+pub fn set_backups_enabled(enabled: bool) {
+    BACKUPS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Backups kept per file; `backup_before_write` drops the oldest beyond this.
+const MAX_BACKUPS_PER_FILE: usize = 20;
+
+/// Suffix of the copy `undo_last_edit` keeps of the content it replaces.
+const UNDONE_SUFFIX: &str = ".undone";
+
+/// Copy `rel`'s current content to `.shellcraft/backups/<rel>.<timestamp>`
+/// before it is overwritten, keeping at most `MAX_BACKUPS_PER_FILE` backups of
+/// it. Returns the backup path, or `None` if backups are off or the file
+/// doesn't exist yet.
+pub fn backup_before_write(root: &Path, rel: &str) -> Result<Option<PathBuf>> {
+    let (src, rel) = backup_target(root, rel)?;
+    if !BACKUPS_ENABLED.load(Ordering::Relaxed) || !src.is_file() {
+        return Ok(None);
+    }
+    let dst = backup_path(root, &rel, "");
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&src, &dst)?;
+    let backups = backups_of(root, &rel);
+    let excess = backups.len().saturating_sub(MAX_BACKUPS_PER_FILE);
+    for (_, old) in backups.into_iter().take(excess) {
+        let _ = fs::remove_file(old);
+    }
+    Ok(Some(dst))
+}
+
+/// Restore `rel` from its most recent backup and discard that backup, so
+/// repeated calls step further back. The content being replaced is kept as
+/// `<rel>.<timestamp>.undone` beside the backups. Returns the backup that was
+/// restored.
+pub fn undo_last_edit(root: &Path, rel: &str) -> Result<PathBuf> {
+    let (abs, rel) = backup_target(root, rel)?;
+    if let Some(why) = crate::config::protection(&rel) {
+        bail!("`{rel}` {why}");
+    }
+    let latest = backups_of(root, &rel)
+        .into_iter()
+        .rev()
+        .find(|(name, _)| !name.ends_with(UNDONE_SUFFIX))
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow!("no backup of `{}` to restore", rel))?;
+    let content = fs::read_to_string(&latest)?;
+    if abs.is_file() {
+        fs::copy(&abs, backup_path(root, &rel, UNDONE_SUFFIX))?;
+    }
+    atomic_write(&abs, &content)?;
+    fs::remove_file(&latest)?;
+    Ok(latest)
+}
+
+/// `rel` resolved inside `root`, and its normalized root-relative form, under
+/// which its backups are kept.
+fn backup_target(root: &Path, rel: &str) -> Result<(PathBuf, String)> {
+    let abs = resolve_within(root, rel)?;
+    let rel = abs
+        .strip_prefix(root.canonicalize()?)?
+        .to_string_lossy()
+        .replace('\\', "/");
+    Ok((abs, rel))
+}
+
+/// A new, timestamped backup path for `rel`.
+fn backup_path(root: &Path, rel: &str, suffix: &str) -> PathBuf {
+    let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S%3f");
+    root.join(BACKUP_DIR).join(format!("{rel}.{stamp}{suffix}"))
+}
+
+/// Backups of `rel` (including undone copies) as file name and path, oldest
+/// first.
+fn backups_of(root: &Path, rel: &str) -> Vec<(String, PathBuf)> {
+    let target = root.join(BACKUP_DIR).join(rel);
+    let dir = target.parent().unwrap_or(&target);
+    let file_name = target.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let prefix = format!("{file_name}.");
+    let mut backups: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().into_string().ok()?;
+            let rest = name.strip_prefix(&prefix)?;
+            let stamp = rest.strip_suffix(UNDONE_SUFFIX).unwrap_or(rest);
+            (!stamp.is_empty() && stamp.bytes().all(|b| b.is_ascii_digit()))
+                .then(|| (name.clone(), e.path()))
+        })
+        .collect();
+    // Timestamps have a fixed width, so names sort by age.
+    backups.sort_by(|a, b| a.0[prefix.len()..].cmp(&b.0[prefix.len()..]));
+    backups
+}
+
+/// Content of each file before this session first changed it; `None` if it
+/// didn't exist yet.
+static SESSION_ORIGINALS: Lazy<Mutex<BTreeMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Remember `rel`'s current content as its pre-session state, unless the
+/// session already changed it.
+pub fn snapshot_for_session(root: &Path, rel: &str) {
+    let mut originals = SESSION_ORIGINALS.lock().unwrap();
+    if !originals.contains_key(rel) {
+        let content = fs::read_to_string(root.join(rel)).ok();
+        originals.insert(rel.to_string(), content);
+    }
+}
+
+/// `(path, pre-session content, current content)` for every file the
+/// session changed, with missing files read as empty.
+pub fn session_changes(root: &Path) -> Vec<(String, String, String)> {
+    let originals = SESSION_ORIGINALS.lock().unwrap();
+    originals
+        .iter()
+        .filter_map(|(rel, original)| {
+            let before = original.clone().unwrap_or_default();
+            let after = fs::read_to_string(root.join(rel)).unwrap_or_default();
+            (before != after).then(|| (rel.clone(), before, after))
+        })
+        .collect()
+}
+
+/// One lock per file being edited, keyed by canonical path.
+static FILE_LOCKS: Lazy<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Canonical form of `path` even when it doesn't exist yet, so every spelling
+/// of the same file shares a lock.
+fn lock_key(path: &Path) -> PathBuf {
+    if let Ok(canonical) = path.canonicalize() {
+        return canonical;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map(|p| p.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Serialize read-modify-write cycles on `path` within this process: hold the
+/// guard from reading the old content until the new content is written.
+pub async fn lock_path(path: &Path) -> tokio::sync::OwnedMutexGuard<()> {
+    let lock = FILE_LOCKS
+        .lock()
+        .unwrap()
+        .entry(lock_key(path))
+        .or_default()
+        .clone();
+    lock.lock_owned().await
 }
 
 /// Remove a file or directory recursively.
@@ -104,3 +806,360 @@ pub fn remove_path(p: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+/// Remove the plan-relative `rel` under `root` like `remove_path`, refusing
+/// the root itself and anything that resolves outside it, whether through
+/// `..`, an absolute path or a symlink. A symlink inside the root is removed
+/// itself, not what it points to.
+pub fn remove_path_within(root: &Path, rel: &str) -> Result<()> {
+    let target = resolve_within(root, rel)?;
+    if target == root.canonicalize()? {
+        bail!("refusing to delete the project root");
+    }
+    let rel_path = Path::new(rel);
+    if let (Some(parent), Some(name)) = (rel_path.parent(), rel_path.file_name()) {
+        let entry = resolve_within(root, &parent.to_string_lossy())?.join(name);
+        if fs::symlink_metadata(&entry).is_ok_and(|m| m.file_type().is_symlink()) {
+            fs::remove_file(&entry)?;
+            return Ok(());
+        }
+    }
+    remove_path(&target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names of the entries in `dir`, sorted.
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn atomic_writes_replace_the_file_and_leave_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "old content that is longer\n").unwrap();
+
+        atomic_write(&path, "new\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(entries(dir.path()), ["notes.txt"]);
+    }
+
+    #[test]
+    fn failed_atomic_writes_leave_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // Neither renaming nor copying a file over a non-empty directory works.
+        let target = dir.path().join("target");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("keep"), "").unwrap();
+
+        assert!(atomic_write(&target, "content").is_err());
+
+        assert_eq!(entries(dir.path()), ["target"]);
+        assert_eq!(entries(&target), ["keep"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_writes_keep_the_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.sh");
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o754)).unwrap();
+
+        atomic_write(&path, "#!/bin/sh\nmake\n").unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o754);
+    }
+
+    #[tokio::test]
+    async fn ignore_patterns_keep_comments_and_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let gitignore = dir.path().join(".gitignore");
+        fs::write(&gitignore, "# build output\n/target\n\n# editors\n*.swp").unwrap();
+
+        merge_ignore_patterns(dir.path(), &["*.swp", "/dist", "/target", "/dist"])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&gitignore).unwrap(),
+            "# build output\n/target\n\n# editors\n*.swp\n/dist\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_of_one_path_take_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.txt");
+        fs::write(&path, "").unwrap();
+        let alias = dir.path().join(".").join("shared.txt");
+        let append = |spelling: PathBuf, line: &'static str| async move {
+            let _lock = lock_path(&spelling).await;
+            let old = fs::read_to_string(&spelling).unwrap();
+            tokio::task::yield_now().await;
+            atomic_write(&spelling, format!("{old}{line}\n")).unwrap();
+        };
+
+        tokio::join!(append(path.clone(), "one"), append(alias, "two"));
+
+        let mut lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(lines, ["one", "two"]);
+    }
+
+    fn paths(files: &[FileMeta]) -> Vec<&str> {
+        files.iter().map(|f| f.path.as_str()).collect()
+    }
+
+    #[test]
+    fn a_max_depth_of_one_only_lists_top_level_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("src/deep")).unwrap();
+        fs::write(root.join("top.txt"), "top").unwrap();
+        fs::write(root.join("src/lib.rs"), "").unwrap();
+        fs::write(root.join("src/deep/mod.rs"), "").unwrap();
+
+        let opts = InventoryOptions {
+            max_depth: 1,
+            ..InventoryOptions::default()
+        };
+        assert_eq!(
+            paths(&file_inventory_with(root, &opts).unwrap()),
+            ["top.txt"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_skipped_or_followed_only_inside_the_root() {
+        use std::os::unix::fs::symlink;
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("real")).unwrap();
+        fs::write(root.join("real/lib.rs"), "").unwrap();
+        symlink(root.join("real"), root.join("vendored")).unwrap();
+        symlink(outside.path(), root.join("escape")).unwrap();
+
+        let skipped = file_inventory_with(root, &InventoryOptions::default()).unwrap();
+        assert_eq!(paths(&skipped), ["real/lib.rs"]);
+
+        let opts = InventoryOptions {
+            symlinks: SymlinkPolicy::Follow,
+            ..InventoryOptions::default()
+        };
+        let followed = file_inventory_with(root, &opts).unwrap();
+        assert_eq!(paths(&followed), ["real/lib.rs", "vendored/lib.rs"]);
+
+        assert!(resolve_within(root, "vendored/lib.rs").is_ok());
+        assert!(resolve_within(root, "escape/secret.txt").is_err());
+    }
+
+    #[test]
+    fn binary_files_are_flagged_and_can_be_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("notes"), "plain text").unwrap();
+        fs::write(root.join("blob"), b"ELF\0\0\x01data").unwrap();
+        // Decided by extension alone, whatever the content.
+        fs::write(root.join("logo.png"), "not really a png").unwrap();
+
+        let binary = |files: &[FileMeta]| -> Vec<(String, bool)> {
+            files
+                .iter()
+                .map(|f| (f.path.clone(), f.is_binary))
+                .collect()
+        };
+        let all = file_inventory(root).unwrap();
+        assert_eq!(
+            binary(&all),
+            [
+                ("blob".to_string(), true),
+                ("logo.png".to_string(), true),
+                ("notes".to_string(), false),
+            ]
+        );
+
+        let opts = InventoryOptions {
+            skip_binary: true,
+            ..InventoryOptions::default()
+        };
+        let text = file_inventory_with(root, &opts).unwrap();
+        assert_eq!(binary(&text), [("notes".to_string(), false)]);
+    }
+
+    #[test]
+    fn added_files_match_a_full_rescan() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("src")).unwrap();
+        for path in ["b.rs", "src/main.rs", "z.toml"] {
+            fs::write(root.join(path), "x").unwrap();
+        }
+        let mut inventory = file_inventory(root).unwrap();
+
+        fs::write(root.join("a.rs"), "new").unwrap();
+        fs::write(root.join("src/lib.rs"), "new").unwrap();
+        fs::write(root.join(".hidden"), "skipped").unwrap();
+        let changes = [
+            PathChange::Added(root.join("src/lib.rs")),
+            PathChange::Added(root.join("a.rs")),
+            PathChange::Added(root.join(".hidden")),
+        ];
+        apply_inventory_changes(&mut inventory, root, &changes);
+
+        assert_eq!(inventory, file_inventory(root).unwrap());
+    }
+
+    #[test]
+    fn saved_inventories_are_only_loaded_for_their_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.rs"), "x").unwrap();
+        let inventory = file_inventory(dir.path()).unwrap();
+        let path = dir.path().join("inventory.json");
+
+        save_inventory(&path, dir.path(), &inventory).unwrap();
+
+        let loaded = load_inventory(&path, dir.path()).unwrap();
+        assert_eq!(loaded, Some(inventory));
+        assert_eq!(load_inventory(&path, other.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn undo_restores_the_backup_and_keeps_the_undone_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "before\n").unwrap();
+
+        let backup = backup_before_write(root, "a.txt").unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "before\n");
+        atomic_write(&root.join("a.txt"), "after\n").unwrap();
+
+        let restored = undo_last_edit(root, "a.txt").unwrap();
+        assert_eq!(restored, backup);
+        assert!(!backup.exists());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "before\n");
+        let undone = backups_of(root, "a.txt");
+        assert_eq!(undone.len(), 1);
+        assert!(undone[0].0.ends_with(UNDONE_SUFFIX));
+        assert_eq!(fs::read_to_string(&undone[0].1).unwrap(), "after\n");
+        // The undone copy is not restored by a further undo.
+        assert!(undo_last_edit(root, "a.txt").is_err());
+    }
+
+    #[test]
+    fn session_changes_are_the_net_change_from_the_first_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        // Snapshots are keyed by relative path, so use one no other test edits.
+        let rel = "session-net-change.txt";
+        fs::write(root.join(rel), "one\n").unwrap();
+
+        snapshot_for_session(root, rel);
+        fs::write(root.join(rel), "two\n").unwrap();
+        snapshot_for_session(root, rel);
+        fs::write(root.join(rel), "three\n").unwrap();
+
+        let changes = session_changes(root);
+        let change = changes.iter().find(|(path, ..)| path == rel).unwrap();
+        assert_eq!(change.1, "one\n");
+        assert_eq!(change.2, "three\n");
+    }
+
+    #[test]
+    fn undo_stays_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir(&root).unwrap();
+        fs::write(dir.path().join("outside.txt"), "mine\n").unwrap();
+
+        let err = undo_last_edit(&root, "../outside.txt").unwrap_err();
+        assert!(
+            err.to_string().contains("escapes the project root"),
+            "{err}"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("outside.txt")).unwrap(),
+            "mine\n"
+        );
+    }
+
+    #[test]
+    fn deletes_stay_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/old.rs"), "").unwrap();
+        fs::write(dir.path().join("outside.txt"), "mine\n").unwrap();
+
+        remove_path_within(&root, "src/old.rs").unwrap();
+        assert!(!root.join("src/old.rs").exists());
+
+        assert!(remove_path_within(&root, "../outside.txt").is_err());
+        assert!(remove_path_within(&root, "src/../..").is_err());
+        assert!(remove_path_within(&root, ".").is_err());
+        assert!(dir.path().join("outside.txt").exists());
+        assert!(root.join("src").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deletes_through_symlinks_cannot_escape_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("project");
+        fs::create_dir(&root).unwrap();
+        let outside = dir.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("keep.txt"), "mine\n").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+        assert!(remove_path_within(&root, "link/keep.txt").is_err());
+        assert!(remove_path_within(&root, "link").is_err());
+        assert!(outside.join("keep.txt").exists());
+
+        // A link inside the root is removed itself, not what it points to.
+        fs::create_dir(root.join("src")).unwrap();
+        std::os::unix::fs::symlink(root.join("src"), root.join("alias")).unwrap();
+        remove_path_within(&root, "alias").unwrap();
+        assert!(fs::symlink_metadata(root.join("alias")).is_err());
+        assert!(root.join("src").is_dir());
+    }
+
+    #[test]
+    fn backups_are_capped_per_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("a.txt"), "current\n").unwrap();
+        let backups = root.join(BACKUP_DIR);
+        fs::create_dir_all(&backups).unwrap();
+        for i in 0..MAX_BACKUPS_PER_FILE + 5 {
+            fs::write(backups.join(format!("a.txt.20000101000000{i:03}")), "old").unwrap();
+        }
+
+        let newest = backup_before_write(root, "a.txt").unwrap().unwrap();
+
+        let kept = backups_of(root, "a.txt");
+        assert_eq!(kept.len(), MAX_BACKUPS_PER_FILE);
+        assert_eq!(kept.last().unwrap().1, newest);
+        assert!(!backups.join("a.txt.20000101000000005").exists());
+        assert!(backups.join("a.txt.20000101000000006").exists());
+    }
+}
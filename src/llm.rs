@@ -3,14 +3,58 @@ use once_cell::sync::Lazy;
 use reqwest::Client;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{self, json};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
 
-use crate::models::{ModelInfo, ModelRegistry};
+use crate::config;
+use crate::errors::Failure;
+use crate::fsutil;
+use crate::models::{ModelInfo, ModelRegistry, TaskType};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EditReq {
     pub file_path: String,
     pub file_content: String,
     pub instruction: String,
+    /// Overrides the project's `[edit] temperature` for this call.
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    /// Overrides the project's `[edit] top_p` for this call.
+    #[serde(default)]
+    pub top_p: Option<f32>,
+}
+
+/// Temperature used for edits when neither the request nor the project
+/// config sets one.
+const DEFAULT_EDIT_TEMPERATURE: f32 = 0.2;
+
+/// Project-level sampling defaults for `propose_edit`.
+static EDIT_SAMPLING: Lazy<Mutex<(Option<f32>, Option<f32>)>> =
+    Lazy::new(|| Mutex::new((None, None)));
+
+/// Set the default `temperature`/`top_p` used by `propose_edit` when the
+/// `EditReq` doesn't override them.
+pub fn set_edit_sampling(temperature: Option<f32>, top_p: Option<f32>) {
+    *EDIT_SAMPLING.lock().unwrap() = (temperature, top_p);
+}
+
+/// Files longer than this many lines are edited one region at a time; 0
+/// always sends the whole file.
+static EDIT_CHUNK_LINES: AtomicUsize = AtomicUsize::new(400);
+
+/// Set the line count above which `propose_edit` sends only the region of the
+/// file the instruction is about (the project's `[edit] chunk_lines`).
+pub fn set_edit_chunk_lines(lines: usize) {
+    EDIT_CHUNK_LINES.store(lines, Ordering::Relaxed);
 }
 
 static HTTP: Lazy<Client> = Lazy::new(|| {
@@ -22,51 +66,331 @@ static HTTP: Lazy<Client> = Lazy::new(|| {
 
 static MODEL_REGISTRY: Lazy<ModelRegistry> = Lazy::new(ModelRegistry::load);
 
-fn pick_provider(model_override: Option<&str>) -> Result<(String, String, String)> {
+/// Wakes requests waiting in `until_cancelled` when Ctrl+C is pressed.
+static CANCEL_SIGNAL: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Abort every request currently awaited through `until_cancelled`, e.g. from
+/// the Ctrl+C handler. Safe to call from any thread.
+pub fn signal_cancel() {
+    CANCEL_SIGNAL.notify_waiters();
+}
+
+/// Await `request`, giving up once `signal_cancel` fires (or Ctrl+C has
+/// already cleared `running`): the request is dropped, closing its
+/// connection, and the call fails with `Failure::Interrupted`.
+pub async fn until_cancelled<F: Future>(running: &AtomicBool, request: F) -> Result<F::Output> {
+    let cancelled = CANCEL_SIGNAL.notified();
+    tokio::pin!(cancelled);
+    // Register before checking the flag so a signal in between isn't missed.
+    cancelled.as_mut().enable();
+    if !running.load(Ordering::SeqCst) {
+        return Err(Failure::Interrupted("interrupted by Ctrl+C".into()).into());
+    }
+    tokio::select! {
+        output = request => Ok(output),
+        _ = cancelled => Err(Failure::Interrupted("interrupted by Ctrl+C".into()).into()),
+    }
+}
+
+/// Session-wide model chosen with `/model`; wins over `MODEL_ID`.
+static MODEL_OVERRIDE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Use `id` for every request that doesn't name its own model.
+pub fn set_model_id(id: &str) {
+    *MODEL_OVERRIDE.lock().unwrap() = Some(id.to_string());
+}
+
+/// The model requested for this session: the `/model` override, else
+/// `MODEL_ID`.
+pub fn model_id() -> Option<String> {
+    MODEL_OVERRIDE
+        .lock()
+        .unwrap()
+        .clone()
+        .or_else(|| config::env_var("MODEL_ID").ok())
+}
+
+/// Whether `SHELLCRAFT_OFFLINE=1` is set: no request leaves the machine, and
+/// the planner falls back to a heuristic plan (see `planner::offline_plan`).
+pub fn is_offline() -> bool {
+    config::env_var("SHELLCRAFT_OFFLINE").is_ok_and(|v| v == "1")
+}
+
+/// The model requests will go to when they don't name one.
+pub fn active_model() -> String {
+    model_id().unwrap_or_else(|| MODEL_REGISTRY.default_model.clone())
+}
+
+/// Model to use for `task`: a `/model` choice wins; otherwise the first model
+/// in `models.json` whose `specialty` matches the task and whose credentials
+/// are available, ahead of `MODEL_ID`. `None` leaves the session default.
+pub fn model_for_task(task: TaskType) -> Option<String> {
+    if let Some(id) = MODEL_OVERRIDE.lock().unwrap().clone() {
+        return Some(id);
+    }
+    MODEL_REGISTRY
+        .for_task(task)
+        .find(|m| {
+            m.api_key.is_some()
+                || config::env_var(&m.api_key_env).is_ok()
+                || is_local_provider(&m.provider)
+        })
+        .map(|m| m.id.clone())
+}
+
+/// Whether `models.json` lists `id`; `None` when no models are registered.
+pub fn is_known_model(id: &str) -> Option<bool> {
+    if MODEL_REGISTRY.models.is_empty() {
+        None
+    } else {
+        Some(MODEL_REGISTRY.get(id).is_some())
+    }
+}
+
+/// Resolved endpoint for a chat request.
+#[derive(Clone)]
+struct Provider {
+    /// Provider name as in `models.json` (`openai`, `groq`, `ollama`, ...).
+    name: String,
+    /// API key; empty for local servers that don't authenticate.
+    key: String,
+    base: String,
+    model: String,
+    /// Completion-token cap from `models.json`, if the model declares one.
+    max_completion_tokens: Option<u32>,
+    /// How long a single request may take before it is abandoned.
+    timeout: Duration,
+}
+
+impl Provider {
+    /// Ollama-style local servers speak `/api/chat` rather than the
+    /// OpenAI-compatible `/chat/completions`.
+    fn is_local(&self) -> bool {
+        is_local_provider(&self.name)
+    }
+}
+
+fn is_local_provider(name: &str) -> bool {
+    matches!(name, "ollama" | "local")
+}
+
+/// Request timeout for models that don't set `timeout_secs`.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+fn ollama_base() -> String {
+    config::env_var("OLLAMA_BASE_URL").unwrap_or_else(|_| "http://localhost:11434".to_string())
+}
+
+/// Endpoint for a `models.json` provider name; `None` for a provider that is
+/// neither built in nor given a `<NAME>_BASE_URL`.
+fn provider_base(provider: &str) -> Option<String> {
+    match provider {
+        "openai" => Some(
+            config::env_var("OPENAI_BASE_URL")
+                .unwrap_or_else(|_| "https://api.openai.com/v1".to_string()),
+        ),
+        "groq" => Some(
+            config::env_var("GROQ_BASE_URL")
+                .unwrap_or_else(|_| "https://api.groq.com/openai/v1".to_string()),
+        ),
+        "ollama" | "local" => Some(ollama_base()),
+        other => config::env_var(&format!("{}_BASE_URL", other.to_uppercase())).ok(),
+    }
+}
+
+/// How one `models.json` entry resolves, for `/validate-config`.
+pub struct ModelCheck {
+    pub id: String,
+    pub provider: String,
+    /// Endpoint requests would go to, if the provider resolves.
+    pub base: Option<String>,
+    /// Misconfigurations that would make requests to this model fail.
+    pub problems: Vec<String>,
+}
+
+/// Resolve every registered model the way `pick_provider` would, without
+/// sending anything. Also returns registry-wide problems.
+pub fn validate_models() -> (Vec<ModelCheck>, Vec<String>) {
+    validate_registry(&MODEL_REGISTRY)
+}
+
+/// `validate_models` on an already loaded registry.
+fn validate_registry(registry: &ModelRegistry) -> (Vec<ModelCheck>, Vec<String>) {
+    let mut general = Vec::new();
+    if !registry.models.is_empty() && registry.get(&registry.default_model).is_none() {
+        general.push(format!(
+            "default_model `{}` is not listed in models",
+            registry.default_model
+        ));
+    }
+    let checks = registry
+        .models
+        .iter()
+        .map(|m| {
+            let base = m.base_url.clone().or_else(|| provider_base(&m.provider));
+            let mut problems = Vec::new();
+            if base.is_none() {
+                problems.push(format!(
+                    "unknown provider `{}` (set {}_BASE_URL to use it)",
+                    m.provider,
+                    m.provider.to_uppercase()
+                ));
+            }
+            if !is_local_provider(&m.provider) && m.api_key.is_none() {
+                if m.api_key_env.is_empty() {
+                    problems.push("api_key_env is empty".to_string());
+                } else if config::env_var(&m.api_key_env).is_err() {
+                    problems.push(format!("{} not set", m.api_key_env));
+                }
+            }
+            ModelCheck {
+                id: m.id.clone(),
+                provider: m.provider.clone(),
+                base,
+                problems,
+            }
+        })
+        .collect();
+    (checks, general)
+}
+
+/// Strict check of the model config (`--strict-config`): the file must parse,
+/// every model's provider must be known (built in, or given a
+/// `<NAME>_BASE_URL`), and `default_model` must be one of the listed models.
+pub fn check_model_config() -> Result<()> {
+    check_registry(&ModelRegistry::load_strict()?)
+}
+
+/// The strict checks of `check_model_config` on an already loaded registry;
+/// the error names every offending entry.
+pub fn check_registry(registry: &ModelRegistry) -> Result<()> {
+    let mut problems: Vec<String> = registry
+        .models
+        .iter()
+        .filter(|m| m.base_url.is_none() && provider_base(&m.provider).is_none())
+        .map(|m| format!("model `{}` has unknown provider `{}`", m.id, m.provider))
+        .collect();
+    if !registry.models.is_empty() && registry.get(&registry.default_model).is_none() {
+        problems.push(format!(
+            "default_model `{}` is not listed in models",
+            registry.default_model
+        ));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{}: {}",
+        crate::models::config_path(),
+        problems.join("; ")
+    ))
+}
+
+fn pick_provider(model_override: Option<&str>) -> Result<Provider> {
+    #[cfg(test)]
+    if let Some(provider) = mock::routed() {
+        return Ok(match model_override {
+            Some(model) => Provider {
+                model: model.to_string(),
+                ..provider
+            },
+            None => provider,
+        });
+    }
+    if is_offline() {
+        return Err(Failure::NoProviders(
+            "offline mode (SHELLCRAFT_OFFLINE=1): no model is contacted".into(),
+        )
+        .into());
+    }
     let registry = &*MODEL_REGISTRY;
-    let model_id = model_override
+    let requested = model_override
         .map(|s| s.to_string())
-        .or_else(|| std::env::var("MODEL_ID").ok())
+        .or_else(model_id)
         .unwrap_or_else(|| registry.default_model.clone());
 
     if let Some(ModelInfo {
         provider,
         api_key_env,
+        max_completion_tokens,
+        timeout_secs,
+        base_url,
+        api_key,
         ..
-    }) = registry.get(&model_id).cloned()
+    }) = registry.get(&requested).cloned()
     {
-        let key = std::env::var(&api_key_env).map_err(|_| anyhow!("{} not set", api_key_env))?;
-        let base = match provider.as_str() {
-            "openai" => std::env::var("OPENAI_BASE_URL")
-                .unwrap_or_else(|_| "https://api.openai.com/v1".to_string()),
-            "groq" => std::env::var("GROQ_BASE_URL")
-                .unwrap_or_else(|_| "https://api.groq.com/openai/v1".to_string()),
-            other => std::env::var(format!("{}_BASE_URL", other.to_uppercase()))
-                .unwrap_or_else(|_| String::new()),
+        let key = match api_key.map_or_else(|| config::env_var(&api_key_env), Ok) {
+            Ok(key) => key,
+            Err(_) if is_local_provider(&provider) => String::new(),
+            Err(_) => return Err(Failure::NoProviders(format!("{} not set", api_key_env)).into()),
         };
-        return Ok((key, base, model_id));
+        let base = base_url
+            .or_else(|| provider_base(&provider))
+            .unwrap_or_default();
+        return Ok(Provider {
+            name: provider,
+            key,
+            base,
+            model: requested,
+            max_completion_tokens,
+            timeout: timeout_secs.map_or(DEFAULT_TIMEOUT, Duration::from_secs),
+        });
+    }
+
+    if let Some(provider) = env_providers().into_iter().next() {
+        return Ok(provider);
     }
+    Err(Failure::NoProviders(
+        "API_KEY not set. Set OPENAI_API_KEY or GROQ_API_KEY (and optional MODEL_ID / *_BASE_URL), or LOCAL_MODEL for a local Ollama server.".into(),
+    )
+    .into())
+}
 
-    if let Ok(key) = std::env::var("GROQ_API_KEY") {
-        let base = std::env::var("OPENAI_BASE_URL")
-            .or_else(|_| std::env::var("GROQ_BASE_URL"))
+/// Providers configured through the environment, in order of preference:
+/// Groq, OpenAI, then a local Ollama model.
+fn env_providers() -> Vec<Provider> {
+    let mut providers = Vec::new();
+    if let Ok(key) = config::env_var("GROQ_API_KEY") {
+        let base = config::env_var("OPENAI_BASE_URL")
+            .or_else(|_| config::env_var("GROQ_BASE_URL"))
             .unwrap_or_else(|_| "https://api.groq.com/openai/v1".to_string());
-        let model =
-            std::env::var("MODEL_ID").unwrap_or_else(|_| "llama-3.3-70b-versatile".to_string());
-        return Ok((key, base, model));
+        let model = model_id().unwrap_or_else(|| "llama-3.3-70b-versatile".to_string());
+        providers.push(Provider {
+            name: "groq".into(),
+            key,
+            base,
+            model,
+            max_completion_tokens: None,
+            timeout: DEFAULT_TIMEOUT,
+        });
     }
-    if let Ok(key) = std::env::var("OPENAI_API_KEY") {
-        let base = std::env::var("OPENAI_BASE_URL")
+    if let Ok(key) = config::env_var("OPENAI_API_KEY") {
+        let base = config::env_var("OPENAI_BASE_URL")
             .unwrap_or_else(|_| "https://api.openai.com/v1".to_string());
-        let model = std::env::var("MODEL_ID").unwrap_or_else(|_| "gpt-4o-mini".to_string());
-        return Ok((key, base, model));
+        let model = model_id().unwrap_or_else(|| "gpt-4o-mini".to_string());
+        providers.push(Provider {
+            name: "openai".into(),
+            key,
+            base,
+            model,
+            max_completion_tokens: None,
+            timeout: DEFAULT_TIMEOUT,
+        });
     }
-    Err(anyhow!(
-        "API_KEY not set. Set OPENAI_API_KEY or GROQ_API_KEY (and optional MODEL_ID / *_BASE_URL).",
-    ))
+    if let Ok(model) = config::env_var("LOCAL_MODEL") {
+        providers.push(Provider {
+            name: "ollama".into(),
+            key: String::new(),
+            base: ollama_base(),
+            model,
+            max_completion_tokens: None,
+            timeout: DEFAULT_TIMEOUT,
+        });
+    }
+    providers
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ChatRequest<'a> {
     model: &'a str,
     messages: Vec<serde_json::Value>,
@@ -74,11 +398,43 @@ struct ChatRequest<'a> {
     response_format: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    /// `{"include_usage": true}` on streamed requests; without it
+    /// OpenAI-compatible servers send no usage event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<serde_json::Value>,
 }
 
 #[derive(Deserialize)]
 struct ChatResponse {
     choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+#[derive(Deserialize, Default)]
+struct Usage {
+    #[serde(default)]
+    prompt_tokens: u64,
+    #[serde(default)]
+    completion_tokens: u64,
+    #[serde(default)]
+    total_tokens: u64,
+}
+
+impl Usage {
+    /// `(prompt, completion)`, counting a bare `total_tokens` as prompt.
+    fn split(&self) -> (u64, u64) {
+        if self.prompt_tokens + self.completion_tokens == 0 {
+            (self.total_tokens, 0)
+        } else {
+            (self.prompt_tokens, self.completion_tokens)
+        }
+    }
 }
 #[derive(Deserialize)]
 struct Choice {
@@ -89,174 +445,1802 @@ struct Message {
     content: String,
 }
 
-pub async fn chat_text(system: &str, user: &str) -> Result<String> {
-    let (key, base, model) = pick_provider(None)?;
-    let url = format!("{}/chat/completions", base.trim_end_matches('/'));
-    let req = ChatRequest {
-        model: &model,
-        messages: vec![
-            json!({"role":"system","content":system}),
-            json!({"role":"user","content":user}),
-        ],
-        response_format: None,
-        temperature: Some(0.2),
-    };
-    let res = HTTP
-        .post(&url)
-        .bearer_auth(&key)
-        .json(&req)
-        .send()
-        .await
-        .context("LLM HTTP error")?;
-    let status = res.status();
-    let body = res.text().await.unwrap_or_default();
-    if !status.is_success() {
-        return Err(anyhow!("LLM error {status}: {body}"));
+/// Non-streaming reply from Ollama's `/api/chat`.
+#[derive(Deserialize)]
+struct OllamaResponse {
+    message: Option<Message>,
+    #[serde(default)]
+    prompt_eval_count: u64,
+    #[serde(default)]
+    eval_count: u64,
+}
+
+/// One `data:` event of a streamed chat completion.
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    /// Only sent on the final event, and only by some providers.
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Delta,
+}
+#[derive(Deserialize, Default)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+/// Number of times `chat_json_result_with` re-asks the model after an unparseable reply.
+/// Override with `LLM_JSON_RETRIES`.
+const DEFAULT_JSON_RETRIES: usize = 2;
+
+/// Upper bound on how much of an invalid reply is echoed back when re-asking.
+const JSON_RETRY_ECHO_BYTES: usize = 4000;
+
+fn json_retries() -> usize {
+    config::env_var("LLM_JSON_RETRIES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_JSON_RETRIES)
+}
+
+/// A non-success HTTP reply from an LLM provider.
+#[derive(Debug)]
+pub struct LlmHttpError {
+    pub status: reqwest::StatusCode,
+    /// Server-requested delay from the `Retry-After` header, if any.
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+impl fmt::Display for LlmHttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LLM error {}: {}", self.status, self.body)
     }
-    let parsed: ChatResponse = serde_json::from_str(&body).context("parse LLM response")?;
-    let content = parsed
-        .choices
-        .get(0)
-        .map(|c| c.message.content.clone())
-        .unwrap_or_default();
-    Ok(content)
 }
 
-pub async fn chat_json<T: DeserializeOwned>(system: &str, user_json: &str) -> Result<T> {
-    let (key, base, model) = pick_provider(None)?;
-    let url = format!("{}/chat/completions", base.trim_end_matches('/'));
+impl std::error::Error for LlmHttpError {}
 
-    let req = ChatRequest {
-        model: &model,
-        messages: vec![
-            json!({"role":"system","content":system}),
-            json!({"role":"user","content":user_json}),
-        ],
-        response_format: Some(json!({"type":"json_object"})),
-        temperature: Some(0.0),
+/// What `send_chat` does after a failed HTTP request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryClass {
+    /// Transient (rate limit, server error): retry the same provider.
+    Retry,
+    /// The credentials were refused: retrying can't help, but another
+    /// configured provider might.
+    NextProvider,
+    /// The request itself is wrong (or the status is unknown): give up.
+    Fail,
+}
+
+/// Statuses retried by default: 429 and the usual transient 5xx replies.
+const DEFAULT_RETRY_STATUSES: [u16; 5] = [429, 500, 502, 503, 504];
+
+/// Statuses to retry: `SHELLCRAFT_RETRY_STATUSES` (comma-separated codes)
+/// when set, else `DEFAULT_RETRY_STATUSES`.
+fn retry_statuses() -> Vec<u16> {
+    match config::env_var("SHELLCRAFT_RETRY_STATUSES") {
+        Ok(list) => list
+            .split(',')
+            .filter_map(|code| code.trim().parse().ok())
+            .collect(),
+        Err(_) => DEFAULT_RETRY_STATUSES.to_vec(),
+    }
+}
+
+/// How to react to `status`: 401 and 403 move on to the next provider,
+/// retryable statuses (see `retry_statuses`) are retried, anything else fails.
+pub fn classify_status(status: reqwest::StatusCode) -> RetryClass {
+    if matches!(status.as_u16(), 401 | 403) {
+        RetryClass::NextProvider
+    } else if retry_statuses().contains(&status.as_u16()) {
+        RetryClass::Retry
+    } else {
+        RetryClass::Fail
+    }
+}
+
+impl LlmHttpError {
+    pub fn retry_class(&self) -> RetryClass {
+        classify_status(self.status)
+    }
+
+    async fn from_response(res: reqwest::Response) -> Self {
+        let status = res.status();
+        let retry_after = parse_retry_after(res.headers());
+        let body = res.text().await.unwrap_or_default();
+        Self {
+            status,
+            retry_after,
+            body,
+        }
+    }
+}
+
+/// `Retry-After` is either delay-seconds or an HTTP date.
+fn parse_retry_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let value = headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let delta = when.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(delta.to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Retryable failures (see `classify_status`) are retried this many times.
+const RATE_LIMIT_RETRIES: u32 = 3;
+/// Base delay for exponential backoff when no `Retry-After` is given.
+const RATE_LIMIT_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Never honour a `Retry-After` longer than this.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Tokens reported by providers across every call in this process.
+static PROMPT_TOKENS: AtomicU64 = AtomicU64::new(0);
+static COMPLETION_TOKENS: AtomicU64 = AtomicU64::new(0);
+/// Cap on prompt plus completion tokens; 0 means unlimited.
+static TOKEN_BUDGET: AtomicU64 = AtomicU64::new(0);
+
+/// `(prompt, completion)` tokens per model id, for pricing.
+static MODEL_USAGE: Lazy<Mutex<HashMap<String, (u64, u64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// USD price of a model's tokens, per thousand.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct Price {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+/// Model id → price, used to estimate spend in `budget_report`.
+pub type PriceTable = HashMap<String, Price>;
+
+static PRICES: Lazy<Mutex<PriceTable>> = Lazy::new(|| Mutex::new(PriceTable::new()));
+
+pub fn set_price_table(table: PriceTable) {
+    *PRICES.lock().unwrap() = table;
+}
+
+/// Refuse further LLM calls once `max` tokens have been spent (0 = unlimited).
+/// The call that crosses the cap completes; the next one is rejected.
+pub fn set_token_budget(max: u64) {
+    TOKEN_BUDGET.store(max, Ordering::Relaxed);
+}
+
+fn record_tokens(model: &str, prompt: u64, completion: u64) {
+    PROMPT_TOKENS.fetch_add(prompt, Ordering::Relaxed);
+    COMPLETION_TOKENS.fetch_add(completion, Ordering::Relaxed);
+    let mut usage = MODEL_USAGE.lock().unwrap();
+    let entry = usage.entry(model.to_string()).or_default();
+    entry.0 += prompt;
+    entry.1 += completion;
+}
+
+fn total_tokens() -> u64 {
+    PROMPT_TOKENS.load(Ordering::Relaxed) + COMPLETION_TOKENS.load(Ordering::Relaxed)
+}
+
+/// Prompt plus completion tokens reported so far in this process; compare
+/// readings taken around a call to see what it spent.
+pub fn tokens_used() -> u64 {
+    total_tokens()
+}
+
+/// Provider of the most recent chat request (e.g. "openai", "ollama").
+static LAST_PROVIDER: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Name of the provider the most recent chat request went to, if any.
+pub fn last_provider() -> Option<String> {
+    LAST_PROVIDER.lock().unwrap().clone()
+}
+
+fn check_budget() -> Result<()> {
+    let budget = TOKEN_BUDGET.load(Ordering::Relaxed);
+    if budget > 0 && total_tokens() >= budget {
+        return Err(anyhow!("token budget exceeded"));
+    }
+    Ok(())
+}
+
+/// Estimated USD spent so far, or `None` if a model that was used has no
+/// price in the table.
+fn estimated_cost() -> Option<f64> {
+    cost_of(&MODEL_USAGE.lock().unwrap(), &PRICES.lock().unwrap())
+}
+
+/// USD cost of `(prompt, completion)` tokens per model at `prices`, or `None`
+/// if a model has no price.
+fn cost_of(usage: &HashMap<String, (u64, u64)>, prices: &PriceTable) -> Option<f64> {
+    let mut usd = 0.0;
+    for (model, &(prompt, completion)) in usage.iter() {
+        let price = prices.get(model)?;
+        usd += prompt as f64 / 1000.0 * price.input_per_1k;
+        usd += completion as f64 / 1000.0 * price.output_per_1k;
+    }
+    Some(usd)
+}
+
+/// One-line summary of tokens spent so far, the budget if one is set, and the
+/// estimated cost.
+pub fn budget_report() -> String {
+    let used = total_tokens();
+    let tokens = match TOKEN_BUDGET.load(Ordering::Relaxed) {
+        0 => format!("{used} tokens used"),
+        budget => format!("{used} of {budget} tokens used"),
+    };
+    let cost = match estimated_cost() {
+        Some(usd) => format!("~${usd:.4}"),
+        None => "unknown".to_string(),
     };
+    format!(
+        "{tokens} ({} prompt, {} completion); cost {cost}",
+        PROMPT_TOKENS.load(Ordering::Relaxed),
+        COMPLETION_TOKENS.load(Ordering::Relaxed)
+    )
+}
 
-    let res = HTTP
-        .post(&url)
-        .bearer_auth(&key)
-        .json(&req)
-        .send()
-        .await
-        .context("LLM HTTP error")?;
-    let status = res.status();
-    let body = res.text().await.unwrap_or_default();
-    if !status.is_success() {
-        return Err(anyhow!("LLM error {status}: {body}"));
+/// Most responses kept in memory by the response cache.
+const CACHE_CAPACITY: usize = 128;
+
+/// Replies to previously seen prompts, keyed by a hash of provider, model and
+/// messages. Off unless enabled with `set_cache_enabled`.
+#[derive(Default)]
+struct ResponseCache {
+    enabled: bool,
+    /// Also persist entries here so they survive restarts.
+    dir: Option<PathBuf>,
+    entries: HashMap<u64, String>,
+    /// Keys from least to most recently used.
+    order: VecDeque<u64>,
+}
+
+impl ResponseCache {
+    fn touch(&mut self, key: u64) {
+        self.order.retain(|k| *k != key);
+        self.order.push_back(key);
+    }
+
+    fn get(&mut self, key: u64) -> Option<String> {
+        if let Some(hit) = self.entries.get(&key).cloned() {
+            self.touch(key);
+            return Some(hit);
+        }
+        let path = self.dir.as_ref()?.join(format!("{key:016x}.txt"));
+        let hit = fs::read_to_string(path).ok()?;
+        self.insert(key, hit.clone());
+        Some(hit)
+    }
+
+    fn insert(&mut self, key: u64, value: String) {
+        self.entries.insert(key, value);
+        self.touch(key);
+        while self.order.len() > CACHE_CAPACITY {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
     }
-    let parsed: ChatResponse = serde_json::from_str(&body).context("parse LLM response")?;
-    let content = parsed
-        .choices
-        .get(0)
-        .map(|c| c.message.content.clone())
-        .unwrap_or_else(|| "{}".into());
 
-    serde_json::from_str::<T>(&content)
-        .or_else(|_| Err(anyhow!("LLM did not return valid JSON: {}", content)))
+    fn put(&mut self, key: u64, value: &str) {
+        if let Some(dir) = self.dir.as_ref().filter(|_| !fsutil::is_read_only()) {
+            let path = dir.join(format!("{key:016x}.txt"));
+            if let Err(e) = fsutil::atomic_write(&path, value) {
+                eprintln!("warning: could not write {}: {e}", path.display());
+            }
+        }
+        self.insert(key, value.to_string());
+    }
 }
 
-pub async fn propose_edit(req: EditReq) -> Result<String> {
-    let system = r#"You are a code editor. Given a file path, the current full file, and an instruction, return the **entire new file content**. Do not add code fences or commentary. Output only the file content."#;
-    let user = format!(
-        "PATH: {}\n--- CURRENT FILE START ---\n{}\n--- CURRENT FILE END ---\nINSTRUCTION:\n{}\n",
-        req.file_path, req.file_content, req.instruction
-    );
-    let content = chat_text(system, &user).await?;
-    Ok(strip_code_fences(&content).to_string())
+static CACHE: Lazy<Mutex<ResponseCache>> = Lazy::new(|| Mutex::new(ResponseCache::default()));
+
+/// Turn the response cache on or off. Identical prompts to the same model are
+/// answered from the cache instead of calling the provider again.
+pub fn set_cache_enabled(enabled: bool) {
+    CACHE.lock().unwrap().enabled = enabled;
 }
 
-pub async fn propose_patch(log_tail: &str, _diff_hint: &str) -> Result<String> {
-    let system = r#"You are a code fixer. The user will give you an error log snippet. Produce a minimal unified diff patch (git-style) that fixes the error. No explanations or fences, just the patch text."#;
-    let user = format!("--- ERROR LOG (tail) ---\n{}\n", log_tail);
-    let content = chat_text(system, &user).await?;
-    Ok(strip_code_fences(&content).to_string())
+/// Persist cached responses under `dir` (or keep them in memory only).
+pub fn set_cache_dir(dir: Option<PathBuf>) {
+    CACHE.lock().unwrap().dir = dir;
 }
 
-fn strip_code_fences(s: &str) -> &str {
-    let t = s.trim();
-    if t.starts_with("```") {
-        if let Some(pos) = t.find('\n') {
-            let rest = &t[pos + 1..];
-            if let Some(end) = rest.rfind("```") {
-                return &rest[..end];
+/// Forget every cached response, including those on disk (except in
+/// read-only mode, which only forgets the ones in memory).
+pub fn clear_cache() {
+    let mut cache = CACHE.lock().unwrap();
+    cache.entries.clear();
+    cache.order.clear();
+    if let Some(dir) = cache.dir.as_ref().filter(|_| !fsutil::is_read_only()) {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+fn cache_key(provider: &Provider, req: &ChatRequest<'_>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    provider.name.hash(&mut hasher);
+    provider.base.hash(&mut hasher);
+    req.model.hash(&mut hasher);
+    serde_json::to_string(&req.messages)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    req.response_format.is_some().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Send a chat request to `provider` and return the first choice's content,
+/// retrying and falling through to other providers as `Fallback` decides.
+async fn send_chat(provider: &Provider, req: &ChatRequest<'_>) -> Result<Option<String>> {
+    send_chat_via(Fallback::new(provider), req).await
+}
+
+/// `send_chat` along an explicit provider chain.
+async fn send_chat_via(fallback: Fallback, req: &ChatRequest<'_>) -> Result<Option<String>> {
+    stream_chat_via(fallback, req, None).await
+}
+
+/// `send_chat_via`, passing the reply to `on_token` as it arrives when one is
+/// given. Only HTTP failures before the first token are retried or passed on
+/// to the next provider, so no token is delivered twice. Cached replies, and
+/// replies from providers that can't stream (Ollama), arrive in one piece.
+async fn stream_chat_via(
+    mut fallback: Fallback,
+    req: &ChatRequest<'_>,
+    mut on_token: Option<&mut (dyn FnMut(&str) + Send)>,
+) -> Result<Option<String>> {
+    let clamped;
+    let req = match clamp_max_tokens(fallback.provider(), req) {
+        Some(r) => {
+            clamped = r;
+            &clamped
+        }
+        None => req,
+    };
+    let key = CACHE
+        .lock()
+        .unwrap()
+        .enabled
+        .then(|| cache_key(fallback.provider(), req));
+    if let Some(hit) = key.and_then(|k| CACHE.lock().unwrap().get(k)) {
+        if let Some(on_token) = on_token {
+            on_token(&hit);
+        }
+        return Ok(Some(hit));
+    }
+    loop {
+        let provider = fallback.start()?;
+        let switched;
+        let req = if fallback.is_original() {
+            req
+        } else {
+            switched = ChatRequest {
+                model: &provider.model,
+                ..req.clone()
+            };
+            &switched
+        };
+        let result = match on_token.as_deref_mut() {
+            Some(on_token) if !provider.is_local() => {
+                stream_openai_chat(provider, req, on_token).await
+            }
+            Some(on_token) => send_ollama_chat(provider, req).await.inspect(|content| {
+                if let Some(c) = content.as_deref().filter(|c| !c.is_empty()) {
+                    on_token(c);
+                }
+            }),
+            None if provider.is_local() => send_ollama_chat(provider, req).await,
+            None => send_openai_chat(provider, req).await,
+        };
+        match result {
+            Ok(content) => {
+                // Only the requested provider's replies are cached under its key.
+                if let (Some(k), Some(c), true) = (key, &content, fallback.is_original()) {
+                    CACHE.lock().unwrap().put(k, c);
+                }
+                return Ok(content);
+            }
+            Err(err) => fallback.recover(err).await?,
+        }
+    }
+}
+
+/// The providers one request may go to, and where it is along them: the
+/// requested provider first, then the others configured in the environment.
+/// Retryable failures (see `classify_status`) are retried on the same
+/// provider after the server's `Retry-After` delay (or an exponential backoff
+/// when the header is absent); refused credentials move on to the next one.
+struct Fallback {
+    chain: Vec<Provider>,
+    current: usize,
+    attempt: u32,
+}
+
+impl Fallback {
+    fn new(provider: &Provider) -> Self {
+        let fallbacks = env_providers()
+            .into_iter()
+            .filter(|p| p.name != provider.name);
+        Self::with_chain(std::iter::once(provider.clone()).chain(fallbacks).collect())
+    }
+
+    fn with_chain(chain: Vec<Provider>) -> Self {
+        Self {
+            chain,
+            current: 0,
+            attempt: 0,
+        }
+    }
+
+    fn provider(&self) -> &Provider {
+        &self.chain[self.current]
+    }
+
+    /// Whether requests still go to the provider originally asked for.
+    fn is_original(&self) -> bool {
+        self.current == 0
+    }
+
+    /// The provider for the next attempt, once the token budget allows it;
+    /// recorded as `last_provider`.
+    fn start(&self) -> Result<&Provider> {
+        let provider = self.provider();
+        *LAST_PROVIDER.lock().unwrap() = Some(provider.name.clone());
+        check_budget()?;
+        Ok(provider)
+    }
+
+    /// After a failed attempt: wait and return `Ok` to try again (on the same
+    /// or the next provider), or return `err` when it is final.
+    async fn recover(&mut self, err: anyhow::Error) -> Result<()> {
+        let Some(http) = err.downcast_ref::<LlmHttpError>() else {
+            return Err(err);
+        };
+        match http.retry_class() {
+            RetryClass::Retry if self.attempt < RATE_LIMIT_RETRIES => {
+                let delay = http
+                    .retry_after
+                    .map(|d| d.min(MAX_RETRY_AFTER))
+                    .unwrap_or(RATE_LIMIT_BASE_DELAY * 2u32.pow(self.attempt));
+                tokio::time::sleep(delay).await;
+                self.attempt += 1;
+                Ok(())
+            }
+            RetryClass::NextProvider if self.current + 1 < self.chain.len() => {
+                eprintln!(
+                    "warning: {} refused the request ({}); trying {}",
+                    self.chain[self.current].name,
+                    http.status,
+                    self.chain[self.current + 1].name
+                );
+                self.current += 1;
+                self.attempt = 0;
+                Ok(())
             }
+            _ => Err(err),
         }
     }
-    t
 }
 
-pub async fn robust_chat_text(system: &str, user: &str) -> Result<String> {
-    let (key, base, model) = pick_provider(None)?;
-    let url = format!("{}/chat/completions", base.trim_end_matches('/'));
+/// A copy of `req` with `max_tokens` lowered to the model's completion cap,
+/// or `None` if it's already within it.
+fn clamp_max_tokens<'a>(provider: &Provider, req: &ChatRequest<'a>) -> Option<ChatRequest<'a>> {
+    let (want, cap) = (req.max_tokens?, provider.max_completion_tokens?);
+    if want <= cap {
+        return None;
+    }
+    eprintln!(
+        "warning: max_tokens {want} exceeds {}'s limit; clamped to {cap}",
+        provider.model
+    );
+    Some(ChatRequest {
+        max_tokens: Some(cap),
+        ..req.clone()
+    })
+}
+
+/// POST to an OpenAI-compatible `/chat/completions` endpoint.
+async fn send_openai_chat(provider: &Provider, req: &ChatRequest<'_>) -> Result<Option<String>> {
+    let url = format!("{}/chat/completions", provider.base.trim_end_matches('/'));
+    let mut builder = HTTP.post(&url).timeout(provider.timeout).json(req);
+    if !provider.key.is_empty() {
+        builder = builder.bearer_auth(&provider.key);
+    }
+    let res = builder.send().await.context("LLM HTTP error")?;
+    if !res.status().is_success() {
+        return Err(LlmHttpError::from_response(res).await.into());
+    }
+    let body = res.text().await.unwrap_or_default();
+    let parsed: ChatResponse = serde_json::from_str(&body).context("parse LLM response")?;
+    if let Some(usage) = &parsed.usage {
+        let (prompt, completion) = usage.split();
+        record_tokens(req.model, prompt, completion);
+    }
+    Ok(parsed.choices.first().map(|c| c.message.content.clone()))
+}
+
+/// Ollama's native chat endpoint: `POST {base}/api/chat` with
+/// `{model, messages, stream: false}`, answered by `{"message": {"content"}}`.
+async fn send_ollama_chat(provider: &Provider, req: &ChatRequest<'_>) -> Result<Option<String>> {
+    let url = format!("{}/api/chat", provider.base.trim_end_matches('/'));
+    let mut payload = json!({
+        "model": req.model,
+        "messages": req.messages,
+        "stream": false,
+    });
+    let mut options = serde_json::Map::new();
+    if let Some(t) = req.temperature {
+        options.insert("temperature".into(), json!(t));
+    }
+    if let Some(p) = req.top_p {
+        options.insert("top_p".into(), json!(p));
+    }
+    if let Some(n) = req.max_tokens {
+        options.insert("num_predict".into(), json!(n));
+    }
+    if !options.is_empty() {
+        payload["options"] = serde_json::Value::Object(options);
+    }
+    if req.response_format.is_some() {
+        payload["format"] = json!("json");
+    }
+    let mut builder = HTTP.post(&url).timeout(provider.timeout).json(&payload);
+    if !provider.key.is_empty() {
+        builder = builder.bearer_auth(&provider.key);
+    }
+    let res = builder.send().await.context("LLM HTTP error")?;
+    if !res.status().is_success() {
+        return Err(LlmHttpError::from_response(res).await.into());
+    }
+    let body = res.text().await.unwrap_or_default();
+    let parsed: OllamaResponse = serde_json::from_str(&body).context("parse LLM response")?;
+    record_tokens(req.model, parsed.prompt_eval_count, parsed.eval_count);
+    Ok(parsed.message.map(|m| m.content))
+}
+
+/// Ask `model` (else the session's) and return its whole reply.
+pub async fn chat_text_with(model: Option<&str>, system: &str, user: &str) -> Result<String> {
+    chat_text_stream_with(model, system, user, |_| {}).await
+}
+
+/// Outcome of `chat_json_result_with`.
+#[derive(Debug)]
+pub enum ChatJsonResult<T> {
+    /// The model replied with JSON of the expected shape.
+    Parsed(T),
+    /// The model never produced parseable JSON; `raw` is its last reply.
+    Fallback { raw: String },
+}
+
+/// Stream a chat completion, calling `on_token` with each content delta as it
+/// arrives. Returns the full concatenated text.
+pub async fn chat_text_stream(
+    system: &str,
+    user: &str,
+    on_token: impl FnMut(&str) + Send,
+) -> Result<String> {
+    chat_text_stream_with(None, system, user, on_token).await
+}
+
+/// `chat_text_stream` against a specific model instead of the session's.
+async fn chat_text_stream_with(
+    model: Option<&str>,
+    system: &str,
+    user: &str,
+    mut on_token: impl FnMut(&str) + Send,
+) -> Result<String> {
+    let provider = pick_provider(model)?;
     let req = ChatRequest {
-        model: &model,
+        model: &provider.model,
         messages: vec![
             json!({"role":"system","content":system}),
             json!({"role":"user","content":user}),
         ],
         response_format: None,
         temperature: Some(0.2),
+        top_p: None,
+        max_tokens: None,
+        stream: None,
+        stream_options: None,
     };
-    let mut res = HTTP
-        .post(&url)
-        .bearer_auth(&key)
-        .json(&req)
-        .send()
-        .await
-        .context("LLM HTTP error")?;
-    let mut status = res.status();
-    let mut body = res.text().await.unwrap_or_default();
-    if !status.is_success() {
-        return Err(anyhow!("LLM error {status}: {body}"));
-    }
-    let mut parsed: ChatResponse = serde_json::from_str(&body).context("parse LLM response")?;
-    let mut content = parsed
-        .choices
-        .get(0)
-        .map(|c| c.message.content.clone())
-        .unwrap_or_default();
-    if content.contains("README") {
-        let new_user = format!("{} Please do not return README file.", user);
-        res = HTTP
-            .post(&url)
-            .bearer_auth(&key)
-            .json(&ChatRequest {
-                model: &model,
-                messages: vec![
-                    json!({"role":"system","content":system}),
-                    json!({"role":"user","content":new_user}),
-                ],
-                response_format: None,
-                temperature: Some(0.2),
-            })
-            .send()
-            .await
-            .context("LLM HTTP error")?;
-        status = res.status();
-        body = res.text().await.unwrap_or_default();
-        if !status.is_success() {
-            return Err(anyhow!("LLM error {status}: {body}"));
-        }
-        parsed = serde_json::from_str(&body).context("parse LLM response")?;
-        content = parsed
-            .choices
-            .get(0)
-            .map(|c| c.message.content.clone())
-            .unwrap_or_default();
-    }
-    Ok(content)
+    let reply = stream_chat_via(Fallback::new(&provider), &req, Some(&mut on_token)).await?;
+    Ok(reply.unwrap_or_default())
 }
 
-pub async fn propose_edit_robust(req: EditReq) -> Result<String> {
-    let system = r#"You are a code editor. Given a file path, the current full file, and an instruction, return the **entire new file content**. Do not add code fences or commentary. Output only the file content."#;
-    let user = format!(
-        "PATH: {}\n--- CURRENT FILE START ---\n{}\n--- CURRENT FILE END ---\nINSTRUCTION:\n{}\n",
-        req.file_path, req.file_content, req.instruction
-    );
-    let content = robust_chat_text(system, &user).await?;
+/// `send_openai_chat` with `stream: true`: reads the SSE `data:` events and
+/// passes each content delta to `on_token`. Returns the full concatenated text.
+async fn stream_openai_chat(
+    provider: &Provider,
+    req: &ChatRequest<'_>,
+    on_token: &mut (dyn FnMut(&str) + Send),
+) -> Result<Option<String>> {
+    let req = ChatRequest {
+        stream: Some(true),
+        stream_options: Some(json!({"include_usage": true})),
+        ..req.clone()
+    };
+    let url = format!("{}/chat/completions", provider.base.trim_end_matches('/'));
+    let mut builder = HTTP.post(&url).timeout(provider.timeout).json(&req);
+    if !provider.key.is_empty() {
+        builder = builder.bearer_auth(&provider.key);
+    }
+    let mut res = builder.send().await.context("LLM HTTP error")?;
+    if !res.status().is_success() {
+        return Err(LlmHttpError::from_response(res).await.into());
+    }
+
+    // SSE events are newline-delimited `data: {...}` lines; a network chunk
+    // may end mid-line, so keep the unterminated remainder for the next one.
+    let mut pending: Vec<u8> = Vec::new();
+    let mut full = String::new();
+    'read: while let Some(chunk) = res.chunk().await.context("LLM stream error")? {
+        pending.extend_from_slice(&chunk);
+        while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                break 'read;
+            }
+            let Ok(event) = serde_json::from_str::<StreamChunk>(data) else {
+                continue;
+            };
+            if let Some(usage) = &event.usage {
+                let (prompt, completion) = usage.split();
+                record_tokens(req.model, prompt, completion);
+            }
+            if let Some(token) = event
+                .choices
+                .first()
+                .and_then(|c| c.delta.content.as_deref())
+            {
+                if !token.is_empty() {
+                    on_token(token);
+                    full.push_str(token);
+                }
+            }
+        }
+    }
+    Ok(Some(full))
+}
+
+/// Ask `model` (else the session's) for a JSON reply of type `T`, re-asking
+/// after an unparseable one. A reply that never parses is reported as
+/// `ChatJsonResult::Fallback` so callers can show the user what the model said.
+pub async fn chat_json_result_with<T: DeserializeOwned>(
+    model: Option<&str>,
+    system: &str,
+    user_json: &str,
+) -> Result<ChatJsonResult<T>> {
+    let provider = pick_provider(model)?;
+
+    let mut messages = vec![
+        json!({"role":"system","content":system}),
+        json!({"role":"user","content":user_json}),
+    ];
+    let retries = json_retries();
+    let mut attempt = 0;
+    loop {
+        let req = ChatRequest {
+            model: &provider.model,
+            messages: messages.clone(),
+            response_format: Some(json!({"type":"json_object"})),
+            temperature: Some(0.0),
+            top_p: None,
+            max_tokens: None,
+            stream: None,
+            stream_options: None,
+        };
+        let content = send_chat(&provider, &req)
+            .await?
+            .unwrap_or_else(|| "{}".into());
+
+        let err = match serde_json::from_str::<T>(strip_code_fences(&content)) {
+            Ok(v) => return Ok(ChatJsonResult::Parsed(v)),
+            Err(e) => e,
+        };
+        if attempt >= retries {
+            return Ok(ChatJsonResult::Fallback { raw: content });
+        }
+        attempt += 1;
+
+        // Re-ask with the decode error and (a bounded slice of) the bad reply.
+        messages.push(json!({
+            "role": "assistant",
+            "content": truncate_utf8(&content, JSON_RETRY_ECHO_BYTES),
+        }));
+        messages.push(json!({
+            "role": "user",
+            "content": format!(
+                "Your previous response was not valid JSON ({err}). Return only the JSON object, no prose or code fences."
+            ),
+        }));
+    }
+}
+
+/// Truncate `s` to at most `max` bytes without splitting a UTF-8 character.
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+pub async fn propose_edit(req: EditReq) -> Result<String> {
+    let chunk_lines = EDIT_CHUNK_LINES.load(Ordering::Relaxed);
+    if let Some(region) = edit_region(&req.file_content, &req.instruction, chunk_lines) {
+        return propose_region_edit(&req, region).await;
+    }
+    let system = r#"You are a code editor. Given a file path, the current full file, and an instruction, return the **entire new file content**. Do not add code fences or commentary. Output only the file content."#;
+    let user = format!(
+        "PATH: {}\n--- CURRENT FILE START ---\n{}\n--- CURRENT FILE END ---\nINSTRUCTION:\n{}\n",
+        req.file_path, req.file_content, req.instruction
+    );
+    let content = send_edit(&req, system, &user, &req.file_content).await?;
+    Ok(strip_code_fences(&content).to_string())
+}
+
+/// Edit only lines `start..end` of a large file, then splice the model's
+/// replacement back into the full content. A reply that is evidently the
+/// whole file is used as the whole file.
+async fn propose_region_edit(req: &EditReq, (start, end): (usize, usize)) -> Result<String> {
+    let lines: Vec<&str> = req.file_content.split_inclusive('\n').collect();
+    let excerpt = lines[start..end].concat();
+    let system = r#"You are a code editor. Given a file path, an excerpt of the file with its line numbers, and an instruction, return the **new content of the excerpt only**, to replace those lines exactly. Do not repeat the rest of the file. Do not add code fences or commentary."#;
+    let user = format!(
+        "PATH: {} (lines {}-{} of {})\n--- EXCERPT START ---\n{}\n--- EXCERPT END ---\nINSTRUCTION:\n{}\n",
+        req.file_path,
+        start + 1,
+        end,
+        lines.len(),
+        excerpt,
+        req.instruction
+    );
+    let content = send_edit(req, system, &user, &excerpt).await?;
+    Ok(splice_region(
+        &req.file_content,
+        (start, end),
+        strip_code_fences(&content),
+    ))
+}
+
+/// Send one edit request, sized for rewriting `rewritten`.
+async fn send_edit(req: &EditReq, system: &str, user: &str, rewritten: &str) -> Result<String> {
+    let (default_temperature, default_top_p) = *EDIT_SAMPLING.lock().unwrap();
+    let provider = pick_provider(model_for_task(TaskType::Code).as_deref())?;
+    let chat = ChatRequest {
+        model: &provider.model,
+        messages: vec![
+            json!({"role":"system","content":system}),
+            json!({"role":"user","content":user}),
+        ],
+        response_format: None,
+        temperature: req
+            .temperature
+            .or(default_temperature)
+            .or(Some(DEFAULT_EDIT_TEMPERATURE)),
+        top_p: req.top_p.or(default_top_p),
+        max_tokens: Some(edit_max_tokens(rewritten)),
+        stream: None,
+        stream_options: None,
+    };
+    Ok(send_chat(&provider, &chat).await?.unwrap_or_default())
+}
+
+/// Lines of context kept around the lines an instruction mentions.
+const EDIT_REGION_CONTEXT: usize = 40;
+
+/// The line range `start..end` of `content` worth sending for `instruction`
+/// when the file has more than `max_lines` lines: the lines mentioning the
+/// instruction's identifiers (words of three or more characters) most often,
+/// widened by up to `EDIT_REGION_CONTEXT` and capped at `max_lines`; the
+/// matched lines always stay inside it. `None` means send the whole file: it
+/// is short enough, or nothing in it matches.
+fn edit_region(content: &str, instruction: &str, max_lines: usize) -> Option<(usize, usize)> {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    if max_lines == 0 || lines.len() <= max_lines {
+        return None;
+    }
+    let keywords: Vec<&str> = instruction
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|w| w.len() >= 3)
+        .collect();
+    let score = |line: &str| keywords.iter().filter(|k| line.contains(*k)).count();
+    let best = lines.iter().map(|l| score(l)).max().filter(|&s| s > 0)?;
+    let hits: Vec<usize> = (0..lines.len())
+        .filter(|&i| score(lines[i]) == best)
+        .collect();
+    let first = hits[0];
+    let last = hits
+        .iter()
+        .copied()
+        .take_while(|&i| i < first + max_lines)
+        .last()
+        .unwrap_or(first);
+    // Leading context gets at most half the room the matches leave.
+    let spare = max_lines - (last - first + 1);
+    let start = first.saturating_sub(EDIT_REGION_CONTEXT.min(spare / 2));
+    let end = (last + EDIT_REGION_CONTEXT + 1)
+        .min(start + max_lines)
+        .min(lines.len());
+    Some((start, end))
+}
+
+/// `content` with lines `start..end` replaced by `fragment`. When `fragment`
+/// looks like the whole file instead (it begins with the file's first line
+/// and is longer than the excerpt by more than half of the lines outside it),
+/// it replaces the whole content.
+fn splice_region(content: &str, (start, end): (usize, usize), fragment: &str) -> String {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let outside = lines.len() - (end - start);
+    let fragment_lines = fragment.lines().count();
+    let first_line = lines.first().map_or("", |l| l.trim_end());
+    if fragment_lines > (end - start) + outside / 2 && fragment.starts_with(first_line) {
+        return fragment.to_string();
+    }
+    let mut out = lines[..start].concat();
+    out.push_str(fragment);
+    let excerpt_ends_line = lines[end - 1].ends_with('\n');
+    if excerpt_ends_line && !fragment.is_empty() && !fragment.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&lines[end..].concat());
+    out
+}
+
+/// Completion budget for rewriting a file: room for the whole file to roughly
+/// double, at about four bytes per token.
+fn edit_max_tokens(file_content: &str) -> u32 {
+    let estimate = file_content.len() / 2 + 1024;
+    estimate.min(u32::MAX as usize) as u32
+}
+
+/// Embedding model used when `EMBEDDING_MODEL` isn't set.
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+/// Most texts sent in one `/embeddings` request.
+const EMBED_BATCH: usize = 256;
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+#[derive(Deserialize)]
+struct Embedding {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// The model `embed` uses: `EMBEDDING_MODEL`, else `text-embedding-3-small`.
+pub fn embedding_model() -> String {
+    config::env_var("EMBEDDING_MODEL").unwrap_or_else(|_| DEFAULT_EMBEDDING_MODEL.to_string())
+}
+
+/// Embed `texts` with the session provider's OpenAI-compatible `/embeddings`
+/// endpoint (`/v1/embeddings` on a local Ollama server), using
+/// `embedding_model`. Returns one vector per text, in order. Failures are
+/// retried and passed on to other providers as for chat (see `Fallback`).
+pub async fn embed(texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let provider = pick_provider(None)?;
+    embed_via(Fallback::new(&provider), texts).await
+}
+
+/// `embed` along an explicit provider chain. Refuses up front when the texts
+/// (at about four bytes per token) would take the session past its budget.
+async fn embed_via(mut fallback: Fallback, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let budget = TOKEN_BUDGET.load(Ordering::Relaxed);
+    let estimate = texts.iter().map(|t| t.len() as u64 / 4 + 1).sum::<u64>();
+    if budget > 0 && total_tokens() + estimate > budget {
+        return Err(anyhow!(
+            "embedding about {estimate} tokens would exceed the token budget ({})",
+            budget_report()
+        ));
+    }
+    let model = embedding_model();
+    let mut vectors = Vec::with_capacity(texts.len());
+    while vectors.len() < texts.len() {
+        let provider = fallback.start()?;
+        let batch = &texts[vectors.len()..texts.len().min(vectors.len() + EMBED_BATCH)];
+        match send_embeddings(provider, &model, batch).await {
+            Ok(batch_vectors) => vectors.extend(batch_vectors),
+            Err(err) => {
+                let provider = fallback.current;
+                fallback.recover(err).await?;
+                // Vectors from different providers can't be compared.
+                if fallback.current != provider {
+                    vectors.clear();
+                }
+            }
+        }
+    }
+    Ok(vectors)
+}
+
+/// One `/embeddings` request for `batch`.
+async fn send_embeddings(
+    provider: &Provider,
+    model: &str,
+    batch: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let base = provider.base.trim_end_matches('/');
+    let url = if provider.is_local() {
+        format!("{base}/v1/embeddings")
+    } else {
+        format!("{base}/embeddings")
+    };
+    let payload = json!({ "model": model, "input": batch });
+    let mut builder = HTTP.post(&url).timeout(provider.timeout).json(&payload);
+    if !provider.key.is_empty() {
+        builder = builder.bearer_auth(&provider.key);
+    }
+    let res = builder.send().await.context("LLM HTTP error")?;
+    if !res.status().is_success() {
+        return Err(LlmHttpError::from_response(res).await.into());
+    }
+    let body = res.text().await.unwrap_or_default();
+    let parsed: EmbeddingResponse =
+        serde_json::from_str(&body).context("parse embeddings response")?;
+    if let Some(usage) = &parsed.usage {
+        record_tokens(model, usage.split().0, 0);
+    }
+    if parsed.data.len() != batch.len() {
+        return Err(anyhow!(
+            "expected {} embeddings, got {}",
+            batch.len(),
+            parsed.data.len()
+        ));
+    }
+    let mut data = parsed.data;
+    data.sort_by_key(|e| e.index);
+    Ok(data.into_iter().map(|e| e.embedding).collect())
+}
+
+/// How much of the failing log (its tail) and of the working-tree diff are
+/// included in a patch request.
+const PATCH_LOG_TAIL_BYTES: usize = 8000;
+const PATCH_DIFF_BYTES: usize = 8000;
+
+/// Ask the model for a minimal unified diff that fixes the failure in
+/// `log_tail`, given the current working-tree `diff_hint` (`git diff`).
+pub async fn propose_patch(log_tail: &str, diff_hint: &str) -> Result<String> {
+    propose_patch_with(None, log_tail, diff_hint).await
+}
+
+/// `propose_patch` against a specific model instead of the session's.
+pub async fn propose_patch_with(
+    model: Option<&str>,
+    log_tail: &str,
+    diff_hint: &str,
+) -> Result<String> {
+    let system = r#"You are a code fixer. The user will give you an error log snippet and the current uncommitted diff of the repository. Produce a minimal unified diff patch (git-style) against the current working tree that fixes the error. No explanations or fences, just the patch text."#;
+    let mut user = format!(
+        "--- ERROR LOG (tail) ---\n{}\n",
+        tail_utf8(log_tail, PATCH_LOG_TAIL_BYTES)
+    );
+    if !diff_hint.trim().is_empty() {
+        user.push_str(&format!(
+            "--- CURRENT DIFF ---\n{}\n",
+            truncate_utf8(diff_hint, PATCH_DIFF_BYTES)
+        ));
+    }
+    let model = model
+        .map(str::to_string)
+        .or_else(|| model_for_task(TaskType::Code));
+    let content = chat_text_with(model.as_deref(), system, &user).await?;
     Ok(strip_code_fences(&content).to_string())
 }
+
+/// The last `max` bytes of `s`, without splitting a UTF-8 character.
+fn tail_utf8(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut start = s.len() - max;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+/// The body of `s` when the whole reply is one fenced code block (```` ``` ````
+/// or `~~~`, with or without an info string such as `rust`), unwrapping a
+/// second fence nested directly inside the first. Anything else, including
+/// replies that merely contain fences or inline backticks, is returned as is.
+fn strip_code_fences(s: &str) -> &str {
+    unwrap_fence(s.trim()).unwrap_or(s)
+}
+
+/// The content between the opening and closing fence lines of `t`, if `t` is
+/// exactly one fenced block (or a fenced block wrapping another).
+fn unwrap_fence(t: &str) -> Option<&str> {
+    let fence_char = t.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let (open, rest) = t.split_once('\n')?;
+    let width = open.chars().take_while(|c| *c == fence_char).count();
+    if width < 3 || open[width..].contains(fence_char) {
+        return None;
+    }
+    let (body, close) = rest.rsplit_once('\n').unwrap_or(("", rest));
+    let closes = |line: &str| {
+        let line = line.trim();
+        line.len() >= width && line.chars().all(|c| c == fence_char)
+    };
+    if !closes(close) {
+        return None;
+    }
+    let body = if body.is_empty() {
+        body
+    } else {
+        &rest[..body.len() + 1]
+    };
+    if body.lines().any(closes) {
+        // A fence closes early, so this is several blocks, unless the
+        // body is itself a single block (a doubly fenced reply).
+        return unwrap_fence(trim_blank_lines(body));
+    }
+    Some(body)
+}
+
+/// `s` without its leading and trailing blank lines. Unlike `str::trim`, the
+/// first remaining line keeps its indentation.
+fn trim_blank_lines(s: &str) -> &str {
+    let blank = s.len() - s.trim_start().len();
+    let start = s[..blank].rfind('\n').map_or(0, |i| i + 1);
+    s[start..].trim_end()
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    //! A scripted stand-in for a provider's HTTP API, for unit tests.
+
+    use super::{Provider, DEFAULT_TIMEOUT};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::{Mutex as AsyncMutex, MutexGuard};
+
+    /// One canned HTTP reply.
+    pub struct Reply {
+        status: u16,
+        content_type: &'static str,
+        headers: Vec<(&'static str, String)>,
+        body: String,
+        /// Sent after `body` only when the request asked for usage with
+        /// `stream_options.include_usage`, as OpenAI does.
+        stream_usage: Option<String>,
+        /// How long the server waits before answering.
+        delay: Duration,
+    }
+
+    impl Reply {
+        pub fn new(status: u16, body: impl Into<String>) -> Self {
+            Self {
+                status,
+                content_type: "application/json",
+                headers: Vec::new(),
+                body: body.into(),
+                stream_usage: None,
+                delay: Duration::ZERO,
+            }
+        }
+
+        pub fn header(mut self, name: &'static str, value: &str) -> Self {
+            self.headers.push((name, value.to_string()));
+            self
+        }
+
+        /// Answer only after `delay`, e.g. to outlast a client's timeout.
+        pub fn delayed(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+
+        /// A chat completion answering `content`, reporting 7 prompt and 5
+        /// completion tokens.
+        pub fn chat(content: &str) -> Self {
+            let body = serde_json::json!({
+                "choices": [{"message": {"content": content}}],
+                "usage": {"prompt_tokens": 7, "completion_tokens": 5},
+            });
+            Self::new(200, body.to_string())
+        }
+
+        /// An embeddings reply with one entry per vector, in order.
+        pub fn embeddings(vectors: &[&[f32]]) -> Self {
+            let data: Vec<_> = vectors
+                .iter()
+                .enumerate()
+                .map(|(index, v)| serde_json::json!({"index": index, "embedding": v}))
+                .collect();
+            Self::new(200, serde_json::json!({ "data": data }).to_string())
+        }
+
+        /// A streamed chat completion sending each of `tokens` as its own SSE
+        /// event, then (if the request asked for it) a usage event reporting
+        /// 7 prompt and 5 completion tokens, then `[DONE]`.
+        pub fn stream(tokens: &[&str]) -> Self {
+            let mut body = String::new();
+            for token in tokens {
+                let event = serde_json::json!({"choices": [{"delta": {"content": token}}]});
+                body.push_str(&format!("data: {event}\n\n"));
+            }
+            let usage = serde_json::json!({
+                "choices": [],
+                "usage": {"prompt_tokens": 7, "completion_tokens": 5},
+            });
+            Self {
+                content_type: "text/event-stream",
+                stream_usage: Some(format!("data: {usage}\n\n")),
+                ..Self::new(200, body)
+            }
+        }
+
+        /// The body sent in answer to `request`.
+        fn body_for(&self, request: &Request) -> String {
+            let Some(usage) = &self.stream_usage else {
+                return self.body.clone();
+            };
+            let asked = serde_json::from_str::<serde_json::Value>(&request.body)
+                .is_ok_and(|req| req["stream_options"]["include_usage"] == true);
+            let usage = if asked { usage.as_str() } else { "" };
+            format!("{}{usage}data: [DONE]\n\n", self.body)
+        }
+    }
+
+    /// A request the server received.
+    #[derive(Debug, Clone)]
+    pub struct Request {
+        pub path: String,
+        pub body: String,
+    }
+
+    /// A local server answering each connection with the next of its replies;
+    /// the last reply is repeated once the others are used up. Connections
+    /// are answered concurrently, so delayed replies overlap.
+    pub struct Server {
+        pub base: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl Server {
+        pub fn start(replies: Vec<Reply>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let seen = requests.clone();
+            let replies = Arc::new(replies);
+            std::thread::spawn(move || {
+                for (i, stream) in listener.incoming().enumerate() {
+                    let Ok(mut stream) = stream else { continue };
+                    let (replies, seen) = (replies.clone(), seen.clone());
+                    std::thread::spawn(move || {
+                        let Some(request) = read_request(&mut stream) else {
+                            return;
+                        };
+                        let reply = &replies[i.min(replies.len() - 1)];
+                        let body = reply.body_for(&request);
+                        seen.lock().unwrap().push(request);
+                        std::thread::sleep(reply.delay);
+                        let mut head = format!(
+                            "HTTP/1.1 {} Mock\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                            reply.status,
+                            reply.content_type,
+                            body.len()
+                        );
+                        for (name, value) in &reply.headers {
+                            head.push_str(&format!("{name}: {value}\r\n"));
+                        }
+                        head.push_str("\r\n");
+                        let _ = stream.write_all(head.as_bytes());
+                        let _ = stream.write_all(body.as_bytes());
+                    });
+                }
+            });
+            Self { base, requests }
+        }
+
+        /// Requests received so far, oldest first.
+        pub fn requests(&self) -> Vec<Request> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// A provider called `name` whose requests come here.
+        pub(super) fn provider(&self, name: &str) -> Provider {
+            Provider {
+                name: name.to_string(),
+                key: "test-key".to_string(),
+                base: self.base.clone(),
+                model: "mock-model".to_string(),
+                max_completion_tokens: None,
+                timeout: DEFAULT_TIMEOUT,
+            }
+        }
+    }
+
+    fn read_request(stream: &mut std::net::TcpStream) -> Option<Request> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let path = line.split_whitespace().nth(1)?.to_string();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse().ok()?;
+                }
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+        Some(Request {
+            path,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    /// Serializes tests that depend on process-wide LLM state (token
+    /// counters, the last provider). Async, as the tests hold it across
+    /// their requests.
+    static EXCLUSIVE: AsyncMutex<()> = AsyncMutex::const_new(());
+
+    pub async fn exclusive() -> MutexGuard<'static, ()> {
+        EXCLUSIVE.lock().await
+    }
+
+    static ROUTED: Mutex<Option<Provider>> = Mutex::new(None);
+
+    /// Sends every request that would pick a provider from the registry to
+    /// `server` instead, as a provider called `name`, until the guard drops.
+    /// A model asked for by name is kept. Holds `exclusive()` meanwhile.
+    pub async fn route_to(server: &Server, name: &str) -> Route {
+        let lock = exclusive().await;
+        *ROUTED.lock().unwrap() = Some(server.provider(name));
+        Route { _lock: lock }
+    }
+
+    pub struct Route {
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Drop for Route {
+        fn drop(&mut self) {
+            *ROUTED.lock().unwrap() = None;
+        }
+    }
+
+    pub(super) fn routed() -> Option<Provider> {
+        ROUTED.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{Reply, Server};
+    use super::*;
+
+    fn chat_request(model: &str) -> ChatRequest<'_> {
+        ChatRequest {
+            model,
+            messages: vec![json!({"role":"user","content":"hi"})],
+            response_format: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            stream: None,
+            stream_options: None,
+        }
+    }
+
+    #[test]
+    fn statuses_are_classified() {
+        for code in [429, 500, 502, 503, 504] {
+            let status = reqwest::StatusCode::from_u16(code).unwrap();
+            assert_eq!(classify_status(status), RetryClass::Retry, "{code}");
+        }
+        for code in [401, 403] {
+            let status = reqwest::StatusCode::from_u16(code).unwrap();
+            assert_eq!(classify_status(status), RetryClass::NextProvider, "{code}");
+        }
+        for code in [400, 404, 422] {
+            let status = reqwest::StatusCode::from_u16(code).unwrap();
+            assert_eq!(classify_status(status), RetryClass::Fail, "{code}");
+        }
+    }
+
+    #[test]
+    fn models_with_an_unknown_provider_are_reported() {
+        let registry: ModelRegistry = serde_json::from_str(
+            r#"{
+                "default_model": "gpt-4o-mini",
+                "models": [
+                    {"id": "gpt-4o-mini", "provider": "openai", "api_key": "k"},
+                    {"id": "typo", "provider": "opnai", "api_key": "k"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let (checks, general) = validate_registry(&registry);
+
+        assert!(general.is_empty(), "{general:?}");
+        assert!(checks[0].problems.is_empty(), "{:?}", checks[0].problems);
+        assert_eq!(checks[1].base, None);
+        assert!(
+            checks[1].problems[0].contains("unknown provider `opnai`"),
+            "{:?}",
+            checks[1].problems
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_config_checks_name_a_misspelled_provider() {
+        let _guard = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        let write = |provider: &str| {
+            let config = json!({
+                "default_model": "claude",
+                "models": [{"id": "claude", "provider": provider, "api_key_env": "X"}],
+            });
+            std::fs::write(&path, config.to_string()).unwrap();
+        };
+        // Load the session's registry first so it never sees this config.
+        Lazy::force(&MODEL_REGISTRY);
+        config::set_env_override("MODEL_CONFIG", &path.to_string_lossy());
+
+        write("anthropc");
+        let typo = check_model_config();
+        write("anthropic");
+        let fixed = check_model_config();
+        config::set_env_override("MODEL_CONFIG", "models.json");
+
+        let err = format!("{:#}", typo.unwrap_err());
+        assert!(
+            err.contains("model `claude` has unknown provider `anthropc`"),
+            "{err}"
+        );
+        assert!(fixed.is_ok(), "{fixed:?}");
+    }
+
+    #[tokio::test]
+    async fn unauthorized_falls_through_to_the_next_provider() {
+        let _guard = mock::exclusive().await;
+        let refused = Server::start(vec![Reply::new(401, r#"{"error":"bad key"}"#)]);
+        let backup = Server::start(vec![Reply::chat("from groq")]);
+        let chain = vec![refused.provider("openai"), backup.provider("groq")];
+
+        let reply = send_chat_via(Fallback::with_chain(chain), &chat_request("mock-model"))
+            .await
+            .unwrap();
+
+        assert_eq!(reply.as_deref(), Some("from groq"));
+        assert_eq!(refused.requests().len(), 1);
+        let sent = backup.requests();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].path, "/chat/completions");
+        assert!(sent[0].body.contains("mock-model"));
+        assert_eq!(last_provider().as_deref(), Some("groq"));
+    }
+
+    #[tokio::test]
+    async fn local_providers_speak_the_ollama_chat_api() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::new(
+            200,
+            r#"{"message":{"role":"assistant","content":"from ollama"},"done":true}"#,
+        )]);
+        let chain = vec![server.provider("ollama")];
+
+        let reply = send_chat_via(Fallback::with_chain(chain), &chat_request("llama3"))
+            .await
+            .unwrap();
+
+        assert_eq!(reply.as_deref(), Some("from ollama"));
+        let sent = server.requests();
+        assert_eq!(sent[0].path, "/api/chat");
+        let body: serde_json::Value = serde_json::from_str(&sent[0].body).unwrap();
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["messages"][0]["content"], "hi");
+    }
+
+    #[tokio::test]
+    async fn max_tokens_over_the_models_cap_are_clamped() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::chat("ok")]);
+        let provider = Provider {
+            max_completion_tokens: Some(1024),
+            ..server.provider("openai")
+        };
+        let req = ChatRequest {
+            max_tokens: Some(8000),
+            ..chat_request("mock-model")
+        };
+
+        send_chat_via(Fallback::with_chain(vec![provider]), &req)
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(body["max_tokens"], 1024);
+    }
+
+    #[tokio::test]
+    async fn requests_time_out_after_the_providers_timeout() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::chat("late").delayed(Duration::from_secs(2))]);
+        let provider = Provider {
+            timeout: Duration::from_millis(200),
+            ..server.provider("openai")
+        };
+
+        let started = std::time::Instant::now();
+        let err = send_chat_via(Fallback::with_chain(vec![provider]), &chat_request("m"))
+            .await
+            .unwrap_err();
+
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let timed_out = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+            .any(reqwest::Error::is_timeout);
+        assert!(timed_out, "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_retried_on_the_same_provider() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![
+            Reply::new(429, "slow down").header("Retry-After", "0"),
+            Reply::chat("ok"),
+        ]);
+        let chain = vec![server.provider("openai")];
+
+        let reply = send_chat_via(Fallback::with_chain(chain), &chat_request("mock-model"))
+            .await
+            .unwrap();
+
+        assert_eq!(reply.as_deref(), Some("ok"));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_wait_for_the_servers_retry_after() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![
+            Reply::new(429, "slow down").header("Retry-After", "2"),
+            Reply::chat("ok"),
+        ]);
+        let chain = vec![server.provider("openai")];
+
+        let started = std::time::Instant::now();
+        let reply = send_chat_via(Fallback::with_chain(chain), &chat_request("mock-model"))
+            .await
+            .unwrap();
+
+        assert_eq!(reply.as_deref(), Some("ok"));
+        // Two seconds, not the 500ms of the first exponential backoff step.
+        assert!(started.elapsed() >= Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn cancelling_abandons_a_slow_request() {
+        let _guard = mock::exclusive().await;
+        let slow = Duration::from_secs(5);
+        let server = Server::start(vec![Reply::chat("too late").delayed(slow)]);
+        let chain = vec![server.provider("openai")];
+        let running = AtomicBool::new(true);
+        let request = chat_request("mock-model");
+        let ctrl_c = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            running.store(false, Ordering::SeqCst);
+            signal_cancel();
+        };
+
+        let started = std::time::Instant::now();
+        let send = send_chat_via(Fallback::with_chain(chain), &request);
+        let (result, ()) = tokio::join!(until_cancelled(&running, send), ctrl_c);
+
+        let err = result.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Failure::Interrupted(_))));
+        assert!(started.elapsed() < slow, "{:?}", started.elapsed());
+        // Once cancelled, new requests don't start at all.
+        let again = until_cancelled(&running, async {}).await;
+        assert!(again.is_err());
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_are_passed_on_token_by_token() {
+        let server = Server::start(vec![
+            Reply::new(429, "slow down").header("Retry-After", "0"),
+            Reply::stream(&["Hel", "lo, ", "world"]),
+        ]);
+        let _route = mock::route_to(&server, "openai").await;
+
+        let mut tokens = Vec::new();
+        let text = chat_text_stream("system", "hi", |t| tokens.push(t.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(tokens, ["Hel", "lo, ", "world"]);
+        assert_eq!(text, tokens.concat());
+        let sent = server.requests();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].body.contains(r#""stream":true"#));
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_ask_for_and_record_usage() {
+        let server = Server::start(vec![Reply::stream(&["ok"])]);
+        let _route = mock::route_to(&server, "openai").await;
+        let before = tokens_used();
+
+        chat_text_with(None, "system", "hi").await.unwrap();
+
+        // The mock only reports usage when the request asks for it.
+        assert_eq!(tokens_used() - before, 12);
+        let sent: serde_json::Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(sent["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn fenced_replies_are_unwrapped_keeping_their_indentation() {
+        let code = "    indented();\n}\n";
+        assert_eq!(strip_code_fences(&format!("```rust\n{code}```\n")), code);
+        assert_eq!(
+            strip_code_fences(&format!("```\n\n```rust\n{code}```\n\n```")),
+            code
+        );
+        let clean = "    let s = \"`not a fence`\";\n";
+        assert_eq!(strip_code_fences(clean), clean);
+    }
+
+    #[test]
+    fn edit_regions_keep_the_matches_when_smaller_than_the_context() {
+        let content: String = (0..500).map(|i| format!("let v{i} = {i};\n")).collect();
+
+        let (start, end) = edit_region(&content, "double v300", 10).unwrap();
+
+        assert!(start <= 300 && 300 < end, "{start}..{end}");
+        assert_eq!(end - start, 10);
+    }
+
+    #[tokio::test]
+    async fn large_files_only_change_the_targeted_region() {
+        let content: String = (0..1000)
+            .map(|i| format!("fn f{i}() -> u32 {{ {i} }}\n"))
+            .collect();
+        let instruction = "rename f500 to renamed";
+        let (start, end) = edit_region(&content, instruction, 30).unwrap();
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+        let fragment = lines[start..end]
+            .concat()
+            .replace("fn f500()", "fn renamed()");
+        let server = Server::start(vec![Reply::chat(&fragment)]);
+        let _route = mock::route_to(&server, "openai").await;
+        set_edit_chunk_lines(30);
+
+        let edited = propose_edit(EditReq {
+            file_path: "src/big.rs".into(),
+            file_content: content.clone(),
+            instruction: instruction.into(),
+            ..EditReq::default()
+        })
+        .await;
+        set_edit_chunk_lines(400);
+
+        assert_eq!(
+            edited.unwrap(),
+            content.replace("fn f500()", "fn renamed()")
+        );
+        let sent = &server.requests()[0].body;
+        assert!(sent.contains("fn f500()"));
+        assert!(!sent.contains("fn f0()"), "the whole file was sent");
+    }
+
+    #[tokio::test]
+    async fn configured_edit_sampling_applies_unless_the_request_overrides_it() {
+        let server = Server::start(vec![Reply::chat("new\n")]);
+        let _route = mock::route_to(&server, "openai").await;
+        let edit = |temperature| EditReq {
+            file_path: "a.txt".into(),
+            file_content: "old\n".into(),
+            instruction: "update it".into(),
+            temperature,
+            ..EditReq::default()
+        };
+
+        set_edit_sampling(Some(0.7), Some(0.9));
+        let defaulted = propose_edit(edit(None)).await;
+        let overridden = propose_edit(edit(Some(0.1))).await;
+        set_edit_sampling(None, None);
+
+        assert_eq!(defaulted.unwrap(), "new\n");
+        assert_eq!(overridden.unwrap(), "new\n");
+        let sent: Vec<serde_json::Value> = server
+            .requests()
+            .iter()
+            .map(|r| serde_json::from_str(&r.body).unwrap())
+            .collect();
+        assert_eq!(sent[0]["temperature"], 0.7);
+        assert_eq!(sent[0]["top_p"], 0.9);
+        assert_eq!(sent[1]["temperature"], 0.1);
+    }
+
+    #[tokio::test]
+    async fn chat_text_buffers_the_stream() {
+        let server = Server::start(vec![Reply::stream(&["a", "b", "c"])]);
+        let _route = mock::route_to(&server, "openai").await;
+
+        assert_eq!(chat_text_with(None, "system", "hi").await.unwrap(), "abc");
+    }
+
+    #[tokio::test]
+    async fn unparseable_json_is_asked_for_again() {
+        let server = Server::start(vec![
+            Reply::chat("Sure! Here you go: {oops"),
+            Reply::chat("{\"answer\": 42}"),
+        ]);
+        let _route = mock::route_to(&server, "openai").await;
+
+        let reply = chat_json_result_with::<serde_json::Value>(None, "system", "{}").await;
+
+        let Ok(ChatJsonResult::Parsed(value)) = reply else {
+            panic!("expected parsed JSON, got {reply:?}");
+        };
+        assert_eq!(value["answer"], 42);
+        let sent = server.requests();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].body.contains("not valid JSON"), "{}", sent[1].body);
+        assert!(sent[1].body.contains("{oops"), "{}", sent[1].body);
+    }
+
+    #[tokio::test]
+    async fn replies_that_never_parse_are_reported_as_a_fallback() {
+        let server = Server::start(vec![Reply::chat("I'd rather not write JSON.")]);
+        let _route = mock::route_to(&server, "openai").await;
+
+        let reply = chat_json_result_with::<serde_json::Value>(None, "system", "{}").await;
+
+        let Ok(ChatJsonResult::Fallback { raw }) = reply else {
+            panic!("expected a fallback, got {reply:?}");
+        };
+        assert_eq!(raw, "I'd rather not write JSON.");
+    }
+
+    #[tokio::test]
+    async fn embeddings_fall_through_to_the_next_provider() {
+        let _guard = mock::exclusive().await;
+        let refused = Server::start(vec![Reply::new(403, "forbidden")]);
+        let backup = Server::start(vec![Reply::embeddings(&[&[1.0, 0.0], &[0.0, 1.0]])]);
+        let chain = vec![refused.provider("openai"), backup.provider("groq")];
+        let texts = vec!["one".to_string(), "two".to_string()];
+
+        let vectors = embed_via(Fallback::with_chain(chain), &texts)
+            .await
+            .unwrap();
+
+        assert_eq!(vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+        let sent = backup.requests();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].path, "/embeddings");
+    }
+
+    #[tokio::test]
+    async fn cached_prompts_only_reach_the_provider_once() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::chat("first"), Reply::chat("second")]);
+        let send = || {
+            let chain = vec![server.provider("openai")];
+            async move { send_chat_via(Fallback::with_chain(chain), &chat_request("m")).await }
+        };
+
+        set_cache_enabled(true);
+        let first = send().await;
+        let again = send().await;
+        set_cache_enabled(false);
+        clear_cache();
+
+        assert_eq!(first.unwrap().as_deref(), Some("first"));
+        assert_eq!(again.unwrap().as_deref(), Some("first"));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn spend_is_priced_per_thousand_tokens_of_each_model() {
+        let price = |input_per_1k, output_per_1k| Price {
+            input_per_1k,
+            output_per_1k,
+        };
+        let prices = PriceTable::from([
+            ("gpt-4o".to_string(), price(0.005, 0.015)),
+            ("llama3".to_string(), price(0.0, 0.0)),
+        ]);
+        let mut usage = HashMap::from([
+            ("gpt-4o".to_string(), (2000, 1000)),
+            ("llama3".to_string(), (500, 500)),
+        ]);
+
+        let usd = cost_of(&usage, &prices).unwrap();
+        assert!((usd - 0.025).abs() < 1e-9, "{usd}");
+
+        usage.insert("mystery".to_string(), (10, 10));
+        assert_eq!(cost_of(&usage, &prices), None);
+    }
+
+    #[tokio::test]
+    async fn calls_after_the_budget_is_spent_are_refused() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::chat("ok")]);
+        let send = || {
+            let chain = vec![server.provider("openai")];
+            async move { send_chat_via(Fallback::with_chain(chain), &chat_request("m")).await }
+        };
+
+        set_token_budget(total_tokens() + 1);
+        let first = send().await;
+        let second = send().await;
+        set_token_budget(0);
+
+        assert_eq!(first.unwrap().as_deref(), Some("ok"));
+        let err = second.unwrap_err();
+        assert_eq!(err.to_string(), "token budget exceeded");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn embeddings_past_the_budget_are_refused_up_front() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::embeddings(&[&[1.0]])]);
+        let chain = vec![server.provider("openai")];
+        let texts = vec!["x".repeat(4000)];
+
+        set_token_budget(total_tokens() + 100);
+        let result = embed_via(Fallback::with_chain(chain), &texts).await;
+        set_token_budget(0);
+
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("token budget"), "{err}");
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_requests_are_neither_retried_nor_passed_on() {
+        let _guard = mock::exclusive().await;
+        let server = Server::start(vec![Reply::new(400, "bad request")]);
+        let backup = Server::start(vec![Reply::chat("unused")]);
+        let chain = vec![server.provider("openai"), backup.provider("groq")];
+
+        let err = send_chat_via(Fallback::with_chain(chain), &chat_request("mock-model"))
+            .await
+            .unwrap_err();
+
+        let http = err.downcast_ref::<LlmHttpError>().unwrap();
+        assert_eq!(http.status.as_u16(), 400);
+        assert_eq!(server.requests().len(), 1);
+        assert!(backup.requests().is_empty());
+    }
+}
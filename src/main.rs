@@ -1,25 +1,35 @@
 use anyhow::Result;
 use console::style;
+use futures::StreamExt;
+use std::io::BufRead;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
 
 mod agents;
 mod capabilities;
+mod cli;
+mod config;
+mod diff;
+mod editor;
+mod errors;
 mod fsutil;
 mod llm;
 mod models;
 mod planner;
+mod pty;
+mod runner;
+mod serve;
 mod task_ui;
+mod transcript;
 mod ui;
 
-// We inline a tiny diff preview + atomic write so we don't depend on
-// diff/editor symbols that may differ in your tree.
+// We inline a tiny diff preview so we don't depend on diff/editor symbols
+// that may differ in your tree.
 use similar::{ChangeTag, TextDiff};
 use std::io::Write as _;
-use tempfile::NamedTempFile;
 use tokio::fs as tokio_fs;
 
 #[tokio::main]
@@ -30,21 +40,182 @@ async fn main() -> Result<()> {
         let r = running.clone();
         ctrlc::set_handler(move || {
             r.store(false, Ordering::SeqCst);
+            llm::signal_cancel();
         })?;
     }
 
+    let options = cli::Options::parse(std::env::args().skip(1))?;
+    config::load_env(&std::env::current_dir()?);
+    ui::init_colors(options.color);
+    let strict_env = config::env_var("SHELLCRAFT_STRICT_CONFIG").is_ok_and(|v| v == "1");
+    if options.strict_config || strict_env {
+        llm::check_model_config()?;
+    }
+    if let Some(depth) = options.max_depth {
+        fsutil::set_max_depth(depth);
+    }
+    fsutil::set_skip_binary(options.skip_binary);
+    if options.log_json {
+        runner::set_log_json(true);
+    }
+    if options.log_max_bytes.is_some() || options.log_keep.is_some() {
+        runner::set_log_rotation(
+            options
+                .log_max_bytes
+                .unwrap_or(runner::DEFAULT_LOG_MAX_BYTES),
+            options.log_keep.unwrap_or(runner::DEFAULT_LOG_KEEP),
+        );
+    }
+    for program in &options.allow {
+        runner::allow_program(program);
+    }
+    fsutil::add_scope(options.scope.iter().cloned());
+    transcript::set_path(options.transcript.clone());
+    config::set_allow_config_edits(options.allow_config_edits);
+    CONFIRM_EDITS.store(options.confirm, Ordering::Relaxed);
+    SIDE_BY_SIDE.store(options.side_by_side, Ordering::Relaxed);
+    fsutil::set_backups_enabled(!options.no_backup);
+    fsutil::set_read_only(options.read_only);
+    if let Some(budget) = options.token_budget {
+        llm::set_token_budget(budget);
+    }
+    if options.cache {
+        llm::set_cache_enabled(true);
+        llm::set_cache_dir(Some(
+            std::env::current_dir()?.join(".shellcraft").join("cache"),
+        ));
+    }
+    if options.serve {
+        return serve::serve().await;
+    }
+    if options.read_only && options.transcript.is_some() {
+        eprintln!(
+            "{} read-only mode: no transcript will be written",
+            style("Warning:").yellow()
+        );
+    }
+    if let Some((planner, pipeline)) = options.autonomous.clone() {
+        if options.read_only {
+            anyhow::bail!("read-only mode: --autonomous runs commands and applies patches");
+        }
+        if options.supervised {
+            runner::set_heal_mode(runner::HealMode::Supervised);
+        }
+        if options.watch_content {
+            runner::set_change_detection(runner::ChangeDetection::ContentHash);
+        }
+        runner::set_dry_run(options.dry_run_report.is_some());
+        let running = running.clone();
+        let autonomous = tokio::task::spawn_blocking(move || {
+            runner::start_autonomous_mode(&planner, &pipeline, ".", &running)
+        });
+        autonomous.await??;
+        if let Some(path) = &options.dry_run_report {
+            for line in runner::get_dry_run_report_strings() {
+                println!("{line}");
+            }
+            runner::dump_dry_run_report_json(path)?;
+        }
+        return Ok(());
+    }
+    if options.batch {
+        return run_batch(&options, &running).await;
+    }
+    if let Some(request) = &options.request {
+        if let Err(e) = orchestrate(request, &running).await {
+            eprintln!("{} {e:#}", style("Error:").red());
+            std::process::exit(errors::exit_code(&e));
+        }
+        return Ok(());
+    }
+
     println!(
         "{}",
         style("Welcome to shellcraft — type /help for commands").green()
     );
 
-    repl().await
+    repl(&running).await
+}
+
+/// Whether Ctrl+C was pressed since `running` was last reset.
+fn interrupted(running: &AtomicBool) -> bool {
+    !running.load(Ordering::SeqCst)
+}
+
+/// Run every request read from stdin through `orchestrate`, one after another,
+/// until Ctrl+C.
+async fn run_batch(options: &cli::Options, running: &AtomicBool) -> Result<()> {
+    let mut input = String::new();
+    std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
+    let requests = cli::parse_batch(&input);
+    run_requests(&requests, options, running, |request| async move {
+        orchestrate(&request, running).await
+    })
+    .await
+}
+
+/// Run each of `requests` through `run` in order, reporting per-request results.
+async fn run_requests<F, Fut>(
+    requests: &[String],
+    options: &cli::Options,
+    running: &AtomicBool,
+    mut run: F,
+) -> Result<()>
+where
+    F: FnMut(String) -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let mut failed = 0usize;
+    for (i, request) in requests.iter().enumerate() {
+        if interrupted(running) {
+            anyhow::bail!(errors::Failure::Interrupted(format!(
+                "interrupted; {} of {} batch requests not run",
+                requests.len() - i,
+                requests.len()
+            )));
+        }
+        if !options.json {
+            println!(
+                "{} {}/{}: {}",
+                style("Request").cyan(),
+                i + 1,
+                requests.len(),
+                request
+            );
+        }
+        let result = run(request.clone()).await;
+        if options.json {
+            let record = serde_json::json!({
+                "index": i,
+                "request": request,
+                "ok": result.is_ok(),
+                "error": result.as_ref().err().map(|e| format!("{e:#}")),
+            });
+            println!("{record}");
+        } else if let Err(e) = &result {
+            eprintln!("{} {e:#}", style("Error:").red());
+        }
+        if result.is_err() {
+            failed += 1;
+            if options.stop_on_error {
+                break;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} batch requests failed", requests.len());
+    }
+    Ok(())
 }
 
-async fn repl() -> Result<()> {
+async fn repl(running: &AtomicBool) -> Result<()> {
+    let mut last_request: Option<String> = None;
     loop {
         let user = ui::read_message_singleline("✔ User · >")?;
         let trimmed = user.trim();
+        // A Ctrl+C only interrupts the command it was pressed during.
+        running.store(true, Ordering::SeqCst);
 
         match trimmed {
             "/quit" | "/exit" => break,
@@ -52,11 +223,211 @@ async fn repl() -> Result<()> {
                 println!("{}", HELP_TEXT);
                 continue;
             }
+            "/clear-cache" => {
+                llm::clear_cache();
+                println!("{}", style("Response cache cleared.").green());
+                continue;
+            }
+            "/diff-session" => {
+                diff_session()?;
+                continue;
+            }
+            "/replan" => {
+                planner::clear_plan_cache();
+                match &last_request {
+                    Some(request) => {
+                        if let Err(e) = orchestrate(request, running).await {
+                            eprintln!("{} {e:#}", style("Error:").red());
+                        }
+                    }
+                    None => println!("{}", style("Nothing to replan yet.").yellow()),
+                }
+                continue;
+            }
+            "/capabilities" => {
+                let manifest = capabilities::build_manifest(&std::env::current_dir()?);
+                print!("{}", capabilities::render(&manifest));
+                continue;
+            }
+            "/capabilities --json" => {
+                let manifest = capabilities::build_manifest(&std::env::current_dir()?);
+                let json = capabilities::to_json(&manifest);
+                println!("{}", serde_json::to_string_pretty(&json)?);
+                continue;
+            }
+            "/validate-config" => {
+                validate_config();
+                continue;
+            }
+            "/budget" => {
+                println!("{} {}", style("Tokens:").cyan(), llm::budget_report());
+                continue;
+            }
             _ => {}
         }
+        if let Some(rest) = trimmed.strip_prefix("/replay") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if let Err(e) = replay(rest.trim(), running).await {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/env") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if fsutil::is_read_only() {
+                    eprintln!(
+                        "{} read-only mode: /env would write .shellcraft/env",
+                        style("Error:").red()
+                    );
+                    continue;
+                }
+                let root = std::env::current_dir()?;
+                let result = config::parse_env_assignment(rest.trim()).and_then(|(key, value)| {
+                    config::persist_env(&root, &key, &value)?;
+                    Ok(key)
+                });
+                match result {
+                    Ok(key) => {
+                        println!("{} {key}", style("Set:").green());
+                        if key == "PATH" {
+                            capabilities::invalidate_manifest_cache();
+                        }
+                        if config::is_secret_env(&key) {
+                            eprintln!(
+                                "{} {key} is saved unencrypted in .shellcraft/env (readable by you only); keep it out of version control",
+                                style("Warning:").yellow()
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("{} {e:#}", style("Error:").red()),
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/model") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if let Err(e) = model_command(rest.trim(), llm::set_model_id) {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                }
+                println!("{} {}", style("Model:").cyan(), llm::active_model());
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/apply ") {
+            let mut turn = transcript::Turn::new(trimmed);
+            let root = std::env::current_dir()?;
+            let result = apply_to_glob(&root, rest, &mut turn, running).await;
+            if let Err(e) = &result {
+                eprintln!("{} {e:#}", style("Error:").red());
+                turn.error = Some(format!("{e:#}"));
+            }
+            transcript::record(&turn);
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/diff ") {
+            let root = std::env::current_dir()?;
+            match diff_preview(&root, rest, running).await {
+                Ok(rendered) => print!("{rendered}"),
+                Err(e) => eprintln!("{} {e:#}", style("Error:").red()),
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/ask ") {
+            let result = ask(rest, running).await;
+            println!();
+            if let Err(e) = result {
+                eprintln!("{} {e:#}", style("Error:").red());
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/ab ") {
+            if let Err(e) = compare_models(rest).await {
+                eprintln!("{} {e:#}", style("Error:").red());
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/undo ") {
+            if fsutil::is_read_only() {
+                eprintln!(
+                    "{} read-only mode: /undo would overwrite {}",
+                    style("Error:").red(),
+                    rest.trim()
+                );
+                continue;
+            }
+            let root = std::env::current_dir()?;
+            match fsutil::undo_last_edit(&root, rest.trim()) {
+                Ok(backup) => println!(
+                    "{} {} (from {})",
+                    style("Restored:").green(),
+                    rest.trim(),
+                    backup.display()
+                ),
+                Err(e) => eprintln!("{} {e:#}", style("Error:").red()),
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/tool") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if let Err(e) = tool_command(rest.trim()) {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/ignore ") {
+            if let Err(e) = ignore_command(rest.trim()).await {
+                eprintln!("{} {e:#}", style("Error:").red());
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/exec ") {
+            if let Err(e) = exec_command(rest.trim()) {
+                eprintln!("{} {e:#}", style("Error:").red());
+            }
+            continue;
+        }
+        if let Some(rest) = trimmed.strip_prefix("/fix") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if let Err(e) = fix_command(rest.trim()).await {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/plan") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                let mut turn = transcript::Turn::new(trimmed);
+                let result = plan_command(rest.trim(), &mut turn, running).await;
+                if let Err(e) = &result {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                    turn.error = Some(format!("{e:#}"));
+                }
+                if turn.plan.is_some() {
+                    transcript::record(&turn);
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/timeline") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                if let Err(e) = timeline_command(rest.trim()) {
+                    eprintln!("{} {e:#}", style("Error:").red());
+                }
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("/scope") {
+            if rest.is_empty() || rest.starts_with(' ') {
+                scope_command(rest.trim());
+                continue;
+            }
+        }
 
         if !trimmed.is_empty() {
-            if let Err(e) = orchestrate(&user).await {
+            last_request = Some(user.clone());
+            if let Err(e) = orchestrate(&user, running).await {
                 eprintln!("{} {e:#}", style("Error:").red());
             }
         }
@@ -64,25 +435,365 @@ async fn repl() -> Result<()> {
     Ok(())
 }
 
-async fn orchestrate(user_input: &str) -> Result<()> {
+/// `/tool` lists the tools that apply to this project; `/tool NAME [ARGS...]`
+/// runs one through the runner's guardrails and prints its output.
+fn tool_command(args: &str) -> Result<()> {
+    let root = std::env::current_dir()?;
+    let mut parts = args.split_whitespace();
+    let Some(name) = parts.next() else {
+        println!("{}", runner::available_tools(&root).join(" "));
+        return Ok(());
+    };
+    if fsutil::is_read_only() {
+        anyhow::bail!("read-only mode: /tool would run `{name}`");
+    }
+    let args: Vec<&str> = parts.collect();
+    print!("{}", runner::execute_tool(name, &args, &root)?);
+    Ok(())
+}
+
+/// `/ignore PATTERN...` adds patterns to the project's .gitignore.
+async fn ignore_command(patterns: &str) -> Result<()> {
+    if fsutil::is_read_only() {
+        anyhow::bail!("read-only mode: /ignore would write .gitignore");
+    }
+    let root = std::env::current_dir()?;
+    editor::handle_ignore_command(&root, patterns).await?;
+    println!("{} {patterns}", style("Ignored:").green());
+    Ok(())
+}
+
+/// `/exec FILE` runs a code snippet, picking the interpreter from FILE's
+/// extension (see `editor::execute_code`), and prints its output.
+fn exec_command(file: &str) -> Result<()> {
+    if fsutil::is_read_only() {
+        anyhow::bail!("read-only mode: /exec would run {file}");
+    }
+    let code = std::fs::read_to_string(file).map_err(|e| anyhow::anyhow!("read {file}: {e}"))?;
+    let lang = Path::new(file).extension().and_then(|e| e.to_str());
+    print!("{}", editor::execute_code(&code, lang)?);
+    Ok(())
+}
+
+/// How many times `/fix` runs its command.
+const FIX_ATTEMPTS: usize = 3;
+
+/// `/fix COMMAND` runs COMMAND and, while it fails, applies the planner
+/// agent's patch for the failure and runs it again.
+async fn fix_command(command: &str) -> Result<()> {
+    if command.is_empty() {
+        anyhow::bail!("usage: /fix COMMAND");
+    }
+    if fsutil::is_read_only() {
+        anyhow::bail!("read-only mode: /fix would patch files");
+    }
+    let planner = agents::PlannerAgent::new();
+    let planner = &planner;
+    editor::run_with_fixer_async(
+        || async {
+            let out = tokio::task::block_in_place(|| runner::run_command(command))
+                .map_err(|e| anyhow::anyhow!("`{command}` failed: {e}"))?;
+            print!("{out}");
+            Ok(())
+        },
+        FIX_ATTEMPTS,
+        |error| async move {
+            let report = format!("{error}\n{}", runner::command_log(command));
+            match planner.generate_fix(&report).await {
+                Ok(patch) if !patch.trim().is_empty() => Some(patch),
+                Ok(_) => None,
+                Err(e) => {
+                    eprintln!("{} {e:#}", style("Patch proposal failed:").red());
+                    None
+                }
+            }
+        },
+    )
+    .await
+}
+
+/// `/timeline` prints the recorded timeline as a table; `/timeline export
+/// FILE [--clear]` appends it to FILE as JSON lines, optionally clearing it.
+fn timeline_command(args: &str) -> Result<()> {
+    let mut parts = args.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (None, _, _) => {
+            let timeline = runner::get_timeline();
+            if timeline.is_empty() {
+                println!("{}", style("No timeline entries yet.").dim());
+                return Ok(());
+            }
+            println!(
+                "{:<32} {:>9} {:<8} {:<12} {:>8} verdict",
+                "task", "duration", "agent", "provider", "tokens"
+            );
+            for entry in timeline {
+                let task: String = entry.task.chars().take(32).collect();
+                println!(
+                    "{task:<32} {:>8.1}s {:<8} {:<12} {:>8} {}",
+                    entry.duration.as_secs_f64(),
+                    entry.agent,
+                    entry.llm_provider,
+                    entry.tokens_used,
+                    entry.verdict
+                );
+            }
+        }
+        (Some("export"), Some(file), clear @ (None | Some("--clear"))) => {
+            let exported = runner::export_timeline_jsonl(Path::new(file), clear.is_some())?;
+            println!(
+                "{} {exported} entries to {file}",
+                style("Exported:").green()
+            );
+        }
+        _ => anyhow::bail!("usage: /timeline | /timeline export FILE [--clear]"),
+    }
+    Ok(())
+}
+
+/// `/scope` lists the scope, `/scope clear` resets it, and `/scope <path|glob>...`
+/// adds to it.
+fn scope_command(args: &str) {
+    match args {
+        "" => {}
+        "clear" => fsutil::clear_scope(),
+        _ => fsutil::add_scope(args.split_whitespace().map(str::to_string)),
+    }
+    let scope = fsutil::scope();
+    if scope.is_empty() {
+        println!("{} whole project", style("Scope:").cyan());
+    } else {
+        println!("{} {}", style("Scope:").cyan(), scope.join(", "));
+    }
+}
+
+/// The most recent plan, made or loaded this session (`/plan save|run`).
+static LAST_PLAN: Mutex<Option<planner::Plan>> = Mutex::new(None);
+
+/// `/plan save FILE` writes the last plan as JSON, `/plan load FILE` makes a
+/// saved plan the current one, and `/plan run [FILE]` applies the current (or
+/// saved) plan without asking the model again.
+async fn plan_command(args: &str, turn: &mut transcript::Turn, running: &AtomicBool) -> Result<()> {
+    let mut parts = args.split_whitespace();
+    let (sub, file) = (parts.next(), parts.next());
+    match (sub, file) {
+        (Some("save"), Some(file)) => {
+            let Some(plan) = LAST_PLAN.lock().unwrap().clone() else {
+                anyhow::bail!("no plan yet; make a request first");
+            };
+            plan.save(Path::new(file))?;
+            println!("{} {file}", style("Saved plan:").green());
+        }
+        (Some("load"), Some(file)) => {
+            let plan = planner::Plan::load(Path::new(file))?;
+            println!(
+                "{} {file} ({} read, {} edit, {} delete, {} action(s))",
+                style("Loaded plan:").green(),
+                plan.read.len(),
+                plan.edit.len(),
+                plan.delete.len(),
+                plan.actions.len()
+            );
+            *LAST_PLAN.lock().unwrap() = Some(plan);
+        }
+        (Some("run"), file) => {
+            let plan = match file {
+                Some(file) => planner::Plan::load(Path::new(file))?,
+                None => match LAST_PLAN.lock().unwrap().clone() {
+                    Some(plan) => plan,
+                    None => anyhow::bail!("no plan yet; make a request or /plan load FILE"),
+                },
+            };
+            run_saved_plan(plan, turn, running).await?;
+        }
+        _ => anyhow::bail!("usage: /plan save FILE | /plan load FILE | /plan run [FILE]"),
+    }
+    Ok(())
+}
+
+/// Apply a plan that didn't come from the model this turn. Its actions are
+/// preflighted again since the plan may have been edited by hand.
+async fn run_saved_plan(
+    mut plan: planner::Plan,
+    turn: &mut transcript::Turn,
+    running: &AtomicBool,
+) -> Result<()> {
+    let root = std::env::current_dir()?;
+    let manifest = capabilities::build_manifest(&root);
+    let config = config::Config::load(&root);
+    llm::set_edit_sampling(config.edit.temperature, config.edit.top_p);
+    llm::set_edit_chunk_lines(config.edit.chunk_lines);
+    planner::set_action_limits(config.actions.max_args, config.actions.max_arg_bytes);
+    plan.skipped.clear();
+    planner::preflight_actions(&manifest, &mut plan);
+    turn.plan = Some(plan.clone());
+    *LAST_PLAN.lock().unwrap() = Some(plan.clone());
+    apply_plan(&root, &config, &plan, false, turn, running).await
+}
+
+/// Plan and apply `user_input`, stopping between steps once `running` is
+/// cleared by Ctrl+C.
+async fn orchestrate(user_input: &str, running: &AtomicBool) -> Result<()> {
+    let mut turn = transcript::Turn::new(user_input);
+    let result = plan_and_apply(user_input, &mut turn, running).await;
+    if let Some(plan) = &turn.plan {
+        *LAST_PLAN.lock().unwrap() = Some(plan.clone());
+    }
+    if let Err(e) = &result {
+        turn.error = Some(format!("{e:#}"));
+    }
+    transcript::record(&turn);
+    result
+}
+
+async fn plan_and_apply(
+    user_input: &str,
+    turn: &mut transcript::Turn,
+    running: &AtomicBool,
+) -> Result<()> {
     let root = std::env::current_dir()?;
     let manifest = capabilities::build_manifest(&root); // signature: (&Path) -> Manifest
+    let config = config::Config::load(&root);
+    llm::set_edit_sampling(config.edit.temperature, config.edit.top_p);
+    llm::set_edit_chunk_lines(config.edit.chunk_lines);
+    llm::set_price_table(config.prices.clone());
+    planner::set_action_limits(config.actions.max_args, config.actions.max_arg_bytes);
 
     // Planner agent chats with user and returns plan
-    let planner = agents::PlannerAgent::default();
-    let plan = planner.chat_and_plan(&root, user_input, &manifest).await?;
+    let planner = agents::PlannerAgent::new();
+    let plan = llm::until_cancelled(running, planner.chat_and_plan(&root, user_input, &manifest))
+        .await??;
+    turn.plan = Some(plan.clone());
+
+    if plan.from_cache {
+        println!(
+            "{}",
+            style("Reusing the plan for this unchanged request (/replan for a fresh one).").dim()
+        );
+    }
+    apply_plan(&root, &config, &plan, false, turn, running).await
+}
+
+/// Ask before writing each proposed file (`--confirm`).
+static CONFIRM_EDITS: AtomicBool = AtomicBool::new(false);
+
+/// Per-turn state of the `--confirm` prompt.
+struct ApprovalGate {
+    /// Still asking; cleared by `a` (apply all) or when confirmation is off.
+    prompt: bool,
+    /// `q` was answered: leave every remaining change unapplied.
+    stopped: bool,
+}
+
+impl ApprovalGate {
+    fn new(prompt: bool) -> Self {
+        Self {
+            prompt,
+            stopped: false,
+        }
+    }
+
+    /// Decide whether to apply the change just shown. Returns the reason it
+    /// is skipped, or `None` to apply it.
+    fn decide(&mut self, input: &mut impl BufRead) -> std::io::Result<Option<&'static str>> {
+        if self.stopped {
+            return Ok(Some("not applied (stopped)"));
+        }
+        if !self.prompt {
+            return Ok(None);
+        }
+        print!("Apply this change? [y/N/a/q] ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        match line.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => Ok(None),
+            "a" | "all" => {
+                self.prompt = false;
+                Ok(None)
+            }
+            "q" | "quit" => {
+                self.stopped = true;
+                Ok(Some("not applied (stopped)"))
+            }
+            _ => Ok(Some("declined")),
+        }
+    }
+}
 
+/// Read every file in `paths` concurrently; results are in the given order.
+async fn read_files<'a>(paths: impl Iterator<Item = &'a Path>) -> Vec<std::io::Result<Vec<u8>>> {
+    futures::future::join_all(paths.map(tokio_fs::read)).await
+}
+
+/// Carry out `plan`, recording what was changed in `turn`. With `dry_run`,
+/// only report what would be done: no LLM calls, no writes. In read-only mode
+/// (`fsutil::set_read_only`) reads run and edits are proposed and shown, but
+/// nothing is written, deleted, moved or run.
+///
+/// `running` is checked before each read, delete, move, create, edit and
+/// the actions, and LLM calls are abandoned when Ctrl+C is pressed; the
+/// remaining steps are then skipped and the turn fails with
+/// `Failure::Interrupted`.
+async fn apply_plan(
+    root: &Path,
+    config: &config::Config,
+    plan: &planner::Plan,
+    dry_run: bool,
+    turn: &mut transcript::Turn,
+    running: &AtomicBool,
+) -> Result<()> {
     if !plan.notes.is_empty() {
         println!("{} {}", style("Notes:").cyan(), plan.notes);
     }
 
-    // Reads
+    // Everything shellcraft declines to do, reported together at the end.
+    let mut skipped: Vec<String> = plan.skipped.clone();
+    let read_only = fsutil::is_read_only() && !dry_run;
+    if read_only {
+        println!(
+            "{}",
+            style("Read-only: changes are shown but not applied.").yellow()
+        );
+    }
+    let mut gate =
+        ApprovalGate::new(CONFIRM_EDITS.load(Ordering::Relaxed) && !dry_run && !read_only);
+
+    // Reads: checked in plan order, read concurrently, printed in plan order.
+    let mut to_read = Vec::new();
     for path in plan.read.iter() {
-        let abs = root.join(path);
-        match tokio_fs::read_to_string(&abs).await {
-            Ok(content) => {
+        if interrupted(running) {
+            return stop_interrupted(skipped, turn);
+        }
+        if !fsutil::in_scope(path) {
+            skipped.push(format!("read `{path}`: outside the scope"));
+            continue;
+        }
+        let abs = match fsutil::resolve_within(root, path) {
+            Ok(abs) => abs,
+            Err(err) => {
+                skipped.push(format!("read `{path}`: {err}"));
+                continue;
+            }
+        };
+        if dry_run {
+            println!("{} {}", style("Would read:").yellow(), path);
+            continue;
+        }
+        to_read.push((path, abs));
+    }
+    let contents = read_files(to_read.iter().map(|(_, abs)| abs.as_path())).await;
+    if interrupted(running) {
+        return stop_interrupted(skipped, turn);
+    }
+    for ((path, _), result) in to_read.iter().zip(contents) {
+        match result {
+            Ok(bytes) if fsutil::is_binary(&bytes) => {
+                skipped.push(format!("read `{path}`: binary file"));
+            }
+            Ok(bytes) => {
                 println!("{} {}", style("Read:").yellow(), path);
-                println!("{content}");
+                println!("{}", String::from_utf8_lossy(&bytes));
             }
             Err(err) => eprintln!("{} {} ({err})", style("Failed to read:").red(), path),
         }
@@ -90,45 +801,297 @@ async fn orchestrate(user_input: &str) -> Result<()> {
 
     // Deletes
     for path in plan.delete.iter() {
-        let abs = root.join(path);
-        if abs.exists() {
-            if let Err(err) = fsutil::remove_path(&abs) {
-                eprintln!("{} {} ({err})", style("Failed to delete:").red(), path);
-            } else {
-                println!("{} {}", style("Deleted:").red(), path);
+        if interrupted(running) {
+            return stop_interrupted(skipped, turn);
+        }
+        if !fsutil::in_scope(path) {
+            skipped.push(format!("delete `{path}`: outside the scope"));
+            continue;
+        }
+        if let Some(why) = config::protection(path) {
+            skipped.push(format!("delete `{path}`: {why}"));
+            continue;
+        }
+        let abs = match fsutil::resolve_within(root, path) {
+            Ok(abs) => abs,
+            Err(err) => {
+                skipped.push(format!("delete `{path}`: {err}"));
+                continue;
             }
-        } else {
+        };
+        if !abs.exists() {
             eprintln!("{} {} (not found)", style("Failed to delete:").red(), path);
+            continue;
+        }
+        if dry_run || read_only {
+            println!("{} {}", style("Would delete:").red(), path);
+            continue;
+        }
+        fsutil::snapshot_for_session(root, path);
+        if let Err(err) = fsutil::remove_path_within(root, path) {
+            eprintln!("{} {} ({err})", style("Failed to delete:").red(), path);
+        } else {
+            println!("{} {}", style("Deleted:").red(), path);
+            turn.changes
+                .push(transcript::FileChange::Delete { path: path.clone() });
         }
     }
 
-    // Edits
-    for edit in plan.edit.iter() {
-        let file_path: PathBuf = root.join(&edit.path);
-        let old_content = tokio_fs::read_to_string(&file_path)
-            .await
-            .unwrap_or_default();
+    // Moves
+    for action in plan.actions.iter() {
+        let planner::Action::Move { from, to } = action else {
+            continue;
+        };
+        if interrupted(running) {
+            return stop_interrupted(skipped, turn);
+        }
+        if dry_run || read_only {
+            println!("{} {} → {}", style("Would move:").green(), from, to);
+            continue;
+        }
+        fsutil::snapshot_for_session(root, from);
+        fsutil::snapshot_for_session(root, to);
+        if let Err(why) = move_path(root, config, from, to) {
+            skipped.push(format!("move `{from}` → `{to}`: {why:#}"));
+            continue;
+        }
+        println!("{} {} → {}", style("Moved:").green(), from, to);
+        turn.changes.push(transcript::FileChange::Move {
+            from: from.clone(),
+            to: to.clone(),
+        });
+    }
 
-        // llm::propose_edit(EditReq)
+    // Creates
+    for action in plan.actions.iter() {
+        let planner::Action::Create {
+            path,
+            content_intent,
+        } = action
+        else {
+            continue;
+        };
+        if interrupted(running) {
+            return stop_interrupted(skipped, turn);
+        }
+        if !fsutil::in_scope(path) {
+            skipped.push(format!("create `{path}`: outside the scope"));
+            continue;
+        }
+        if let Some(why) = config.edit.denial(path) {
+            skipped.push(format!("create `{path}`: {why}"));
+            continue;
+        }
+        let file_path = match fsutil::resolve_within(root, path) {
+            Ok(p) => p,
+            Err(err) => {
+                skipped.push(format!("create `{path}`: {err}"));
+                continue;
+            }
+        };
+        if file_path.exists() {
+            skipped.push(format!("create `{path}`: already exists"));
+            continue;
+        }
+        if dry_run {
+            println!(
+                "{} {} ({})",
+                style("Would create:").green(),
+                path,
+                content_intent
+            );
+            continue;
+        }
+        if gate.stopped {
+            skipped.push(format!("create `{path}`: not applied (stopped)"));
+            continue;
+        }
+        let _lock = fsutil::lock_path(&file_path).await;
+        // Re-check under the lock: a concurrent edit may have created it.
+        if file_path.exists() {
+            skipped.push(format!("create `{path}`: already exists"));
+            continue;
+        }
         let req = llm::EditReq {
-            file_path: edit.path.clone(),
-            file_content: old_content.clone(),
-            instruction: edit.intent.clone(),
+            file_path: path.clone(),
+            file_content: String::new(),
+            instruction: content_intent.clone(),
+            ..Default::default()
+        };
+        // Never create a file from a failed or empty proposal.
+        let content = match llm::until_cancelled(running, llm::propose_edit(req)).await {
+            Ok(Ok(content)) if content.trim().is_empty() => {
+                skipped.push(format!("create `{path}`: the model returned no content"));
+                continue;
+            }
+            Ok(Ok(content)) => content,
+            Ok(Err(err)) => {
+                skipped.push(format!("create `{path}`: {err:#}"));
+                continue;
+            }
+            Err(_) => return stop_interrupted(skipped, turn),
+        };
+        print_unified_diff(path, "", &content);
+        if read_only {
+            skipped.push(format!("create `{path}`: read-only mode"));
+            continue;
+        }
+        if let Some(why) = gate.decide(&mut std::io::stdin().lock())? {
+            skipped.push(format!("create `{path}`: {why}"));
+            continue;
+        }
+        fsutil::snapshot_for_session(root, path);
+        if let Err(err) = fsutil::atomic_write(&file_path, &content) {
+            skipped.push(format!("create `{path}`: {err:#}"));
+            continue;
+        }
+        println!("{} {}", style("Created:").green(), path);
+        turn.changes.push(transcript::FileChange::Create {
+            path: path.clone(),
+            diff: transcript::unified_diff(path, "", &content),
+        });
+    }
+
+    // Edits: checked in plan order, proposed concurrently (up to
+    // `edit.concurrency` at a time), then reviewed and written in plan order.
+    let mut to_edit = Vec::new();
+    for edit in plan.edit.iter() {
+        if !fsutil::in_scope(&edit.path) {
+            skipped.push(format!("edit `{}`: outside the scope", edit.path));
+            continue;
+        }
+        if let Some(why) = config.edit.denial(&edit.path) {
+            skipped.push(format!("edit `{}`: {why}", edit.path));
+            continue;
+        }
+        let file_path: PathBuf = match fsutil::resolve_within(root, &edit.path) {
+            Ok(p) => p,
+            Err(err) => {
+                skipped.push(format!("edit `{}`: {err}", edit.path));
+                continue;
+            }
+        };
+        let created = !file_path.exists();
+        if !created && fsutil::looks_binary(&file_path) {
+            skipped.push(format!("edit `{}`: binary file", edit.path));
+            continue;
+        }
+        if dry_run {
+            let new = if created { " [new file]" } else { "" };
+            println!(
+                "{} {}{new} ({})",
+                style("Would edit:").green(),
+                edit.path,
+                edit.intent
+            );
+            continue;
+        }
+        to_edit.push((edit, file_path, created));
+    }
+
+    let mut proposals = futures::stream::iter(to_edit.iter())
+        .map(|(edit, file_path, _)| async move {
+            // Held until the proposal is written (or skipped) below.
+            let lock = fsutil::lock_path(file_path).await;
+            let old_content = tokio_fs::read_to_string(file_path)
+                .await
+                .unwrap_or_default();
+            // Not asked for once interrupted; the loop below stops first.
+            if interrupted(running) {
+                return (lock, old_content, Ok(String::new()));
+            }
+            let req = llm::EditReq {
+                file_path: edit.path.clone(),
+                file_content: old_content.clone(),
+                instruction: edit.intent.clone(),
+                ..Default::default()
+            };
+            let proposal = llm::propose_edit(req).await;
+            (lock, old_content, proposal)
+        })
+        .buffered(config.edit.concurrency.max(1));
+
+    for (edit, file_path, created) in to_edit.iter() {
+        if interrupted(running) {
+            drop(proposals);
+            return stop_interrupted(skipped, turn);
+        }
+        if gate.stopped {
+            skipped.push(format!("edit `{}`: not applied (stopped)", edit.path));
+            continue;
+        }
+        // Dropping the stream on Ctrl+C aborts the proposals in flight.
+        let Ok(next) = llm::until_cancelled(running, proposals.next()).await else {
+            drop(proposals);
+            return stop_interrupted(skipped, turn);
+        };
+        let Some((_lock, old_content, proposal)) = next else {
+            break;
+        };
+        // Never write a failed, empty or no-op proposal over the file.
+        let proposal = match proposal {
+            Ok(p) if p.trim().is_empty() => {
+                skipped.push(format!(
+                    "edit `{}`: the model returned no content",
+                    edit.path
+                ));
+                continue;
+            }
+            Ok(p) if p == old_content => {
+                skipped.push(format!(
+                    "edit `{}`: the model proposed no changes",
+                    edit.path
+                ));
+                continue;
+            }
+            Ok(p) => p,
+            Err(err) => {
+                skipped.push(format!("edit `{}`: {err:#}", edit.path));
+                continue;
+            }
         };
-        let proposal = llm::propose_edit(req).await.unwrap_or_default();
 
         print_unified_diff(&edit.path, &old_content, &proposal);
-        atomic_write(&file_path, proposal.as_bytes())?;
-        println!("{} {}", style("Applied:").green(), edit.path);
+        if read_only {
+            skipped.push(format!("edit `{}`: read-only mode", edit.path));
+            continue;
+        }
+        if let Some(why) = gate.decide(&mut std::io::stdin().lock())? {
+            skipped.push(format!("edit `{}`: {why}", edit.path));
+            continue;
+        }
+        fsutil::snapshot_for_session(root, &edit.path);
+        // One failed write skips that file; the rest of the plan still runs.
+        let written = fsutil::backup_before_write(root, &edit.path)
+            .and_then(|_| fsutil::atomic_write(file_path, &proposal));
+        if let Err(err) = written {
+            skipped.push(format!("edit `{}`: {err:#}", edit.path));
+            continue;
+        }
+        let diff = transcript::unified_diff(&edit.path, &old_content, &proposal);
+        if *created {
+            println!("{} {} (created)", style("Applied:").green(), edit.path);
+            turn.changes.push(transcript::FileChange::Create {
+                path: edit.path.clone(),
+                diff,
+            });
+        } else {
+            println!("{} {}", style("Applied:").green(), edit.path);
+            turn.changes.push(transcript::FileChange::Edit {
+                path: edit.path.clone(),
+                diff,
+            });
+        }
     }
+    // Release the locks of proposals left unreviewed after `q`.
+    drop(proposals);
 
-    // Actions (placeholder): avoid referencing fields of planner::Action.
+    if interrupted(running) {
+        return stop_interrupted(skipped, turn);
+    }
+
+    // Actions: review in the task dashboard, then run.
     if !plan.actions.is_empty() {
-        println!(
-            "{} {}",
-            style("Planned actions:").cyan(),
-            plan.actions.len()
-        );
         // Interactive task dashboard for planned actions
         let mut items: Vec<task_ui::TaskItem> = plan
             .actions
@@ -141,21 +1104,395 @@ async fn orchestrate(user_input: &str) -> Result<()> {
                     detail: format!("program: {}\nargs: {}", program, args.join(" ")),
                     status: task_ui::TaskStatus::Pending,
                     expanded: false,
+                    error: None,
+                    cancel: Default::default(),
                 }),
+                planner::Action::Shell { command } => Some(task_ui::TaskItem {
+                    id: i,
+                    summary: command.clone(),
+                    detail: format!("shell: {}", command),
+                    status: task_ui::TaskStatus::Pending,
+                    expanded: false,
+                    error: None,
+                    cancel: Default::default(),
+                }),
+                // Already written above.
+                planner::Action::Create { .. } | planner::Action::Move { .. } => None,
             })
             .collect();
 
-        if !items.is_empty() {
-            task_ui::task_dashboard(&mut items)?;
+        let first_record = turn.actions.len();
+        turn.actions
+            .extend(items.iter().map(|item| transcript::ActionRecord {
+                command: item.summary.clone(),
+                output: None,
+            }));
+        if dry_run || read_only {
+            for item in &items {
+                println!("{} {}", style("Would run:").cyan(), item.summary);
+            }
+        } else if !items.is_empty() {
+            println!("{} {}", style("Planned actions:").cyan(), items.len());
+
+            // Run every action in the background while the dashboard shows
+            // their progress; output goes to the logs until it is closed.
+            let actions: Vec<planner::Action> = items
+                .iter()
+                .map(|item| plan.actions[item.id].clone())
+                .collect();
+            let cancel: Vec<_> = items.iter().map(|item| item.cancel.clone()).collect();
+            let (tx, rx) = std::sync::mpsc::channel();
+            let worker_root = root.to_path_buf();
+            pty::set_echo(false);
+            let worker = std::thread::spawn(move || {
+                agents::WorkerAgent.run_with_updates(&worker_root, &actions, &tx, &cancel)
+            });
+            let shown = task_ui::task_dashboard(&mut items, &rx);
+            if !worker.is_finished() {
+                println!("{}", style("Waiting for running actions...").dim());
+            }
+            let outcomes = tokio::task::block_in_place(|| worker.join())
+                .map_err(|_| anyhow::anyhow!("action worker panicked"))?;
+            pty::set_echo(true);
+            shown?;
+            let outcomes = outcomes?;
+            for (record, outcome) in outcomes.into_iter().enumerate() {
+                let record = first_record + record;
+                if outcome.cancelled {
+                    println!("{} {}", style("Cancelled:").yellow(), outcome.command);
+                } else if outcome.success {
+                    println!("{} {}", style("Ran:").green(), outcome.command);
+                } else {
+                    let code = outcome
+                        .exit_code
+                        .map_or("no exit code".to_string(), |c| format!("exit {c}"));
+                    eprintln!(
+                        "{} {} ({code}, {} attempt(s))",
+                        style("Failed:").red(),
+                        outcome.command,
+                        outcome.attempts
+                    );
+                }
+                turn.actions[record].output = Some(outcome.output);
+            }
+        }
+    }
+
+    print_skipped(&skipped);
+    turn.skipped = skipped;
+    Ok(())
+}
+
+/// End `apply_plan` after Ctrl+C: report what was skipped so far and fail
+/// the turn as interrupted.
+fn stop_interrupted(skipped: Vec<String>, turn: &mut transcript::Turn) -> Result<()> {
+    eprintln!(
+        "{}",
+        style("Interrupted; skipping the remaining steps.").yellow()
+    );
+    print_skipped(&skipped);
+    turn.skipped = skipped;
+    Err(errors::Failure::Interrupted("interrupted by Ctrl+C".into()).into())
+}
+
+/// `/apply <glob> <instruction>`: edit every inventory file matching `glob`
+/// with the same instruction, then summarize the lines changed per file.
+async fn apply_to_glob(
+    root: &Path,
+    args: &str,
+    turn: &mut transcript::Turn,
+    running: &AtomicBool,
+) -> Result<()> {
+    let mut parts = args.trim().splitn(2, char::is_whitespace);
+    let (Some(pattern), Some(instruction)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("usage: /apply <glob> <instruction>");
+    };
+    let instruction = instruction.trim();
+    let config = config::Config::load(root);
+    llm::set_edit_sampling(config.edit.temperature, config.edit.top_p);
+    llm::set_edit_chunk_lines(config.edit.chunk_lines);
+
+    let edit: Vec<planner::EditPlan> = fsutil::file_inventory(root)?
+        .into_iter()
+        .filter(|f| !f.is_link && !f.is_binary)
+        .filter(|f| fsutil::glob_match(pattern, &f.path) && fsutil::in_scope(&f.path))
+        .map(|f| planner::EditPlan {
+            path: f.path,
+            intent: instruction.to_string(),
+        })
+        .collect();
+    if edit.is_empty() {
+        anyhow::bail!("no files match `{pattern}`");
+    }
+    println!("{} {} file(s)", style("Applying to").cyan(), edit.len());
+    let plan = planner::Plan {
+        edit,
+        ..Default::default()
+    };
+    turn.plan = Some(plan.clone());
+    apply_plan(root, &config, &plan, false, turn, running).await?;
+    print_diff_stat(&turn.changes);
+    Ok(())
+}
+
+/// `/diff <path> <instruction>`: ask the model how it would edit `path` and
+/// render the change as a colored diff. Nothing is written.
+async fn diff_preview(root: &Path, args: &str, running: &AtomicBool) -> Result<String> {
+    let mut parts = args.trim().splitn(2, char::is_whitespace);
+    let (Some(path), Some(instruction)) = (parts.next(), parts.next()) else {
+        anyhow::bail!("usage: /diff <path> <instruction>");
+    };
+    let config = config::Config::load(root);
+    llm::set_edit_sampling(config.edit.temperature, config.edit.top_p);
+    llm::set_edit_chunk_lines(config.edit.chunk_lines);
+    let full = fsutil::resolve_within(root, path)?;
+    let old = std::fs::read_to_string(&full).map_err(|e| anyhow::anyhow!("read {path}: {e}"))?;
+    let req = llm::EditReq {
+        file_path: path.to_string(),
+        file_content: old.clone(),
+        instruction: instruction.trim().to_string(),
+        ..Default::default()
+    };
+    let new = llm::until_cancelled(running, llm::propose_edit(req)).await??;
+    if new == old {
+        return Ok(format!("{}\n", style("No changes proposed.").dim()));
+    }
+    Ok(diff::unified_colored(&old, &new, path))
+}
+
+/// `/ask <question>`: answer `question` without planning, printing the reply
+/// as it arrives.
+async fn ask(question: &str, running: &AtomicBool) -> Result<()> {
+    let system = "You are a helpful assistant for a software project. Answer concisely.";
+    let print_token = |token: &str| {
+        print!("{token}");
+        let _ = std::io::stdout().flush();
+    };
+    let reply = llm::chat_text_stream(system, question.trim(), print_token);
+    llm::until_cancelled(running, reply).await??;
+    Ok(())
+}
+
+/// Print `path | +added -removed` for each edited file and a total line.
+fn print_diff_stat(changes: &[transcript::FileChange]) {
+    let (mut files, mut added, mut removed) = (0, 0, 0);
+    for change in changes {
+        let (transcript::FileChange::Edit { path, diff }
+        | transcript::FileChange::Create { path, diff }) = change
+        else {
+            continue;
+        };
+        let body = diff
+            .lines()
+            .filter(|l| !l.starts_with("+++") && !l.starts_with("---"));
+        let (mut a, mut r) = (0, 0);
+        for line in body {
+            if line.starts_with('+') {
+                a += 1;
+            } else if line.starts_with('-') {
+                r += 1;
+            }
+        }
+        println!(
+            " {path} | {} {}",
+            style(format!("+{a}")).green(),
+            style(format!("-{r}")).red()
+        );
+        files += 1;
+        added += a;
+        removed += r;
+    }
+    println!(" {files} file(s) changed, {added} insertion(s), {removed} deletion(s)");
+}
+
+/// `/model <MODEL_ID>`: hand `id` to `set_model`, warning when models.json
+/// doesn't list it.
+fn model_command(id: &str, set_model: impl FnOnce(&str)) -> Result<()> {
+    if id.is_empty() {
+        anyhow::bail!("usage: /model <MODEL_ID>");
+    }
+    if llm::is_known_model(id) == Some(false) {
+        eprintln!(
+            "{} {id} is not listed in models.json; using it anyway",
+            style("Warning:").yellow()
+        );
+    }
+    set_model(id);
+    Ok(())
+}
+
+/// `/ab <model1> <model2> <request>`: plan `request` with both models at once
+/// and show the two plans side by side. Nothing is applied.
+async fn compare_models(args: &str) -> Result<()> {
+    let root = std::env::current_dir()?;
+    let width = (console::Term::stdout().size().1 as usize).saturating_sub(3) / 2;
+    print!("{}", compare_plans(&root, args, width).await?);
+    Ok(())
+}
+
+/// The `/ab` plans of both models for the request in `args`, as two columns
+/// of `width`.
+async fn compare_plans(root: &Path, args: &str, width: usize) -> Result<String> {
+    let mut parts = args.trim().splitn(3, char::is_whitespace);
+    let (Some(a), Some(b), Some(request)) = (parts.next(), parts.next(), parts.next()) else {
+        anyhow::bail!("usage: /ab <model1> <model2> <request>");
+    };
+    let request = request.trim();
+    let manifest = capabilities::build_manifest(root);
+
+    let (plan_a, plan_b) = tokio::join!(
+        planner::plan_changes_with(root, request, &manifest, Some(a)),
+        planner::plan_changes_with(root, request, &manifest, Some(b)),
+    );
+    let render = |plan: Result<planner::Plan>| match plan {
+        Ok(plan) => serde_json::to_string_pretty(&plan).unwrap_or_default(),
+        Err(e) => format!("error: {e:#}"),
+    };
+    Ok(diff::side_by_side_text(
+        a,
+        &render(plan_a),
+        b,
+        &render(plan_b),
+        width,
+    ))
+}
+
+/// `/validate-config`: show how each model in `models.json` resolves and
+/// flag entries that would fail at request time.
+fn validate_config() {
+    let (checks, general) = llm::validate_models();
+    if checks.is_empty() {
+        println!("{}", style("No models configured in models.json.").dim());
+    }
+    let mut invalid = general.len();
+    for problem in &general {
+        println!("{} {problem}", style("✘").red());
+    }
+    for check in &checks {
+        let base = check.base.as_deref().unwrap_or("?");
+        if check.problems.is_empty() {
+            println!(
+                "{} {} → {} ({base})",
+                style("✔").green(),
+                check.id,
+                check.provider
+            );
+        } else {
+            invalid += 1;
+            println!(
+                "{} {} → {} ({base})",
+                style("✘").red(),
+                check.id,
+                check.provider
+            );
+            for problem in &check.problems {
+                println!("    {problem}");
+            }
+        }
+    }
+    if invalid == 0 {
+        println!("{}", style("Model configuration looks valid.").green());
+    }
+}
+
+/// `/diff-session`: the net change to every file this session touched,
+/// compared with its content before the session first changed it.
+fn diff_session() -> Result<()> {
+    let root = std::env::current_dir()?;
+    let changes = fsutil::session_changes(&root);
+    if changes.is_empty() {
+        println!("{}", style("No changes this session.").dim());
+    }
+    for (path, before, after) in changes {
+        print!("{}", diff::unified_colored(&before, &after, &path));
+    }
+    Ok(())
+}
+
+/// `/replay <file> [--apply]`: re-run the plans recorded in a transcript
+/// against the current tree, as a dry run unless `--apply` is given.
+async fn replay(args: &str, running: &AtomicBool) -> Result<()> {
+    let mut apply = false;
+    let mut file = None;
+    for arg in args.split_whitespace() {
+        match arg {
+            "--apply" => apply = true,
+            other => file = Some(other),
         }
-        // TODO: replace with your actual runner call, e.g.:
-        // runner::run_and_capture(&root, &plan.actions).await?;
     }
+    let Some(file) = file else {
+        anyhow::bail!("usage: /replay <transcript.jsonl> [--apply]");
+    };
+    let turns = transcript::load(Path::new(file))?;
+    let root = std::env::current_dir()?;
+    let config = config::Config::load(&root);
+    let total = turns.len();
+    for (i, recorded) in turns.into_iter().enumerate() {
+        println!(
+            "{} {}/{}: {}",
+            style("Replaying").cyan(),
+            i + 1,
+            total,
+            recorded.request
+        );
+        let Some(plan) = recorded.plan else {
+            println!("{}", style("  (no plan recorded)").dim());
+            continue;
+        };
+        let mut turn = transcript::Turn::new(&recorded.request);
+        apply_plan(&root, &config, &plan, !apply, &mut turn, running).await?;
+    }
+    Ok(())
+}
 
+/// Rename `from` to `to` within `root`, creating missing parent directories.
+fn move_path(root: &Path, config: &config::Config, from: &str, to: &str) -> Result<()> {
+    for path in [from, to] {
+        if !fsutil::in_scope(path) {
+            anyhow::bail!("`{path}` is outside the scope");
+        }
+        if let Some(why) = config.edit.denial(path) {
+            anyhow::bail!("`{path}` {why}");
+        }
+    }
+    let src = fsutil::resolve_within(root, from)?;
+    let dst = fsutil::resolve_within(root, to)?;
+    if !src.exists() {
+        anyhow::bail!("source does not exist");
+    }
+    if dst.exists() {
+        anyhow::bail!("destination already exists");
+    }
+    let parent = dst.parent().unwrap_or(root);
+    std::fs::create_dir_all(parent)?;
+    if std::fs::metadata(parent)?.permissions().readonly() {
+        anyhow::bail!("destination directory is not writable");
+    }
+    std::fs::rename(&src, &dst)?;
     Ok(())
 }
 
+/// Print the operations shellcraft declined to perform, with reasons.
+fn print_skipped(skipped: &[String]) {
+    if skipped.is_empty() {
+        return;
+    }
+    println!("{}", style("Skipped:").yellow());
+    for reason in skipped {
+        println!("  - {reason}");
+    }
+}
+
+/// Show proposed changes as two columns instead of a unified diff
+/// (`--side-by-side`).
+static SIDE_BY_SIDE: AtomicBool = AtomicBool::new(false);
+
 fn print_unified_diff(rel_path: &str, old: &str, new: &str) {
+    if SIDE_BY_SIDE.load(Ordering::Relaxed) {
+        let width = (console::Term::stdout().size().1 as usize).saturating_sub(3) / 2;
+        print!("{}", diff::side_by_side(old, new, rel_path, width));
+        return;
+    }
     let diff = TextDiff::from_lines(old, new);
     println!(
         "{}",
@@ -172,25 +1509,520 @@ fn print_unified_diff(rel_path: &str, old: &str, new: &str) {
     println!();
 }
 
-fn atomic_write(path: &Path, bytes: &[u8]) -> Result<()> {
-    let parent = path.parent().unwrap_or_else(|| Path::new("."));
-    std::fs::create_dir_all(parent)?;
-    let mut tmp = NamedTempFile::new_in(parent)?;
-    tmp.write_all(bytes)?;
-    tmp.flush()?;
-    tmp.persist(path)?;
-    Ok(())
-}
-
 const HELP_TEXT: &str = r#"
 Input:
   • Enter submits
   • Shift+Enter inserts newline (best effort); Ctrl+Enter as fallback
   • Pasting preserves newlines and does not auto-submit
+  • Left/Right/Home/End move the cursor; typing and Backspace/Delete edit there
+  • Up/Down recall earlier messages; Tab completes /commands and paths
 Commands:
   • /env KEY=VAL       – set & persist an env var
+  • /apply GLOB INSTRUCTION – make the same edit to every file matching GLOB
   • /model <MODEL_ID>  – switch model for this session
-  • /capabilities      – show detected tools/providers
+  • /capabilities [--json] – show detected tools/providers
+  • /validate-config   – check every model in models.json resolves
+  • /scope [PATH...]   – confine work to paths/globs (`/scope clear` resets)
+  • /replay FILE [--apply] – re-run a transcript's plans (dry run by default)
+  • /plan save|load|run FILE – keep the last plan as JSON, edit it, re-run it
+  • /ask QUESTION      – ask the model directly, streaming its answer
+  • /tool [NAME ARGS...] – list the project's tools, or run one
+  • /ignore PATTERN... – add patterns to .gitignore
+  • /exec FILE         – run a Python, JS, Go, Ruby, shell or Rust snippet
+  • /fix COMMAND       – run COMMAND, patching and re-running it while it fails
+  • /ab M1 M2 REQUEST  – plan REQUEST with two models and compare the plans
+  • /diff PATH INSTRUCTION – preview the model's edit to PATH without writing it
+  • /diff-session      – show everything changed this session
+  • /undo PATH         – restore PATH from its latest backup
+  • /replan            – plan the last request again, ignoring the plan cache
+  • /budget            – show tokens used against the budget
+  • /timeline [export FILE [--clear]] – show self-healing runs, or append them as JSONL
+  • /clear-cache       – forget cached LLM replies (see --cache)
   • /help              – this message
   • /quit or /exit     – quit shellcraft
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{self, Reply, Server};
+    use std::time::{Duration, Instant};
+
+    fn edit(path: &str) -> planner::EditPlan {
+        planner::EditPlan {
+            path: path.to_string(),
+            intent: "rewrite it".to_string(),
+        }
+    }
+
+    async fn apply(root: &Path, plan: &planner::Plan) -> transcript::Turn {
+        let mut turn = transcript::Turn::new("test");
+        let running = AtomicBool::new(true);
+        let config = config::Config::default();
+        apply_plan(root, &config, plan, false, &mut turn, &running)
+            .await
+            .unwrap();
+        turn
+    }
+
+    #[tokio::test]
+    async fn planned_reads_return_every_file_in_plan_order() {
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        let names: Vec<String> = (0..8).map(|i| format!("f{i}.txt")).collect();
+        for name in &names {
+            std::fs::write(dir.path().join(name), format!("content of {name}")).unwrap();
+        }
+        std::fs::write(dir.path().join("logo.png"), b"\x89PNG\0\0").unwrap();
+
+        let paths: Vec<PathBuf> = names.iter().map(|n| dir.path().join(n)).collect();
+        let contents = read_files(paths.iter().map(PathBuf::as_path)).await;
+        let texts: Vec<String> = contents
+            .into_iter()
+            .map(|c| String::from_utf8(c.unwrap()).unwrap())
+            .collect();
+        let expected: Vec<String> = names.iter().map(|n| format!("content of {n}")).collect();
+        assert_eq!(texts, expected);
+
+        let plan = planner::Plan {
+            read: vec!["f0.txt".into(), "logo.png".into(), "f1.txt".into()],
+            ..Default::default()
+        };
+        let turn = apply(dir.path(), &plan).await;
+        assert_eq!(turn.skipped, ["read `logo.png`: binary file"]);
+    }
+
+    #[tokio::test]
+    async fn batches_run_each_parsed_request_in_order() {
+        let requests = cli::parse_batch("add a README\n\nfix the\nbuild\n");
+        let running = AtomicBool::new(true);
+        let mut seen = Vec::new();
+
+        run_requests(&requests, &cli::Options::default(), &running, |request| {
+            seen.push(request);
+            async { Ok(()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(seen, ["add a README", "fix the\nbuild"]);
+    }
+
+    #[test]
+    fn the_approval_gate_follows_the_users_answers() {
+        let decisions = |answers: &str, edits: usize| {
+            let mut gate = ApprovalGate::new(true);
+            let mut input = std::io::Cursor::new(answers.to_string());
+            (0..edits)
+                .map(|_| gate.decide(&mut input).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            decisions("n\ny\n\n", 3),
+            [Some("declined"), None, Some("declined")]
+        );
+        // `a` applies the rest without asking; `q` skips the rest.
+        assert_eq!(decisions("a\n", 3), [None, None, None]);
+        let stopped = Some("not applied (stopped)");
+        assert_eq!(decisions("y\nq\n", 3), [None, stopped, stopped]);
+        assert_eq!(ApprovalGate::new(false).decide(&mut "".as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn model_sets_the_given_id_and_needs_one() {
+        let mut chosen = None;
+        model_command("gpt-4o", |id| chosen = Some(id.to_string())).unwrap();
+        assert_eq!(chosen.as_deref(), Some("gpt-4o"));
+
+        let err = model_command("", |_| panic!("no model to set")).unwrap_err();
+        assert!(err.to_string().contains("usage: /model"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn apply_edits_every_file_matching_the_glob() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        for file in ["src/a.rs", "src/b.rs", "README.md"] {
+            std::fs::write(dir.path().join(file), "old\n").unwrap();
+        }
+        let server = Server::start(vec![Reply::chat("new\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+        let mut turn = transcript::Turn::new("/apply");
+
+        let running = AtomicBool::new(true);
+        apply_to_glob(dir.path(), "src/*.rs add a header", &mut turn, &running)
+            .await
+            .unwrap();
+
+        let sent = server.requests();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().any(|r| r.body.contains("src/a.rs")));
+        assert!(sent.iter().any(|r| r.body.contains("src/b.rs")));
+        assert_eq!(turn.changes.len(), 2);
+        let readme = std::fs::read_to_string(dir.path().join("README.md")).unwrap();
+        assert_eq!(readme, "old\n");
+    }
+
+    #[tokio::test]
+    async fn ab_plans_the_request_with_both_models() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.rs"), "fn a() {}\n").unwrap();
+        let server = Server::start(vec![Reply::chat(r#"{"notes": "read a.rs first"}"#)]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let shown = compare_plans(dir.path(), "model-a model-b tidy up a.rs", 60)
+            .await
+            .unwrap();
+
+        let mut models: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|r| {
+                let body: serde_json::Value = serde_json::from_str(&r.body).unwrap();
+                body["model"].as_str().unwrap().to_string()
+            })
+            .collect();
+        models.sort();
+        assert_eq!(models, ["model-a", "model-b"]);
+        assert_eq!(shown.matches("read a.rs first").count(), 2, "{shown}");
+    }
+
+    #[tokio::test]
+    async fn failed_proposals_leave_files_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "original\n").unwrap();
+        let server = Server::start(vec![Reply::new(400, r#"{"error":"bad"}"#)]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::Plan {
+            edit: vec![edit("a.txt")],
+            ..Default::default()
+        };
+        let turn = apply(dir.path(), &plan).await;
+
+        let content = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+        assert_eq!(content, "original\n");
+        assert!(turn.changes.is_empty());
+        assert!(turn.skipped.iter().any(|s| s.starts_with("edit `a.txt`")));
+    }
+
+    #[tokio::test]
+    async fn edits_to_denied_files_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("Cargo.lock"), "locked\n").unwrap();
+        std::fs::write(dir.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+        let server = Server::start(vec![Reply::chat("rewritten\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::Plan {
+            edit: vec![edit("Cargo.lock"), edit("src/main.rs")],
+            ..Default::default()
+        };
+        let turn = apply(dir.path(), &plan).await;
+
+        let lock = std::fs::read_to_string(dir.path().join("Cargo.lock")).unwrap();
+        assert_eq!(lock, "locked\n");
+        let main = std::fs::read_to_string(dir.path().join("src/main.rs")).unwrap();
+        assert_eq!(main, "rewritten\n");
+        assert_eq!(
+            turn.skipped,
+            ["edit `Cargo.lock`: matches edit deny pattern `*.lock`"]
+        );
+        // Only the allowed file was sent to the model.
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn preflight_drops_and_skipped_edits_are_reported_together() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("logo.png"), "not text").unwrap();
+        let server = Server::start(vec![Reply::chat("rewritten\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let mut plan = planner::Plan {
+            edit: vec![edit("logo.png")],
+            actions: vec![planner::Action::Shell {
+                command: "cargo test".to_string(),
+            }],
+            ..Default::default()
+        };
+        planner::preflight_actions(&capabilities::Manifest::default(), &mut plan);
+        let turn = apply(dir.path(), &plan).await;
+
+        assert_eq!(
+            turn.skipped,
+            [
+                "action `cargo`: binary `cargo` not on PATH",
+                "edit `logo.png`: binary file",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn files_outside_the_scope_are_neither_planned_nor_edited() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/auth")).unwrap();
+        std::fs::write(dir.path().join("src/auth/login.rs"), "fn login() {}\n").unwrap();
+        std::fs::write(dir.path().join("src/db.rs"), "fn connect() {}\n").unwrap();
+        let plan_json = serde_json::json!({
+            "edit": [
+                {"path": "src/db.rs", "intent": "rename"},
+                {"path": "src/auth/login.rs", "intent": "rename"},
+            ],
+        });
+        let server = Server::start(vec![
+            Reply::chat(&plan_json.to_string()),
+            Reply::chat("rewritten\n"),
+        ]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        fsutil::add_scope(["src/auth/*".to_string()]);
+        let plan = planner::plan_changes(dir.path(), "rename", &Default::default()).await;
+        let turn = apply(dir.path(), &plan.unwrap()).await;
+        fsutil::clear_scope();
+
+        let prompt = &server.requests()[0].body;
+        assert!(prompt.contains("src/auth/login.rs"), "{prompt}");
+        assert!(!prompt.contains("src/db.rs"), "{prompt}");
+        let db = std::fs::read_to_string(dir.path().join("src/db.rs")).unwrap();
+        assert_eq!(db, "fn connect() {}\n");
+        let login = std::fs::read_to_string(dir.path().join("src/auth/login.rs")).unwrap();
+        assert_eq!(login, "rewritten\n");
+        assert_eq!(turn.skipped, ["edit `src/db.rs`: outside the scope"]);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_refuses_plan_save_and_timeline_export() {
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        let plan_file = dir.path().join("plan.json");
+        let timeline_file = dir.path().join("timeline.jsonl");
+
+        fsutil::set_read_only(true);
+        let saved = planner::Plan::default().save(&plan_file);
+        let exported = timeline_command(&format!("export {}", timeline_file.display()));
+        fsutil::set_read_only(false);
+
+        assert!(saved.is_err());
+        assert!(exported.is_err());
+        assert!(!plan_file.exists());
+        assert!(!timeline_file.exists());
+    }
+
+    #[tokio::test]
+    async fn read_only_plans_leave_files_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "original\n").unwrap();
+        std::fs::write(dir.path().join("b.txt"), "doomed\n").unwrap();
+        let server = Server::start(vec![Reply::chat("rewritten\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::Plan {
+            edit: vec![edit("a.txt")],
+            delete: vec!["b.txt".to_string()],
+            ..Default::default()
+        };
+        fsutil::set_read_only(true);
+        let turn = apply(dir.path(), &plan).await;
+        fsutil::set_read_only(false);
+
+        let a = std::fs::read_to_string(dir.path().join("a.txt")).unwrap();
+        assert_eq!(a, "original\n");
+        let b = std::fs::read_to_string(dir.path().join("b.txt")).unwrap();
+        assert_eq!(b, "doomed\n");
+        assert!(!dir.path().join(".shellcraft").exists());
+        assert!(turn.changes.is_empty());
+        // The edit was still proposed, and shown.
+        assert_eq!(server.requests().len(), 1);
+        assert!(turn
+            .skipped
+            .contains(&"edit `a.txt`: read-only mode".to_string()));
+    }
+
+    #[tokio::test]
+    async fn create_actions_write_the_proposed_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::start(vec![Reply::chat("# Greeter\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan: planner::Plan = serde_json::from_str(
+            r#"{"actions": [{"kind": "create", "path": "docs/README.md", "content_intent": "a title"}]}"#,
+        )
+        .unwrap();
+        let turn = apply(dir.path(), &plan).await;
+
+        let created = std::fs::read_to_string(dir.path().join("docs/README.md")).unwrap();
+        assert_eq!(created, "# Greeter\n");
+        assert!(matches!(
+            &turn.changes[..],
+            [transcript::FileChange::Create { path, .. }] if path == "docs/README.md"
+        ));
+    }
+
+    #[tokio::test]
+    async fn edits_to_missing_files_create_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let plan_json = r#"{"edit": [{"path": "src/new.rs", "intent": "scaffold"}]}"#;
+        let server = Server::start(vec![
+            Reply::chat(plan_json),
+            Reply::chat("pub fn new() {}\n"),
+        ]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::plan_changes(dir.path(), "scaffold", &Default::default()).await;
+        let turn = apply(dir.path(), &plan.unwrap()).await;
+
+        let created = std::fs::read_to_string(dir.path().join("src/new.rs")).unwrap();
+        assert_eq!(created, "pub fn new() {}\n");
+        assert!(matches!(
+            &turn.changes[..],
+            [transcript::FileChange::Create { path, .. }] if path == "src/new.rs"
+        ));
+    }
+
+    #[tokio::test]
+    async fn edits_of_one_plan_are_proposed_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["a.rs", "b.rs", "c.rs"];
+        for name in names {
+            std::fs::write(dir.path().join(name), "old\n").unwrap();
+        }
+        let delay = Duration::from_millis(800);
+        let server = Server::start(vec![Reply::chat("new\n").delayed(delay)]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::Plan {
+            edit: names.iter().map(|name| edit(name)).collect(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let turn = apply(dir.path(), &plan).await;
+
+        // One at a time would take three delays.
+        assert!(started.elapsed() < delay * 2, "{:?}", started.elapsed());
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(turn.changes.len(), 3);
+        for name in names {
+            let text = std::fs::read_to_string(dir.path().join(name)).unwrap();
+            assert_eq!(text, "new\n", "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn ctrl_c_mid_plan_skips_the_remaining_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["a.rs", "b.rs", "c.rs"];
+        for name in names {
+            std::fs::write(dir.path().join(name), "old\n").unwrap();
+        }
+        let slow = Duration::from_secs(3);
+        let server = Server::start(vec![
+            Reply::chat("new\n"),
+            Reply::chat("new\n").delayed(slow),
+            Reply::chat("new\n"),
+        ]);
+        let _route = mock::route_to(&server, "mock").await;
+        let plan = planner::Plan {
+            edit: names.iter().map(|name| edit(name)).collect(),
+            ..Default::default()
+        };
+        let mut config = config::Config::default();
+        config.edit.concurrency = 1;
+        let mut turn = transcript::Turn::new("test");
+        let running = AtomicBool::new(true);
+        // Press Ctrl+C while the second edit is being proposed.
+        let ctrl_c = async {
+            while server.requests().len() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            running.store(false, Ordering::SeqCst);
+            llm::signal_cancel();
+        };
+
+        let started = Instant::now();
+        let (result, ()) = tokio::join!(
+            apply_plan(dir.path(), &config, &plan, false, &mut turn, &running),
+            ctrl_c
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(errors::Failure::Interrupted(_))
+        ));
+        assert!(started.elapsed() < slow, "{:?}", started.elapsed());
+        assert_eq!(server.requests().len(), 2);
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(
+            [read("a.rs"), read("b.rs"), read("c.rs")],
+            ["new\n", "old\n", "old\n"]
+        );
+        assert_eq!(turn.changes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn diff_previews_show_the_change_without_writing_it() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("lib.rs"), "fn old() {}\n").unwrap();
+        let server = Server::start(vec![Reply::chat("fn renamed() {}\n")]);
+        let _route = mock::route_to(&server, "mock").await;
+        let running = AtomicBool::new(true);
+
+        let rendered = diff_preview(dir.path(), "lib.rs rename old", &running)
+            .await
+            .unwrap();
+
+        let rendered = console::strip_ansi_codes(&rendered).into_owned();
+        assert!(rendered.contains("-fn old() {}"), "{rendered}");
+        assert!(rendered.contains("+fn renamed() {}"), "{rendered}");
+        let on_disk = std::fs::read_to_string(dir.path().join("lib.rs")).unwrap();
+        assert_eq!(on_disk, "fn old() {}\n");
+        assert!(diff_preview(dir.path(), "lib.rs", &running).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn move_actions_rename_the_file_keeping_its_content() {
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.rs"), "fn a() {}\n").unwrap();
+
+        let plan = planner::Plan {
+            actions: vec![planner::Action::Move {
+                from: "a.rs".to_string(),
+                to: "src/b.rs".to_string(),
+            }],
+            ..Default::default()
+        };
+        let turn = apply(dir.path(), &plan).await;
+
+        assert!(!dir.path().join("a.rs").exists());
+        let moved = std::fs::read_to_string(dir.path().join("src/b.rs")).unwrap();
+        assert_eq!(moved, "fn a() {}\n");
+        assert!(turn.skipped.is_empty(), "{:?}", turn.skipped);
+    }
+
+    #[tokio::test]
+    async fn empty_proposals_create_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = Server::start(vec![Reply::chat("  \n")]);
+        let _route = mock::route_to(&server, "mock").await;
+
+        let plan = planner::Plan {
+            actions: vec![planner::Action::Create {
+                path: "new.txt".to_string(),
+                content_intent: "a greeting".to_string(),
+            }],
+            ..Default::default()
+        };
+        let turn = apply(dir.path(), &plan).await;
+
+        assert!(!dir.path().join("new.txt").exists());
+        assert!(turn.changes.is_empty());
+        assert_eq!(
+            turn.skipped,
+            vec!["create `new.txt`: the model returned no content".to_string()]
+        );
+    }
+}
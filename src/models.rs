@@ -1,6 +1,9 @@
+use anyhow::{anyhow, bail, Context, Result};
 use serde::Deserialize;
 use std::fs;
 
+use crate::config;
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ModelInfo {
     pub id: String,
@@ -8,9 +11,80 @@ pub struct ModelInfo {
     #[serde(default)]
     pub api_key_env: String,
     #[serde(default)]
-    pub tools: Vec<String>,
-    #[serde(default)]
     pub specialty: String,
+    /// Largest completion the model accepts; larger `max_tokens` requests are
+    /// clamped to it.
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    /// Per-request HTTP timeout in seconds (default 120).
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Endpoint overriding the provider's default (e.g. `${AZURE_BASE_URL}`).
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// API key used instead of `api_key_env`; normally a reference such as
+    /// `${OPENAI_API_KEY}` rather than the secret itself.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+impl ModelInfo {
+    /// Expand `${VAR}` references in `id`, `base_url` and `api_key`.
+    fn resolve_env(&mut self) -> Result<()> {
+        let id = self.id.clone();
+        let field = |name: &str, value: &str| {
+            interpolate_env(value).with_context(|| format!("model `{id}` field `{name}`"))
+        };
+        self.id = field("id", &self.id)?;
+        if let Some(base) = &self.base_url {
+            self.base_url = Some(field("base_url", base)?);
+        }
+        if let Some(key) = &self.api_key {
+            self.api_key = Some(field("api_key", key)?);
+        }
+        Ok(())
+    }
+}
+
+/// `value` with every `${VAR}` replaced by that environment variable; an unset
+/// variable or an unterminated `${` is an error.
+pub fn interpolate_env(value: &str) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated `${{` in `{value}`"))?;
+        let name = &after[..end];
+        match config::env_var(name) {
+            Ok(v) => out.push_str(&v),
+            Err(_) => bail!("environment variable `{name}` is not set"),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Kinds of work that can be routed to a specialist model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskType {
+    /// Writing or fixing code: edits and patches.
+    Code,
+    /// Planning and other multi-step reasoning.
+    Reasoning,
+}
+
+impl TaskType {
+    /// Words in a model's `specialty` that mark it for this task.
+    fn specialty_words(self) -> &'static [&'static str] {
+        match self {
+            TaskType::Code => &["code", "coding", "coder"],
+            TaskType::Reasoning => &["reasoning"],
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -20,17 +94,105 @@ pub struct ModelRegistry {
     pub models: Vec<ModelInfo>,
 }
 
+/// The model config file: `MODEL_CONFIG`, else `models.json`.
+pub fn config_path() -> String {
+    config::env_var("MODEL_CONFIG").unwrap_or_else(|_| "models.json".into())
+}
+
 impl ModelRegistry {
+    /// Load the model config. Entries referencing an unset `${VAR}` are
+    /// dropped with a warning.
     pub fn load() -> Self {
-        let path = std::env::var("MODEL_CONFIG").unwrap_or_else(|_| "models.json".into());
+        let path = config_path();
         let data = fs::read_to_string(&path).unwrap_or_else(|_| "{}".into());
-        serde_json::from_str(&data).unwrap_or_else(|_| ModelRegistry {
+        let mut registry: Self = serde_json::from_str(&data).unwrap_or_else(|_| Self::builtin());
+        registry.models.retain_mut(|m| match m.resolve_env() {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("warning: ignoring a model in {path}: {e:#}");
+                false
+            }
+        });
+        registry
+    }
+
+    /// Like `load`, but a config file that exists and doesn't parse, or that
+    /// references an unset `${VAR}`, is an error instead.
+    pub fn load_strict() -> Result<Self> {
+        let path = config_path();
+        let Ok(data) = fs::read_to_string(&path) else {
+            return Ok(Self::builtin());
+        };
+        let mut registry: Self =
+            serde_json::from_str(&data).with_context(|| format!("parse {path}"))?;
+        for model in &mut registry.models {
+            model.resolve_env().with_context(|| path.clone())?;
+        }
+        Ok(registry)
+    }
+
+    /// The registry used without a (valid) config file.
+    fn builtin() -> Self {
+        ModelRegistry {
             default_model: "gpt-4o-mini".into(),
             models: vec![],
-        })
+        }
     }
 
     pub fn get(&self, id: &str) -> Option<&ModelInfo> {
         self.models.iter().find(|m| m.id == id)
     }
+
+    /// Models whose `specialty` names `task` (e.g. "code" or "general coding"
+    /// for `TaskType::Code`), in registry order.
+    pub fn for_task(&self, task: TaskType) -> impl Iterator<Item = &ModelInfo> {
+        let words = task.specialty_words();
+        self.models.iter().filter(move |m| {
+            m.specialty
+                .to_ascii_lowercase()
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .any(|w| words.contains(&w))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_references_in_models_are_interpolated() {
+        config::set_env_override("TEST_KEY", "sk-test");
+        let model = |json: &str| serde_json::from_str::<ModelInfo>(json).unwrap();
+
+        let mut openai = model(r#"{"id": "gpt", "provider": "openai", "api_key": "${TEST_KEY}"}"#);
+        openai.resolve_env().unwrap();
+        assert_eq!(openai.api_key.as_deref(), Some("sk-test"));
+
+        let mut azure = model(
+            r#"{"id": "gpt", "provider": "azure", "base_url": "https://${TEST_UNSET_HOST}/v1"}"#,
+        );
+        let err = format!("{:#}", azure.resolve_env().unwrap_err());
+        assert!(err.contains("base_url"), "{err}");
+        assert!(err.contains("`TEST_UNSET_HOST` is not set"), "{err}");
+    }
+
+    #[test]
+    fn tasks_resolve_to_their_specialists() {
+        let registry: ModelRegistry = serde_json::from_str(
+            r#"{
+                "default_model": "gpt-4o-mini",
+                "models": [
+                    {"id": "gpt-4o-mini", "provider": "openai", "specialty": "general"},
+                    {"id": "qwen-coder", "provider": "ollama", "specialty": "General coding"},
+                    {"id": "o3-mini", "provider": "openai", "specialty": "reasoning"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let first = |task| registry.for_task(task).next().map(|m| m.id.as_str());
+        assert_eq!(first(TaskType::Code), Some("qwen-coder"));
+        assert_eq!(first(TaskType::Reasoning), Some("o3-mini"));
+    }
 }
@@ -1,13 +1,24 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::Path;
+use std::sync::Mutex;
 
 use crate::capabilities::{can_run, system_preamble, Manifest};
-use crate::fsutil::{file_inventory, FileMeta};
+use crate::config;
+use crate::errors::Failure;
+use crate::fsutil::{
+    self, embedding_text, file_inventory, git_recent_paths, in_scope, modified_ns,
+    rank_by_similarity, resolve_within, EmbeddingCache, FileMeta,
+};
 use crate::llm;
+use crate::models::TaskType;
 
 /// Final plan from planner
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Plan {
     #[serde(default)]
     pub read: Vec<String>,
@@ -19,6 +30,65 @@ pub struct Plan {
     pub actions: Vec<Action>,
     #[serde(default)]
     pub notes: String,
+    /// Operations dropped before execution, with the reason for each.
+    #[serde(skip)]
+    pub skipped: Vec<String>,
+    /// Set when the plan was reused from an identical earlier request.
+    #[serde(skip)]
+    pub from_cache: bool,
+}
+
+impl Plan {
+    /// Write the plan as pretty-printed JSON so it can be reviewed or edited.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if fsutil::is_read_only() {
+            anyhow::bail!("read-only mode: not writing plan {}", path.display());
+        }
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json + "\n").with_context(|| format!("write plan {}", path.display()))
+    }
+
+    /// Read a plan written by `save` (or by hand).
+    pub fn load(path: &Path) -> Result<Plan> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read plan {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("parse plan {}", path.display()))
+    }
+}
+
+/// Plans made this session, keyed by `plan_cache_key`.
+static PLAN_CACHE: Lazy<Mutex<HashMap<u64, Plan>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Forget cached plans so the next request is planned afresh.
+pub fn clear_plan_cache() {
+    PLAN_CACHE.lock().unwrap().clear();
+}
+
+/// Hash of everything a plan depends on: the request, the model, the
+/// manifest, and the indexed files with their sizes and modification times (so
+/// any file change invalidates it).
+fn plan_cache_key(
+    root: &Path,
+    user_request: &str,
+    model: Option<&str>,
+    index: &[FileMeta],
+    manifest: &Manifest,
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    user_request.hash(&mut hasher);
+    model.hash(&mut hasher);
+    serde_json::to_string(manifest)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    for m in index {
+        m.path.hash(&mut hasher);
+        m.size.hash(&mut hasher);
+        std::fs::metadata(root.join(&m.path))
+            .and_then(|md| md.modified())
+            .ok()
+            .hash(&mut hasher);
+    }
+    hasher.finish()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,6 +113,19 @@ pub enum Action {
         #[serde(default = "default_backoff")]
         backoff_ms: u64,
     },
+    /// A shell command line (pipelines, redirections) run by the platform
+    /// shell through `CommandRunner`; its first word must be an enabled tool.
+    #[serde(rename = "shell")]
+    Shell { command: String },
+    /// Write a new file whose content the model proposes from `content_intent`.
+    #[serde(rename = "create")]
+    Create {
+        path: String,
+        content_intent: String,
+    },
+    /// Rename a file or directory, keeping its content (and git history).
+    #[serde(rename = "move")]
+    Move { from: String, to: String },
 }
 
 fn default_retries() -> u32 {
@@ -67,19 +150,29 @@ fn guidance() -> String {
   - add paths to `read` to view file contents
   - provide {path,intent} entries in `edit` to modify files
   - add paths in `delete` to remove them
+  - add a `create` action with a path and a description of its content to
+    add a new file; an `edit` of a path that doesn't exist also creates it
+  - use a `move` action to rename a file instead of deleting and recreating it
 - Do not call external tools like `repo_browser.print_tree`; the file index
   already contains the repository structure.
 - Prefer touching the fewest files.
 - If the ask is informational only, leave `edit=[]` and put a short answer in `notes`.
 - Use actions only for tools that are enabled in the capabilities list.
 - For Rust projects, typical actions are: `cargo build`, `cargo test`.
+- Use a `shell` action only when you need a pipeline or redirection (e.g.
+  `grep -rn foo src | wc -l`); otherwise prefer `run`.
 - Always fill `retries` and `backoff_ms` (small numbers).
 Schema:
 {
   "read": string[],
   "edit": [{"path": string, "intent": string}],
   "delete": string[],
-  "actions": [{"kind":"run","program":string,"args":string[],"workdir?":string,"log_hint?":string,"retries":number,"backoff_ms":number}],
+  "actions": [
+    {"kind":"run","program":string,"args":string[],"workdir?":string,"log_hint?":string,"retries":number,"backoff_ms":number}
+    | {"kind":"shell","command":string}
+    | {"kind":"create","path":string,"content_intent":string}
+    | {"kind":"move","from":string,"to":string}
+  ],
   "notes": string
 }
 Return pure JSON, no markdown."#.to_string()
@@ -87,9 +180,53 @@ Return pure JSON, no markdown."#.to_string()
 
 /// Build a plan using the LLM and preflight
 pub async fn plan_changes(root: &Path, user_request: &str, manifest: &Manifest) -> Result<Plan> {
-    let mut index = file_inventory(root)?;
-    if index.len() > 800 {
-        index = compact_index(index);
+    plan_changes_with(root, user_request, manifest, None).await
+}
+
+/// `plan_changes` with `model` instead of the session's default model.
+pub async fn plan_changes_with(
+    root: &Path,
+    user_request: &str,
+    manifest: &Manifest,
+    model: Option<&str>,
+) -> Result<Plan> {
+    let routed = match model {
+        Some(m) => Some(m.to_string()),
+        None => llm::model_for_task(TaskType::Reasoning),
+    };
+    let model = routed.as_deref();
+    let mut index = inventory(root)?;
+    index.retain(|m| in_scope(&m.path));
+    let cache_key = plan_cache_key(root, user_request, model, &index, manifest);
+    if let Some(plan) = PLAN_CACHE.lock().unwrap().get(&cache_key) {
+        return Ok(Plan {
+            from_cache: true,
+            ..plan.clone()
+        });
+    }
+    let compact = CompactConfig::load(root);
+    if index.len() > compact.max_files {
+        let semantic = if compact.semantic && !llm::is_offline() {
+            match rank_index_semantically(root, user_request, &index, compact.max_files).await {
+                Ok(ranked) => Some(ranked),
+                Err(e) => {
+                    eprintln!("warning: semantic file ranking failed: {e:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        index = match semantic {
+            Some(ranked) => ranked,
+            None => compact_index(index, &compact, &git_recent_paths(root)),
+        };
+    }
+
+    if llm::is_offline() {
+        let plan = offline_plan(user_request, &index);
+        PLAN_CACHE.lock().unwrap().insert(cache_key, plan.clone());
+        return Ok(plan);
     }
 
     // Ask LLM with capability preamble
@@ -101,82 +238,599 @@ pub async fn plan_changes(root: &Path, user_request: &str, manifest: &Manifest)
         capabilities: &preamble,
     };
 
-    let mut plan: Plan = llm::chat_json(
+    let reply = llm::chat_json_result_with::<Plan>(
+        model,
         &format!("You are a senior planner.\n{}\n", preamble),
         &serde_json::to_string(&prompt).unwrap(),
     )
     .await
     .context("planner LLM failed")?;
-    if !validate_plan_paths(root, &plan) {
-        return Err(anyhow!("LLM returned non-existent file paths"));
+    let mut plan = match reply {
+        llm::ChatJsonResult::Parsed(plan) => plan,
+        llm::ChatJsonResult::Fallback { raw } => {
+            return Err(Failure::PlanInvalid(format!(
+                "the model didn't return a plan; here's what it said:\n{}",
+                raw.trim()
+            ))
+            .into());
+        }
+    };
+    if let Err(why) = validate_plan_paths(root, &plan) {
+        return Err(Failure::PlanInvalid(format!("LLM returned an invalid plan: {why}")).into());
     }
 
-    // Preflight: drop invalid actions & annotate notes
+    // Preflight: drop invalid actions, recording why in `plan.skipped`
     preflight_actions(manifest, &mut plan);
 
+    PLAN_CACHE.lock().unwrap().insert(cache_key, plan.clone());
     Ok(plan)
 }
 
-fn validate_plan_paths(root: &Path, plan: &Plan) -> bool {
-    for p in plan
+/// Most files an offline plan reads.
+const OFFLINE_MAX_READS: usize = 10;
+
+/// A plan made without a model, for `SHELLCRAFT_OFFLINE=1`: it reads the
+/// indexed files the request names (by path or file name) and changes nothing.
+pub fn offline_plan(user_request: &str, index: &[FileMeta]) -> Plan {
+    let words: HashSet<&str> = user_request
+        .split_whitespace()
+        .map(|w| w.trim_matches(|c: char| "`'\"(),;:!?".contains(c)))
+        .map(|w| w.trim_end_matches('.'))
+        .filter(|w| !w.is_empty())
+        .collect();
+    let read: Vec<String> = index
+        .iter()
+        .filter(|m| {
+            let name = Path::new(&m.path).file_name().and_then(|n| n.to_str());
+            words.contains(m.path.as_str()) || name.is_some_and(|n| words.contains(n))
+        })
+        .map(|m| m.path.clone())
+        .take(OFFLINE_MAX_READS)
+        .collect();
+    let notes = if read.is_empty() {
+        "Offline mode: no model was asked, and the request names no known files.".to_string()
+    } else {
+        format!(
+            "Offline mode: no model was asked; reading the files the request names ({}).",
+            read.join(", ")
+        )
+    };
+    Plan {
+        read,
+        notes,
+        ..Plan::default()
+    }
+}
+
+/// Every path in the plan, run actions' `workdir`s included, must resolve
+/// inside `root` (no `..` or symlink escapes, no absolute paths outside it),
+/// and every read and delete target
+/// must exist, except files the plan itself creates or moves into place.
+/// Edits may name new files: they start empty.
+fn validate_plan_paths(root: &Path, plan: &Plan) -> Result<(), String> {
+    let created: Vec<&String> = plan
+        .actions
+        .iter()
+        .filter_map(|a| match a {
+            Action::Create { path, .. } => Some(path),
+            Action::Move { to, .. } => Some(to),
+            _ => None,
+        })
+        .collect();
+    let moved_from = plan.actions.iter().filter_map(|a| match a {
+        Action::Move { from, .. } => Some(from),
+        _ => None,
+    });
+    let workdirs = plan.actions.iter().filter_map(|a| match a {
+        Action::Run { workdir, .. } => workdir.as_ref(),
+        _ => None,
+    });
+    let all_paths = plan
         .read
         .iter()
-        .chain(plan.edit.iter().map(|e| &e.path))
         .chain(plan.delete.iter())
-    {
-        if !root.join(p).exists() {
-            return false;
+        .chain(plan.edit.iter().map(|e| &e.path))
+        .chain(created.iter().copied())
+        .chain(moved_from)
+        .chain(workdirs);
+    for p in all_paths {
+        resolve_within(root, p).map_err(|e| e.to_string())?;
+    }
+    for p in plan.read.iter().chain(plan.delete.iter()) {
+        if !root.join(p).exists() && !created.contains(&p) {
+            return Err(format!("`{p}` does not exist"));
         }
     }
-    true
+    Ok(())
+}
+
+/// Default cap on the number of arguments of a run action.
+pub const DEFAULT_MAX_ACTION_ARGS: usize = 64;
+/// Default cap on the combined byte length of a run action's arguments.
+pub const DEFAULT_MAX_ACTION_ARG_BYTES: usize = 16 * 1024;
+
+static ACTION_LIMITS: Lazy<Mutex<(usize, usize)>> =
+    Lazy::new(|| Mutex::new((DEFAULT_MAX_ACTION_ARGS, DEFAULT_MAX_ACTION_ARG_BYTES)));
+
+/// Set the argument count and total-length caps enforced by
+/// `preflight_actions`.
+pub fn set_action_limits(max_args: usize, max_arg_bytes: usize) {
+    *ACTION_LIMITS.lock().unwrap() = (max_args, max_arg_bytes);
+}
+
+/// Why a run action's arguments are unacceptable, if they are.
+fn check_action_args(program: &str, args: &[String]) -> Option<String> {
+    let (max_args, max_bytes) = *ACTION_LIMITS.lock().unwrap();
+    if program.contains('\0') || args.iter().any(|a| a.contains('\0')) {
+        return Some("argument contains a NUL byte".into());
+    }
+    if args.len() > max_args {
+        return Some(format!(
+            "{} arguments exceed the limit of {}",
+            args.len(),
+            max_args
+        ));
+    }
+    let bytes: usize = args.iter().map(String::len).sum();
+    if bytes > max_bytes {
+        return Some(format!(
+            "arguments total {} bytes, over the limit of {}",
+            bytes, max_bytes
+        ));
+    }
+    None
 }
 
 pub fn preflight_actions(manifest: &Manifest, plan: &mut Plan) {
     let mut kept = vec![];
-    let mut dropped = vec![];
     for a in &plan.actions {
         match a {
-            Action::Run { program, .. } => {
+            Action::Run { program, args, .. } => {
+                if let Some(why) = check_action_args(program, args) {
+                    plan.skipped.push(format!("action `{}`: {}", program, why));
+                    continue;
+                }
                 let (ok, why) = can_run(manifest, program);
                 if ok {
                     kept.push(a.clone());
                 } else {
-                    dropped.push(format!(
-                        "drop action `{}`: {}",
-                        program,
-                        why.unwrap_or_default()
-                    ));
+                    plan.skipped
+                        .push(format!("action `{}`: {}", program, why.unwrap_or_default()));
                 }
             }
+            Action::Shell { command } => {
+                let program = command.split_whitespace().next().unwrap_or("");
+                if program.is_empty() {
+                    plan.skipped.push("shell action: empty command".into());
+                    continue;
+                }
+                if let Some(why) = check_action_args(program, std::slice::from_ref(command)) {
+                    plan.skipped.push(format!("action `{}`: {}", program, why));
+                    continue;
+                }
+                let (ok, why) = can_run(manifest, program);
+                if ok {
+                    kept.push(a.clone());
+                } else {
+                    plan.skipped
+                        .push(format!("action `{}`: {}", program, why.unwrap_or_default()));
+                }
+            }
+            // Target paths are checked when the file is written.
+            Action::Create { .. } | Action::Move { .. } => kept.push(a.clone()),
         }
     }
     plan.actions = kept;
-    if !dropped.is_empty() {
-        if !plan.notes.is_empty() {
-            plan.notes.push_str("\n");
+}
+
+/// How the file index is trimmed for large repositories, read from
+/// `.shellcraft/index.toml`:
+///
+/// ```toml
+/// max_files = 800
+/// recent_boost = 5
+/// semantic = false   # rank by embedding similarity to the request instead
+/// [weights]   # merged over the defaults; unlisted extensions weigh 1
+/// ex = 10
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompactConfig {
+    /// Most files sent to the planner.
+    pub max_files: usize,
+    /// Priority per file extension; heavier files are kept first.
+    pub weights: HashMap<String, i32>,
+    /// Extra weight for files changed in the working tree or recent commits.
+    pub recent_boost: i32,
+    /// Keep the files most similar to the request (see `llm::embed`) rather
+    /// than the heaviest; falls back to weights if embedding fails.
+    pub semantic: bool,
+}
+
+impl Default for CompactConfig {
+    fn default() -> Self {
+        let groups: [(&[&str], i32); 3] = [
+            (&["rs", "ts", "tsx", "js", "jsx", "py"], 10),
+            (&["toml", "json", "yml", "yaml", "md"], 8),
+            (&["go", "rb", "java", "kt", "c", "h", "cpp", "hpp"], 7),
+        ];
+        let weights = groups
+            .iter()
+            .flat_map(|(exts, w)| exts.iter().map(move |e| (e.to_string(), *w)))
+            .collect();
+        Self {
+            max_files: 800,
+            weights,
+            recent_boost: 5,
+            semantic: false,
+        }
+    }
+}
+
+impl CompactConfig {
+    pub fn load(root: &Path) -> Self {
+        let path = root.join(".shellcraft").join("index.toml");
+        let Ok(data) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        match toml::from_str::<Self>(&data) {
+            Ok(mut config) => {
+                let mut weights = Self::default().weights;
+                weights.extend(config.weights);
+                config.weights = weights;
+                config
+            }
+            Err(e) => {
+                eprintln!("warning: ignoring invalid {}: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    fn weight(&self, ext: Option<&str>) -> i32 {
+        ext.and_then(|e| self.weights.get(e)).copied().unwrap_or(1)
+    }
+}
+
+/// The index of `root`: the one an `AutonomousRunner` keeps current (see
+/// `fsutil::INVENTORY_ENV`) when it was taken of `root`, else a fresh scan.
+fn inventory(root: &Path) -> Result<Vec<FileMeta>> {
+    if let Ok(path) = config::env_var(fsutil::INVENTORY_ENV) {
+        match fsutil::load_inventory(Path::new(&path), root) {
+            Ok(Some(index)) => return Ok(index),
+            Ok(None) => {}
+            Err(e) => eprintln!("warning: ignoring inventory {path}: {e:#}"),
         }
-        plan.notes.push_str(&format!(
-            "Preflight removed actions not supported in this environment:\n- {}",
-            dropped.join("\n- ")
-        ));
     }
+    file_inventory(root)
 }
 
-/// Keep top ~800 source-like files
-fn compact_index(mut v: Vec<FileMeta>) -> Vec<FileMeta> {
-    fn weight(ext: &str) -> i32 {
-        match ext {
-            "rs" | "ts" | "tsx" | "js" | "jsx" | "py" => 10,
-            "toml" | "json" | "yml" | "yaml" | "md" => 8,
-            "go" | "rb" | "java" | "kt" | "c" | "h" | "cpp" | "hpp" => 7,
-            _ => 1,
+/// The `max_files` files of `index` whose path and opening lines are most
+/// similar to `user_request`, by embedding both. File vectors are cached in
+/// `.shellcraft/` (see `EmbeddingCache`), so only new and changed files are
+/// embedded again.
+async fn rank_index_semantically(
+    root: &Path,
+    user_request: &str,
+    index: &[FileMeta],
+    max_files: usize,
+) -> Result<Vec<FileMeta>> {
+    let mut cache = EmbeddingCache::load(root, &llm::embedding_model());
+    let stamps: Vec<Option<u64>> = index
+        .iter()
+        .map(|m| modified_ns(&root.join(&m.path)))
+        .collect();
+    let mut vectors: Vec<Option<Vec<f32>>> = index
+        .iter()
+        .zip(&stamps)
+        .map(|(m, ns)| {
+            ns.and_then(|ns| cache.get(&m.path, ns))
+                .map(<[f32]>::to_vec)
+        })
+        .collect();
+    let missing: Vec<usize> = (0..index.len()).filter(|&i| vectors[i].is_none()).collect();
+    let texts: Vec<String> = std::iter::once(user_request.to_string())
+        .chain(missing.iter().map(|&i| embedding_text(root, &index[i])))
+        .collect();
+    let embeddings = llm::embed(&texts).await?;
+    let (query, fresh) = embeddings.split_first().context("no request embedding")?;
+    for (&i, vector) in missing.iter().zip(fresh) {
+        if let Some(ns) = stamps[i] {
+            cache.insert(&index[i].path, ns, vector.clone());
         }
+        vectors[i] = Some(vector.clone());
     }
+    let files: Vec<Vec<f32>> = vectors.into_iter().map(Option::unwrap_or_default).collect();
+    if !fsutil::is_read_only() {
+        if let Err(e) = cache.save(root, index) {
+            eprintln!("warning: could not save file embeddings: {e:#}");
+        }
+    }
+    let mut ranked = rank_by_similarity(query, index.to_vec(), &files);
+    ranked.truncate(max_files);
+    Ok(ranked)
+}
+
+/// Keep the `config.max_files` heaviest (then smallest) source-like files;
+/// `recent` paths (see `git_recent_paths`) weigh `config.recent_boost` more.
+pub fn compact_index(
+    mut v: Vec<FileMeta>,
+    config: &CompactConfig,
+    recent: &HashSet<String>,
+) -> Vec<FileMeta> {
     v.sort_by_key(|m| {
-        let w = m.ext.as_deref().map(weight).unwrap_or(1);
-        let size_bucket = (m.size as i64 / 4096) as i64;
+        let mut w = config.weight(m.ext.as_deref());
+        if recent.contains(&m.path) {
+            w += config.recent_boost;
+        }
+        let size_bucket = m.size as i64 / 4096;
         (-(w as i64), size_bucket)
     });
-    v.truncate(800);
+    v.truncate(config.max_files);
     v
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::{self, Reply, Server};
+
+    fn meta(path: &str) -> FileMeta {
+        FileMeta {
+            path: path.to_string(),
+            size: 10,
+            ext: Some("rs".to_string()),
+            is_link: false,
+            is_binary: false,
+        }
+    }
+
+    fn run_in(workdir: &str) -> Plan {
+        Plan {
+            actions: vec![Action::Run {
+                program: "cargo".into(),
+                args: vec!["test".into()],
+                workdir: Some(workdir.into()),
+                log_hint: None,
+                retries: 0,
+                backoff_ms: 0,
+            }],
+            ..Plan::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn saved_plans_load_back_unchanged() {
+        // Read-only mode, which refuses saves, is flipped under this lock.
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+        let mut plan = run_in("crates");
+        plan.read = vec!["src/lib.rs".into()];
+        plan.edit = vec![EditPlan {
+            path: "src/lib.rs".into(),
+            intent: "add a test".into(),
+        }];
+        plan.delete = vec!["old.rs".into()];
+        plan.actions.push(Action::Move {
+            from: "a.rs".into(),
+            to: "b.rs".into(),
+        });
+        plan.notes = "round trip".into();
+
+        plan.save(&path).unwrap();
+        let loaded = Plan::load(&path).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&plan).unwrap()
+        );
+        assert_eq!(loaded.edit[0].intent, "add a test");
+    }
+
+    #[test]
+    fn configured_weights_keep_their_files_through_truncation() {
+        let elixir = FileMeta {
+            ext: Some("ex".to_string()),
+            ..meta("lib/app.ex")
+        };
+        let index = vec![elixir, meta("a.rs"), meta("b.rs")];
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".shellcraft")).unwrap();
+        let index_toml = dir.path().join(".shellcraft/index.toml");
+        let kept = || -> Vec<String> {
+            let config = CompactConfig::load(dir.path());
+            let compact = compact_index(index.clone(), &config, &HashSet::new());
+            compact.into_iter().map(|m| m.path).collect()
+        };
+
+        std::fs::write(&index_toml, "max_files = 2\n").unwrap();
+        assert_eq!(kept(), ["a.rs", "b.rs"]);
+
+        std::fs::write(&index_toml, "max_files = 2\n[weights]\nex = 20\n").unwrap();
+        assert_eq!(kept(), ["lib/app.ex", "a.rs"]);
+    }
+
+    #[test]
+    fn files_changed_in_the_working_tree_rank_first() {
+        if which::which("git").is_err() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=t", "-c", "user.email=t@t", "-C"])
+                .arg(dir.path())
+                .args(args)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "-q"]);
+        for name in ["a.rs", "b.rs", "filler.md"] {
+            std::fs::write(dir.path().join(name), "v1\n").unwrap();
+        }
+        git(&["add", "."]);
+        git(&["commit", "-qm", "start"]);
+        // Push a.rs and b.rs out of the recent commits.
+        for n in 0..fsutil::GIT_RECENT_COMMITS {
+            std::fs::write(dir.path().join("filler.md"), format!("{n}\n")).unwrap();
+            git(&["commit", "-qam", "filler"]);
+        }
+        std::fs::write(dir.path().join("b.rs"), "v2\n").unwrap();
+
+        let recent = fsutil::git_recent_paths(dir.path());
+        let config = CompactConfig {
+            max_files: 1,
+            ..CompactConfig::default()
+        };
+        let kept = compact_index(vec![meta("a.rs"), meta("b.rs")], &config, &recent);
+
+        assert!(!recent.contains("a.rs"), "{recent:?}");
+        assert_eq!(kept[0].path, "b.rs");
+    }
+
+    #[tokio::test]
+    async fn offline_mode_plans_without_a_model() {
+        // Nothing is routed to a mock, so a model call would fail.
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("src")).unwrap();
+        std::fs::write(dir.path().join("src/lib.rs"), "pub fn lib() {}\n").unwrap();
+        std::fs::write(dir.path().join("README.md"), "# demo\n").unwrap();
+
+        config::set_env_override("SHELLCRAFT_OFFLINE", "1");
+        let plan = plan_changes(dir.path(), "explain `src/lib.rs`", &Manifest::default()).await;
+        config::set_env_override("SHELLCRAFT_OFFLINE", "");
+
+        let plan = plan.unwrap();
+        assert_eq!(plan.read, ["src/lib.rs"]);
+        assert!(plan.edit.is_empty() && plan.delete.is_empty() && plan.actions.is_empty());
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(serde_json::from_str::<Plan>(&json).is_ok(), "{json}");
+    }
+
+    #[test]
+    fn run_workdirs_must_stay_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("crates")).unwrap();
+
+        assert_eq!(validate_plan_paths(dir.path(), &run_in("crates")), Ok(()));
+        assert!(validate_plan_paths(dir.path(), &run_in("../elsewhere")).is_err());
+        assert!(validate_plan_paths(dir.path(), &run_in("/")).is_err());
+    }
+
+    #[test]
+    fn plan_paths_must_stay_inside_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("repo");
+        std::fs::create_dir_all(root.join("src/nested")).unwrap();
+        std::fs::write(root.join("src/nested/lib.rs"), "").unwrap();
+        std::fs::write(dir.path().join("outside"), "secret").unwrap();
+        let reading = |path: &str| Plan {
+            read: vec![path.to_string()],
+            ..Plan::default()
+        };
+
+        assert_eq!(
+            validate_plan_paths(&root, &reading("src/nested/lib.rs")),
+            Ok(())
+        );
+        assert!(validate_plan_paths(&root, &reading("../outside")).is_err());
+        let outside = dir.path().join("outside").display().to_string();
+        assert!(validate_plan_paths(&root, &reading(&outside)).is_err());
+    }
+
+    #[test]
+    fn preflight_drops_actions_with_too_many_or_bad_arguments() {
+        let manifest = Manifest {
+            tools: crate::capabilities::Tools {
+                cargo: true,
+                ..Default::default()
+            },
+            ..Manifest::default()
+        };
+        let cargo = |args: Vec<String>| Action::Run {
+            program: "cargo".into(),
+            args,
+            workdir: None,
+            log_hint: None,
+            retries: 0,
+            backoff_ms: 0,
+        };
+        let mut plan = Plan {
+            actions: vec![
+                cargo(vec!["x".into(); DEFAULT_MAX_ACTION_ARGS + 1]),
+                cargo(vec!["te\0st".into()]),
+                cargo(vec!["test".into()]),
+            ],
+            ..Plan::default()
+        };
+
+        preflight_actions(&manifest, &mut plan);
+
+        assert_eq!(plan.actions.len(), 1);
+        assert!(matches!(&plan.actions[0], Action::Run { args, .. } if args == &["test"]));
+        let too_many = format!(
+            "action `cargo`: {} arguments exceed the limit of {}",
+            DEFAULT_MAX_ACTION_ARGS + 1,
+            DEFAULT_MAX_ACTION_ARGS
+        );
+        let nul = "action `cargo`: argument contains a NUL byte".to_string();
+        assert_eq!(plan.skipped, [too_many, nul]);
+    }
+
+    #[tokio::test]
+    async fn semantic_ranking_puts_the_matching_file_first_and_caches_it() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.rs", "b.rs", "c.rs"] {
+            std::fs::write(dir.path().join(name), "fn main() {}\n").unwrap();
+        }
+        let index = vec![meta("a.rs"), meta("b.rs"), meta("c.rs")];
+        let server = Server::start(vec![
+            // The request, then a.rs, b.rs and c.rs.
+            Reply::embeddings(&[&[1.0, 0.0], &[0.0, 1.0], &[0.9, 0.1], &[0.5, 0.5]]),
+            // Only the request once the files are cached.
+            Reply::embeddings(&[&[0.0, 1.0]]),
+        ]);
+        let _route = mock::route_to(&server, "openai").await;
+
+        let ranked = rank_index_semantically(dir.path(), "fix b", &index, 2)
+            .await
+            .unwrap();
+        let paths: Vec<&str> = ranked.iter().map(|m| m.path.as_str()).collect();
+        assert_eq!(paths, ["b.rs", "c.rs"]);
+
+        let ranked = rank_index_semantically(dir.path(), "fix a", &index, 1)
+            .await
+            .unwrap();
+        assert_eq!(ranked[0].path, "a.rs");
+        let sent = server.requests();
+        assert_eq!(sent.len(), 2);
+        let input: serde_json::Value = serde_json::from_str(&sent[1].body).unwrap();
+        assert_eq!(input["input"], serde_json::json!(["fix a"]));
+    }
+
+    #[tokio::test]
+    async fn identical_requests_reuse_the_plan_until_the_files_change() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.rs"), "fn main() {}\n").unwrap();
+        let server = Server::start(vec![Reply::chat(r#"{"read": ["a.rs"]}"#)]);
+        let _route = mock::route_to(&server, "openai").await;
+        let manifest = Manifest::default();
+        let plan = || plan_changes(dir.path(), "look at a", &manifest);
+
+        let first = plan().await.unwrap();
+        let again = plan().await.unwrap();
+        assert!(!first.from_cache);
+        assert!(again.from_cache);
+        assert_eq!(again.read, ["a.rs"]);
+        assert_eq!(server.requests().len(), 1);
+
+        std::fs::write(dir.path().join("b.rs"), "").unwrap();
+        let replanned = plan().await.unwrap();
+        assert!(!replanned.from_cache);
+        assert_eq!(server.requests().len(), 2);
+    }
+}
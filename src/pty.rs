@@ -5,11 +5,14 @@ use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
+    mpsc::{self, RecvTimeoutError},
     Arc,
 };
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::config;
+
 #[cfg(unix)]
 use libc::{ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
 
@@ -18,31 +21,96 @@ use libc::{ioctl, winsize, STDOUT_FILENO, TIOCGWINSZ};
 pub struct PtyRunResult {
     /// Raw exit status from the PTY child.
     pub raw_status: ExitStatus,
-    /// Tail of the captured output (subject to `max_output_bytes` limit).
-    pub last_output: String,
+    /// Tail of the captured output (subject to `max_output_bytes` limit),
+    /// with ANSI escape sequences removed and `\r\n` folded to `\n`, for log
+    /// assertions and error analysis.
+    pub last_output_plain: String,
     /// Whether the process was terminated because it exceeded the timeout.
     pub timed_out: bool,
+    /// Whether the process was killed because the caller's cancel flag was set.
+    pub cancelled: bool,
     /// Optional error message captured from the runner itself (e.g., I/O errors).
     pub error: Option<String>,
 }
 
+/// Whether PTY output is echoed to stdout as it arrives.
+static ECHO_OUTPUT: AtomicBool = AtomicBool::new(true);
+
+/// Echo PTY output live (the default), or only capture and log it, e.g. while
+/// a full-screen view owns the terminal.
+pub fn set_echo(echo: bool) {
+    ECHO_OUTPUT.store(echo, Ordering::Relaxed);
+}
+
+/// Exit code reported by [`PtyRunResult::exit_code`] when the command was
+/// killed for exceeding its timeout (the same code coreutils `timeout` uses).
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+impl PtyRunResult {
+    /// Whether the command exited successfully within its timeout, without
+    /// being cancelled.
+    pub fn success(&self) -> bool {
+        !self.timed_out && !self.cancelled && self.raw_status.success()
+    }
+
+    /// Numeric exit code, or [`TIMEOUT_EXIT_CODE`] if the command timed out.
+    pub fn exit_code(&self) -> Option<i32> {
+        if self.timed_out {
+            return Some(TIMEOUT_EXIT_CODE);
+        }
+        i32::try_from(self.raw_status.exit_code()).ok()
+    }
+}
+
+/// How `run_with_pty` runs a command, beyond the command line itself.
+///
+/// * `env` – extra variables for the child, on top of those set with `/env`;
+///   each pair overrides any inherited variable of the same name and is
+///   visible to the command run inside the shell wrapper.
+/// * `log_path` – path to a file where all PTY output is appended.
+/// * `timeout` – maximum wall‑clock time the command may run.
+/// * `max_output_bytes` – maximum number of output bytes kept for
+///   `last_output_plain`.
+/// * `size` – initial PTY geometry; `None` uses the current terminal size,
+///   falling back to 30x120. SIGWINCH still resizes the PTY afterwards.
+/// * `cancel` – polled while the command runs; once set, the child is killed
+///   and the result is marked `cancelled`.
+#[derive(Debug, Clone, Copy)]
+pub struct PtyOptions<'a> {
+    pub env: &'a [(String, String)],
+    pub log_path: &'a Path,
+    pub timeout: Duration,
+    pub max_output_bytes: usize,
+    pub size: Option<PtySize>,
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+impl<'a> PtyOptions<'a> {
+    /// Options logging to `log_path`, with no extra variables, a ten‑minute
+    /// timeout, an 8 KiB output tail, the terminal's size and no cancel flag.
+    pub fn new(log_path: &'a Path) -> Self {
+        Self {
+            env: &[],
+            log_path,
+            timeout: Duration::from_secs(10 * 60),
+            max_output_bytes: 8 * 1024,
+            size: None,
+            cancel: None,
+        }
+    }
+}
+
 /// Run a program inside a PTY with safety guardrails.
 ///
 /// * `program` – executable to run (must pass `enforce_command_safety`).
 /// * `args` – arguments passed to the program.
 /// * `workdir` – directory in which the command is executed.
-/// * `_env` – currently unused; kept for future extension.
-/// * `log_path` – path to a file where all PTY output is appended.
-/// * `timeout` – maximum wall‑clock time the command may run.
-/// * `max_output_bytes` – maximum number of bytes retained in `last_output`.
+/// * `opts` – environment, logging, limits and cancellation (see `PtyOptions`).
 pub fn run_with_pty(
     program: &str,
     args: &[String],
     workdir: &Path,
-    _env: &[(String, String)],
-    log_path: &Path,
-    timeout: Duration,
-    max_output_bytes: usize,
+    opts: &PtyOptions<'_>,
 ) -> Result<PtyRunResult> {
     // -------------------------------------------------------------------------
     // Guardrails: deny destructive commands and allowlist safe ones
@@ -50,14 +118,13 @@ pub fn run_with_pty(
     enforce_command_safety(program, args)?;
 
     let pty_system = native_pty_system();
-    let pair = pty_system
-        .openpty(PtySize {
-            rows: 30,
-            cols: 120,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
-        .context("openpty failed")?;
+    let size = opts.size.unwrap_or_else(|| {
+        get_current_terminal_size()
+            .ok()
+            .filter(|s| s.rows > 0 && s.cols > 0)
+            .unwrap_or_else(default_pty_size)
+    });
+    let pair = pty_system.openpty(size).context("openpty failed")?;
 
     // -------------------------------------------------------------------------
     // Set up SIGWINCH handling to resize the PTY when the terminal changes size
@@ -70,32 +137,25 @@ pub fn run_with_pty(
     }
 
     // -------------------------------------------------------------------------
-    // Build a safe shell command: cd into workdir, then run program with args
+    // Build the platform shell command that runs program with args in workdir
     // -------------------------------------------------------------------------
-    let mut cmd_line = String::new();
-    cmd_line.push_str("cd ");
-    cmd_line.push_str(&shell_quote_path(workdir));
-    cmd_line.push_str(" && ");
-    cmd_line.push_str(&shell_quote(program));
-    for a in args {
-        cmd_line.push(' ');
-        cmd_line.push_str(&shell_quote(a));
+    let mut cmd = shell_command(program, args, workdir);
+    for (key, value) in config::env_overrides().iter().chain(opts.env) {
+        cmd.env(key, value);
     }
 
-    // Spawn a shell that runs our command
-    let mut cmd = CommandBuilder::new("/bin/bash");
-    cmd.arg("-lc");
-    cmd.arg(cmd_line);
-
     // Attach the slave end to the command and spawn
     let mut child = pair
         .slave
         .spawn_command(cmd)
         .context("spawn_command failed")?;
+    // Close our copy of the slave so reads hit EOF once the child exits.
+    drop(pair.slave);
 
     // -------------------------------------------------------------------------
     // Logging: open per‑task log file (caller supplies the correct path)
     // -------------------------------------------------------------------------
+    let log_path = opts.log_path;
     let mut log_file = OpenOptions::new()
         .create(true)
         .append(true)
@@ -103,55 +163,82 @@ pub fn run_with_pty(
         .with_context(|| format!("open log file {}", log_path.display()))?;
 
     // -------------------------------------------------------------------------
-    // Drain output until the child exits or we hit the timeout.
+    // Drain output until the child exits, times out or is cancelled. Reads
+    // block, so they happen on a helper thread and a quiet child can still be
+    // timed out or cancelled.
     // -------------------------------------------------------------------------
     let mut reader = pair
         .master
         .try_clone_reader()
         .context("failed to clone pty reader")?;
+    let (chunks_tx, chunks) = mpsc::channel::<std::io::Result<Vec<u8>>>();
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if chunks_tx.send(Ok(buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = chunks_tx.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
 
-    let mut buf = [0u8; 8192];
     let mut last_output = String::new();
     let mut error: Option<String> = None;
     let start = Instant::now();
     let mut timed_out = false;
+    let mut cancelled = false;
     let mut exit_status: Option<ExitStatus> = None;
 
+    let max_output_bytes = opts.max_output_bytes;
+    let mut record_chunk = |bytes: &[u8], error: &mut Option<String>| {
+        let chunk = String::from_utf8_lossy(bytes);
+        // Echo to the current stdout (live PTY)
+        if ECHO_OUTPUT.load(Ordering::Relaxed) {
+            print!("{}", chunk);
+        }
+        // Also tee to the log file
+        if let Err(e) = log_file.write_all(chunk.as_bytes()) {
+            *error = Some(format!("Failed to write to log file: {}", e));
+        }
+
+        // Append to the tail buffer respecting the size limit.
+        last_output.push_str(&chunk);
+        if last_output.len() > max_output_bytes {
+            let cut = last_output.len() - max_output_bytes;
+            last_output.drain(..cut);
+        }
+    };
+
     loop {
-        // Check for a pending resize request before blocking on read.
+        // Check for a pending resize request before waiting for output.
         if resize_requested.swap(false, Ordering::SeqCst) {
             if let Ok(new_size) = get_current_terminal_size() {
                 let _ = pair.master.resize(new_size);
             }
         }
 
-        // Non‑blocking read with a short timeout to allow us to check the child.
-        match reader.read(&mut buf) {
-            Ok(0) => {
-                // EOF – child likely exited.
-                // We'll still check the child's status below.
-            }
-            Ok(n) => {
-                let chunk = String::from_utf8_lossy(&buf[..n]);
-                // Echo to the current stdout (live PTY)
-                print!("{}", chunk);
-                // Also tee to the log file
-                if let Err(e) = log_file.write_all(chunk.as_bytes()) {
-                    error = Some(format!("Failed to write to log file: {}", e));
+        // Wait briefly for output so the child, timeout and cancel flag are
+        // still checked when the command is quiet.
+        match chunks.recv_timeout(Duration::from_millis(50)) {
+            Ok(Ok(bytes)) => record_chunk(&bytes, &mut error),
+            Ok(Err(e)) => {
+                // Once the child has exited the PTY reports EIO; that is just EOF.
+                if !matches!(child.try_wait(), Ok(Some(_))) {
+                    error = Some(format!("PTY read error: {}", e));
                 }
-
-                // Append to the tail buffer respecting the size limit.
-                last_output.push_str(&chunk);
-                if last_output.len() > max_output_bytes {
-                    let cut = last_output.len() - max_output_bytes;
-                    last_output.drain(..cut);
-                }
-            }
-            Err(e) => {
-                // Capture read errors but continue; they usually indicate the PTY is closed.
-                error = Some(format!("PTY read error: {}", e));
                 break;
             }
+            Err(RecvTimeoutError::Timeout) => {}
+            // EOF – the child likely exited; its status is checked below.
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(Duration::from_millis(10)),
         }
 
         // Check if the child has exited.
@@ -161,11 +248,11 @@ pub fn run_with_pty(
                 break;
             }
             Ok(None) => {
-                // Still running – check timeout.
-                if start.elapsed() > timeout {
-                    // Timeout exceeded: attempt to kill the child.
+                // Still running – check the cancel flag and the timeout.
+                cancelled = opts.cancel.is_some_and(|c| c.load(Ordering::SeqCst));
+                timed_out = !cancelled && start.elapsed() > opts.timeout;
+                if cancelled || timed_out {
                     let _ = child.kill();
-                    timed_out = true;
                     // After killing, wait for the final status.
                     match child.wait() {
                         Ok(s) => exit_status = Some(s),
@@ -182,9 +269,11 @@ pub fn run_with_pty(
                 break;
             }
         }
+    }
 
-        // Small sleep to avoid a tight loop if no data is available.
-        thread::sleep(Duration::from_millis(10));
+    // Collect output still in flight when the child exited.
+    while let Ok(Ok(bytes)) = chunks.recv_timeout(Duration::from_millis(50)) {
+        record_chunk(&bytes, &mut error);
     }
 
     // Final resize check in case a SIGWINCH arrived just before exit.
@@ -199,16 +288,16 @@ pub fn run_with_pty(
         Some(s) => s,
         None => child.wait().unwrap_or_else(|e| {
             error = Some(format!("Final wait failed: {}", e));
-            // Construct a generic failure status; portable_pty's ExitStatus does not have a public ctor,
-            // but we can fallback to a zeroed status via Default if available.
-            ExitStatus::default()
+            // No real status is available; report a generic failure rather than success.
+            ExitStatus::with_exit_code(1)
         }),
     };
 
     Ok(PtyRunResult {
         raw_status,
-        last_output,
+        last_output_plain: strip_ansi(&last_output),
         timed_out,
+        cancelled,
         error,
     })
 }
@@ -251,15 +340,16 @@ fn enforce_command_safety(program: &str, args: &[String]) -> Result<()> {
         .and_then(|s| s.to_str())
         .unwrap_or(program);
 
-    if allowlist.iter().any(|&a| a == prog_name) {
+    if allowlist.contains(&prog_name) {
         return Ok(());
     }
 
     // If not explicitly allowed, require an explicit opt‑in via env var
-    match std::env::var("PTY_ALLOW_UNSAFE") {
+    match config::env_var("PTY_ALLOW_UNSAFE") {
         Ok(v) if v == "1" => Ok(()),
         _ => Err(anyhow!(
-            "Command '{}' is not in the allowlist. Set PTY_ALLOW_UNSAFE=1 to override."
+            "Command '{}' is not in the allowlist. Set PTY_ALLOW_UNSAFE=1 to override.",
+            program
         )),
     }
 }
@@ -284,17 +374,58 @@ fn get_current_terminal_size() -> Result<PtySize> {
     #[cfg(not(unix))]
     {
         // On non‑Unix platforms we fall back to a static size.
-        Ok(PtySize {
-            rows: 30,
-            cols: 120,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
+        Ok(default_pty_size())
+    }
+}
+
+fn default_pty_size() -> PtySize {
+    PtySize {
+        rows: 30,
+        cols: 120,
+        pixel_width: 0,
+        pixel_height: 0,
     }
 }
 
 // --- helpers ---
 
+/// Remove ANSI escape sequences (colors, cursor movement) from PTY output.
+pub fn strip_ansi(s: &str) -> String {
+    console::strip_ansi_codes(s).replace("\r\n", "\n")
+}
+
+/// Unix: `cd` into workdir, then run program with args under `bash -lc`.
+#[cfg(not(windows))]
+fn shell_command(program: &str, args: &[String], workdir: &Path) -> CommandBuilder {
+    let mut cmd_line = String::new();
+    cmd_line.push_str("cd ");
+    cmd_line.push_str(&shell_quote_path(workdir));
+    cmd_line.push_str(" && ");
+    cmd_line.push_str(&shell_quote(program));
+    for a in args {
+        cmd_line.push(' ');
+        cmd_line.push_str(&shell_quote(a));
+    }
+
+    let mut cmd = CommandBuilder::new("/bin/bash");
+    cmd.arg("-lc");
+    cmd.arg(cmd_line);
+    cmd
+}
+
+/// Windows: run program with args under `cmd /C` in workdir. Each argument is
+/// passed separately, so `CommandBuilder` applies Windows quoting rules.
+#[cfg(windows)]
+fn shell_command(program: &str, args: &[String], workdir: &Path) -> CommandBuilder {
+    let mut cmd = CommandBuilder::new("cmd");
+    cmd.arg("/C");
+    cmd.arg(program);
+    cmd.args(args);
+    cmd.cwd(workdir);
+    cmd
+}
+
+#[cfg(not(windows))]
 fn shell_quote(s: &str) -> String {
     if s.is_empty() {
         "''".to_string()
@@ -317,6 +448,102 @@ fn shell_quote(s: &str) -> String {
     }
 }
 
+#[cfg(not(windows))]
 fn shell_quote_path(p: &Path) -> String {
     shell_quote(&p.to_string_lossy())
-}
\ No newline at end of file
+}
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// Run `script` under `sh -c` in a scratch directory.
+    fn run_sh(script: &str, env: &[(String, String)]) -> PtyRunResult {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("sh.log");
+        let opts = PtyOptions {
+            env,
+            timeout: Duration::from_secs(30),
+            ..PtyOptions::new(&log)
+        };
+        let args = vec!["-c".to_string(), script.to_string()];
+        run_with_pty("sh", &args, dir.path(), &opts).unwrap()
+    }
+
+    #[test]
+    fn colors_are_stripped_from_the_output_tail() {
+        let script = r"printf '\033[1;31mred\033[0m and \033[32mgreen\033[0m\n'";
+        let result = run_sh(script, &[]);
+        let tail = &result.last_output_plain;
+        assert!(tail.contains("red and green\n"), "{tail:?}");
+        assert!(!tail.contains('\x1b') && !tail.contains('\r'), "{tail:?}");
+    }
+
+    #[test]
+    fn extra_variables_reach_the_command() {
+        let env = [("GREETING".to_string(), "hi".to_string())];
+        let result = run_sh("echo \"greeting=$GREETING\"", &env);
+        assert!(result.success(), "{}", result.last_output_plain);
+        assert!(
+            result.last_output_plain.contains("greeting=hi"),
+            "{:?}",
+            result.last_output_plain
+        );
+    }
+
+    #[test]
+    fn exit_codes_are_reported() {
+        let ok = run_sh("exit 0", &[]);
+        assert!(ok.success());
+        assert_eq!(ok.exit_code(), Some(0));
+
+        let failed = run_sh("exit 3", &[]);
+        assert!(!failed.success());
+        assert_eq!(failed.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn requested_size_is_reported_to_the_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("stty.log");
+        let opts = PtyOptions {
+            size: Some(PtySize {
+                rows: 17,
+                cols: 93,
+                pixel_width: 0,
+                pixel_height: 0,
+            }),
+            timeout: Duration::from_secs(30),
+            ..PtyOptions::new(&log)
+        };
+        let args = vec!["-c".to_string(), "stty size".to_string()];
+        let result = run_with_pty("bash", &args, dir.path(), &opts).unwrap();
+        assert!(result.success(), "{}", result.last_output_plain);
+        assert!(
+            result.last_output_plain.contains("17 93"),
+            "{:?}",
+            result.last_output_plain
+        );
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use super::*;
+
+    #[test]
+    fn commands_run_through_cmd() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("echo.log");
+        let opts = PtyOptions {
+            timeout: Duration::from_secs(30),
+            ..PtyOptions::new(&log)
+        };
+        let result = run_with_pty("echo", &["hi".to_string()], dir.path(), &opts).unwrap();
+        assert!(result.success(), "{}", result.last_output_plain);
+        assert!(
+            result.last_output_plain.contains("hi"),
+            "{:?}",
+            result.last_output_plain
+        );
+    }
+}
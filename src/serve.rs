@@ -0,0 +1,183 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{capabilities, config, fsutil, llm, planner, transcript};
+
+/// JSON-RPC 2.0 error codes used by `--serve`.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// The method ran but failed (LLM error, denied path, ...).
+const APPLICATION_ERROR: i64 = -32000;
+
+/// Serve JSON-RPC 2.0 over stdio for editor integrations. Each line on stdin
+/// is one request object; each response is written to stdout as one line.
+/// Requests without an `id` are notifications and get no response.
+///
+/// Methods, relative to the working directory:
+/// - `capabilities` → the detected `Manifest`.
+/// - `plan {request}` → the `Plan` for `request`; nothing is applied.
+/// - `read {path}` → `{path, content}`.
+/// - `apply_edit {path, instruction, dry_run?}` → `{path, diff, applied}`:
+///   asks the model to edit `path`, then writes the result unless `dry_run`.
+pub async fn serve() -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle_line(&line).await {
+            stdout.write_all(format!("{response}\n").as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer one line of input, or `None` for a notification.
+pub async fn handle_line(line: &str) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+    };
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error(
+            id.unwrap_or(Value::Null),
+            INVALID_REQUEST,
+            "missing method",
+        ));
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let result = dispatch(method, params).await;
+    let id = id?;
+    Some(match result {
+        Ok(value) => json!({ "jsonrpc": "2.0", "id": id, "result": value }),
+        Err((code, message)) => error(id, code, &message),
+    })
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[derive(Deserialize)]
+struct PlanParams {
+    request: String,
+}
+
+#[derive(Deserialize)]
+struct ReadParams {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct ApplyEditParams {
+    path: String,
+    instruction: String,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, (i64, String)> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+async fn dispatch(method: &str, raw: Value) -> Result<Value, (i64, String)> {
+    let app = |e: anyhow::Error| (APPLICATION_ERROR, format!("{e:#}"));
+    let root = std::env::current_dir().map_err(|e| app(e.into()))?;
+    match method {
+        "capabilities" => {
+            let manifest = capabilities::build_manifest(&root);
+            Ok(capabilities::to_json(&manifest))
+        }
+        "plan" => {
+            let p: PlanParams = params(raw)?;
+            let manifest = capabilities::build_manifest(&root);
+            let plan = planner::plan_changes(&root, &p.request, &manifest)
+                .await
+                .map_err(app)?;
+            serde_json::to_value(plan).map_err(|e| app(e.into()))
+        }
+        "read" => {
+            let p: ReadParams = params(raw)?;
+            if !fsutil::in_scope(&p.path) {
+                return Err(app(anyhow!("`{}` is outside the scope", p.path)));
+            }
+            let abs = fsutil::resolve_within(&root, &p.path).map_err(app)?;
+            if fsutil::looks_binary(&abs) {
+                return Err(app(anyhow!("`{}` is a binary file", p.path)));
+            }
+            let content = fsutil::read_to_string(&abs).map_err(app)?;
+            Ok(json!({ "path": p.path, "content": content }))
+        }
+        "apply_edit" => {
+            let p: ApplyEditParams = params(raw)?;
+            apply_edit(&root, p).await.map_err(app)
+        }
+        other => Err((METHOD_NOT_FOUND, format!("unknown method `{other}`"))),
+    }
+}
+
+async fn apply_edit(root: &std::path::Path, p: ApplyEditParams) -> Result<Value> {
+    let config = config::Config::load(root);
+    llm::set_edit_sampling(config.edit.temperature, config.edit.top_p);
+    llm::set_edit_chunk_lines(config.edit.chunk_lines);
+    if !fsutil::in_scope(&p.path) {
+        anyhow::bail!("`{}` is outside the scope", p.path);
+    }
+    if let Some(why) = config.edit.denial(&p.path) {
+        anyhow::bail!("`{}` {why}", p.path);
+    }
+    let abs = fsutil::resolve_within(root, &p.path)?;
+
+    let _lock = fsutil::lock_path(&abs).await;
+    let old = std::fs::read_to_string(&abs).unwrap_or_default();
+    let new = llm::propose_edit(llm::EditReq {
+        file_path: p.path.clone(),
+        file_content: old.clone(),
+        instruction: p.instruction,
+        ..Default::default()
+    })
+    .await?;
+    if new.trim().is_empty() && !old.trim().is_empty() {
+        anyhow::bail!("the model returned no content for {}", p.path);
+    }
+    let diff = transcript::unified_diff(&p.path, &old, &new);
+    let apply = !p.dry_run && !fsutil::is_read_only();
+    if apply {
+        fsutil::snapshot_for_session(root, &p.path);
+        fsutil::backup_before_write(root, &p.path)?;
+        fsutil::atomic_write(&abs, &new)?;
+    }
+    Ok(json!({ "path": p.path, "diff": diff, "applied": apply }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn capabilities_requests_return_the_manifest() {
+        // Tool overrides, which change the manifest, are set under this lock.
+        let _lock = crate::llm::mock::exclusive().await;
+        let line = r#"{"jsonrpc":"2.0","id":7,"method":"capabilities"}"#;
+
+        let response = handle_line(line).await.unwrap();
+
+        let root = std::env::current_dir().unwrap();
+        let manifest = capabilities::to_json(&capabilities::build_manifest(&root));
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "id": 7, "result": manifest })
+        );
+    }
+}
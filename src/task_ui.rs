@@ -3,18 +3,24 @@ use crossterm::{
     cursor,
     event::{self, Event, KeyCode},
     queue,
-    style,
-    terminal::{Clear, ClearType, disable_raw_mode, enable_raw_mode},
+    style::{self, Stylize},
+    terminal::{self, Clear, ClearType, disable_raw_mode, enable_raw_mode},
 };
 use std::io::{stdout, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::time::Duration;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TaskStatus {
     Pending,
     Running,
     Paused,
     Cancelled,
+    Succeeded,
+    Failed,
 }
 
 #[derive(Clone)]
@@ -24,53 +30,170 @@ pub struct TaskItem {
     pub detail: String,
     pub status: TaskStatus,
     pub expanded: bool,
+    /// Why the task failed, shown in the expanded detail.
+    pub error: Option<String>,
+    /// Set by `c` to stop whoever runs the task, e.g. the worker passed the
+    /// same flag.
+    pub cancel: Arc<AtomicBool>,
 }
 
-pub fn task_dashboard(tasks: &mut [TaskItem]) -> Result<()> {
+impl TaskItem {
+    /// Whether the task has finished, so cancelling it has no effect.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            TaskStatus::Succeeded | TaskStatus::Failed | TaskStatus::Cancelled
+        )
+    }
+
+    /// Ask the task's runner to stop it; finished tasks are left alone.
+    pub fn request_cancel(&mut self) {
+        if !self.is_finished() {
+            self.cancel.store(true, Ordering::SeqCst);
+            self.status = TaskStatus::Cancelled;
+        }
+    }
+}
+
+/// A status change for the task at an index, with the error for a failure.
+pub type TaskUpdate = (usize, TaskStatus, Option<String>);
+
+/// Rows below the task list: a blank line and the key help/position line.
+const FOOTER_ROWS: usize = 2;
+
+/// The slice of `total` items to show in `height` rows so that `selected` is
+/// visible, scrolling as little as possible from the previous window start
+/// `offset`.
+pub fn visible_window(total: usize, height: usize, selected: usize, offset: usize) -> Range<usize> {
+    let height = height.max(1);
+    let mut start = offset.min(total.saturating_sub(height));
+    if selected < start {
+        start = selected;
+    } else if selected >= start + height {
+        start = selected + 1 - height;
+    }
+    start..(start + height).min(total)
+}
+
+/// Symbol and label for `status`, colored so states stand apart at a glance.
+fn status_label(status: TaskStatus) -> style::StyledContent<&'static str> {
+    match status {
+        TaskStatus::Pending => "· pending".dim(),
+        TaskStatus::Running => "▶ running".cyan(),
+        TaskStatus::Paused => "‖ paused".yellow(),
+        TaskStatus::Cancelled => "■ cancelled".dark_grey(),
+        TaskStatus::Succeeded => "✔ succeeded".green(),
+        TaskStatus::Failed => "✘ failed".red(),
+    }
+}
+
+/// Most lines of a failure's error shown in the expanded detail; the tail is
+/// kept since that is where the cause usually is.
+const ERROR_DETAIL_LINES: usize = 10;
+
+/// Lines shown under an expanded task: its detail, then the error when it
+/// failed.
+pub fn render_detail(task: &TaskItem) -> Vec<String> {
+    let mut lines: Vec<String> = task.detail.lines().map(str::to_string).collect();
+    if let (TaskStatus::Failed, Some(error)) = (task.status, &task.error) {
+        let error_lines: Vec<&str> = error.trim_end().lines().collect();
+        let tail = &error_lines[error_lines.len().saturating_sub(ERROR_DETAIL_LINES)..];
+        lines.push("error:".to_string());
+        lines.extend(tail.iter().map(|l| format!("  {l}")));
+    }
+    lines
+}
+
+/// Apply every update waiting on `updates`. Returns whether anything changed.
+pub fn apply_updates(tasks: &mut [TaskItem], updates: &Receiver<TaskUpdate>) -> bool {
+    let mut changed = false;
+    while let Ok((idx, status, error)) = updates.try_recv() {
+        if let Some(task) = tasks.get_mut(idx) {
+            task.status = status;
+            task.error = error;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Show `tasks` until the user quits, rendering status updates pushed on
+/// `updates` (e.g. by `WorkerAgent::run_with_updates`) as they arrive.
+pub fn task_dashboard(tasks: &mut [TaskItem], updates: &Receiver<TaskUpdate>) -> Result<()> {
     enable_raw_mode()?;
     let mut out = stdout();
     let mut selected: usize = 0;
+    let mut offset: usize = 0;
+    let mut drawn_rows;
 
     loop {
+        apply_updates(tasks, updates);
+        let (_, term_rows) = terminal::size().unwrap_or((80, 24));
+        let detail: Vec<String> = match tasks.get(selected) {
+            Some(task) if task.expanded => render_detail(task),
+            _ => vec![],
+        };
+        let list_rows = (term_rows as usize)
+            .saturating_sub(FOOTER_ROWS + detail.len())
+            .max(1);
+        let window = visible_window(tasks.len(), list_rows, selected, offset);
+        offset = window.start;
+
         queue!(out, cursor::MoveTo(0, 0), Clear(ClearType::All))?;
-        for (idx, task) in tasks.iter().enumerate() {
+        let mut row: u16 = 0;
+        for idx in window {
+            let task = &tasks[idx];
             let prefix = if idx == selected { ">" } else { " " };
-            let status = match task.status {
-                TaskStatus::Pending => "pending",
-                TaskStatus::Running => "running",
-                TaskStatus::Paused => "paused",
-                TaskStatus::Cancelled => "cancelled",
-            };
             queue!(
                 out,
-                style::Print(format!("{prefix} [{status}] {}\n", task.summary))
+                cursor::MoveTo(0, row),
+                style::Print(format!("{prefix} [")),
+                style::PrintStyledContent(status_label(task.status)),
+                style::Print(format!("] {}", task.summary))
             )?;
-            if task.expanded && idx == selected {
-                queue!(out, style::Print(format!("    {}\n", task.detail)))?;
+            row += 1;
+            if idx == selected {
+                for line in &detail {
+                    queue!(
+                        out,
+                        cursor::MoveTo(0, row),
+                        style::Print(format!("    {line}"))
+                    )?;
+                    row += 1;
+                }
             }
         }
-        queue!(out, style::Print("\nq: quit  Enter: expand  p: pause/resume  c: cancel"))?;
+        queue!(
+            out,
+            cursor::MoveTo(0, row + 1),
+            style::Print(format!(
+                "[{}/{}]  q: quit  Enter: expand  p: pause/resume  c: cancel  PgUp/PgDn: scroll",
+                (selected + 1).min(tasks.len()),
+                tasks.len()
+            ))
+        )?;
+        drawn_rows = row as usize + FOOTER_ROWS;
         out.flush()?;
 
         if event::poll(Duration::from_millis(250))? {
-            match event::read()? {
-                Event::Key(key) => match key.code {
+            if let Event::Key(key) = event::read()? {
+                match key.code {
                     KeyCode::Char('q') => break,
-                    KeyCode::Up => {
-                        if selected > 0 {
-                            selected -= 1;
-                        }
+                    KeyCode::Up => selected = selected.saturating_sub(1),
+                    KeyCode::Down if selected + 1 < tasks.len() => selected += 1,
+                    KeyCode::PageUp => {
+                        selected = selected.saturating_sub(list_rows);
                     }
-                    KeyCode::Down => {
-                        if selected + 1 < tasks.len() {
-                            selected += 1;
-                        }
+                    KeyCode::PageDown => {
+                        selected = (selected + list_rows).min(tasks.len().saturating_sub(1));
                     }
                     KeyCode::Enter => {
                         tasks[selected].expanded = !tasks[selected].expanded;
                     }
                     KeyCode::Char('c') => {
-                        tasks[selected].status = TaskStatus::Cancelled;
+                        if let Some(task) = tasks.get_mut(selected) {
+                            task.request_cancel();
+                        }
                     }
                     KeyCode::Char('p') => {
                         tasks[selected].status = match tasks[selected].status {
@@ -80,15 +203,93 @@ pub fn task_dashboard(tasks: &mut [TaskItem]) -> Result<()> {
                         };
                     }
                     _ => {}
-                },
-                _ => {}
+                }
             }
         }
     }
     disable_raw_mode()?;
     // Move to next line to avoid overwriting prompt
-    queue!(out, cursor::MoveTo(0, (tasks.len() + 3) as u16), Clear(ClearType::CurrentLine))?;
+    queue!(out, cursor::MoveTo(0, drawn_rows as u16), Clear(ClearType::CurrentLine))?;
     out.flush()?;
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(status: TaskStatus) -> TaskItem {
+        TaskItem {
+            id: 0,
+            summary: "sleep 30".into(),
+            detail: String::new(),
+            status,
+            expanded: false,
+            error: None,
+            cancel: Default::default(),
+        }
+    }
+
+    #[test]
+    fn the_window_scrolls_just_enough_to_show_the_selection() {
+        assert_eq!(visible_window(50, 10, 30, 0), 21..31);
+        assert_eq!(visible_window(50, 10, 30, 25), 25..35);
+        assert_eq!(visible_window(50, 10, 3, 25), 3..13);
+        assert_eq!(visible_window(50, 10, 49, 45), 40..50);
+        assert_eq!(visible_window(4, 10, 2, 0), 0..4);
+    }
+
+    #[test]
+    fn updates_from_the_channel_change_the_statuses() {
+        let mut tasks = vec![item(TaskStatus::Pending), item(TaskStatus::Pending)];
+        let (tx, rx) = std::sync::mpsc::channel();
+        assert!(!apply_updates(&mut tasks, &rx));
+
+        let script = [
+            (0, TaskStatus::Running, None),
+            (1, TaskStatus::Running, None),
+            (0, TaskStatus::Succeeded, None),
+            (1, TaskStatus::Failed, Some("exit 1".to_string())),
+            (7, TaskStatus::Running, None),
+        ];
+        for update in script {
+            tx.send(update).unwrap();
+        }
+
+        assert!(apply_updates(&mut tasks, &rx));
+        assert_eq!(tasks[0].status, TaskStatus::Succeeded);
+        assert_eq!(tasks[1].status, TaskStatus::Failed);
+        assert_eq!(tasks[1].error.as_deref(), Some("exit 1"));
+    }
+
+    #[test]
+    fn failed_items_show_their_error_under_the_detail() {
+        let mut task = item(TaskStatus::Failed);
+        task.detail = "cargo test".into();
+        task.error = Some("error[E0308]: mismatched types\n".into());
+
+        assert_eq!(
+            render_detail(&task),
+            ["cargo test", "error:", "  error[E0308]: mismatched types"]
+        );
+
+        task.status = TaskStatus::Succeeded;
+        assert_eq!(render_detail(&task), ["cargo test"]);
+    }
+
+    #[test]
+    fn cancel_sets_the_flag_of_a_running_task() {
+        let mut task = item(TaskStatus::Running);
+        task.request_cancel();
+        assert_eq!(task.status, TaskStatus::Cancelled);
+        assert!(task.cancel.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn finished_tasks_ignore_cancel() {
+        let mut task = item(TaskStatus::Succeeded);
+        task.request_cancel();
+        assert_eq!(task.status, TaskStatus::Succeeded);
+        assert!(!task.cancel.load(Ordering::SeqCst));
+    }
+}
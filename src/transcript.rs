@@ -0,0 +1,161 @@
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::fsutil;
+use crate::planner::Plan;
+
+/// One request and everything done for it, as written to the transcript.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Turn {
+    /// RFC 3339 time the request was received.
+    pub ts: String,
+    pub request: String,
+    /// The plan that was executed; absent if planning failed.
+    #[serde(default)]
+    pub plan: Option<Plan>,
+    #[serde(default)]
+    pub changes: Vec<FileChange>,
+    #[serde(default)]
+    pub actions: Vec<ActionRecord>,
+    #[serde(default)]
+    pub skipped: Vec<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Turn {
+    pub fn new(request: &str) -> Self {
+        Self {
+            ts: chrono::Utc::now().to_rfc3339(),
+            request: request.to_string(),
+            ..Self::default()
+        }
+    }
+}
+
+/// A file-system change applied during a turn.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FileChange {
+    Edit { path: String, diff: String },
+    Create { path: String, diff: String },
+    Delete { path: String },
+    Move { from: String, to: String },
+}
+
+/// A planned command and, once it has run, its output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionRecord {
+    pub command: String,
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+static TRANSCRIPT_PATH: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+
+/// Append every subsequent turn to `path` as one JSON line.
+pub fn set_path(path: Option<PathBuf>) {
+    *TRANSCRIPT_PATH.lock().unwrap() = path;
+}
+
+/// Append `turn` to the transcript, if one is being written. Failures are
+/// reported but never abort the turn.
+pub fn record(turn: &Turn) {
+    let Some(path) = TRANSCRIPT_PATH.lock().unwrap().clone() else {
+        return;
+    };
+    if fsutil::is_read_only() {
+        return;
+    }
+    if let Err(e) = append(&path, turn) {
+        eprintln!(
+            "warning: could not write transcript {}: {e:#}",
+            path.display()
+        );
+    }
+}
+
+fn append(path: &Path, turn: &Turn) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(turn)?)?;
+    Ok(())
+}
+
+/// Read back every turn from a transcript written by `record`.
+pub fn load(path: &Path) -> Result<Vec<Turn>> {
+    let data = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{}:{}: invalid transcript record", path.display(), i + 1))
+        })
+        .collect()
+}
+
+/// Plain unified diff of `old` → `new`, for the transcript.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(&format!("a/{path}"), &format!("b/{path}"))
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock;
+
+    #[tokio::test]
+    async fn each_turn_is_one_record_with_every_field() {
+        let _lock = mock::exclusive().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.jsonl");
+        let turn = Turn {
+            plan: Some(Plan::default()),
+            changes: vec![FileChange::Edit {
+                path: "a.txt".into(),
+                diff: unified_diff("a.txt", "old\n", "new\n"),
+            }],
+            actions: vec![ActionRecord {
+                command: "cargo test".into(),
+                output: Some("ok".into()),
+            }],
+            skipped: vec!["delete `b.txt`: not found".into()],
+            error: Some("action `cargo` failed".into()),
+            ..Turn::new("update a")
+        };
+
+        set_path(Some(path.clone()));
+        record(&turn);
+        set_path(None);
+
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(data.lines().count(), 1);
+        let record: serde_json::Value = serde_json::from_str(&data).unwrap();
+        for field in [
+            "ts", "request", "plan", "changes", "actions", "skipped", "error",
+        ] {
+            assert!(!record[field].is_null(), "missing {field}: {record}");
+        }
+        assert_eq!(record["changes"][0]["kind"], "edit");
+        assert!(record["changes"][0]["diff"]
+            .as_str()
+            .unwrap()
+            .contains("+new"));
+        assert_eq!(load(&path).unwrap()[0].request, "update a");
+    }
+}
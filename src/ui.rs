@@ -1,4 +1,5 @@
 use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
@@ -7,112 +8,537 @@ use crossterm::{
     terminal::{Clear, ClearType, disable_raw_mode, enable_raw_mode},
 };
 
+/// Most recent submissions kept in `.shellcraft/history`.
+const HISTORY_LIMIT: usize = 1000;
+
+fn history_path() -> PathBuf {
+    PathBuf::from(".shellcraft").join("history")
+}
+
+/// Previously submitted messages, oldest first. Each line of the history file
+/// holds one JSON string so multi-line messages survive the round trip.
+fn load_history() -> Vec<String> {
+    let data = std::fs::read_to_string(history_path()).unwrap_or_default();
+    let mut entries: Vec<String> = data
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    let excess = entries.len().saturating_sub(HISTORY_LIMIT);
+    entries.drain(..excess);
+    entries
+}
+
+fn append_history(entry: &str) -> std::io::Result<()> {
+    let path = history_path();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)
+}
+
+/// What a key press did to the message being typed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputStep {
+    Continue,
+    Submit,
+    /// Tab matched several completions; show them under the prompt.
+    Candidates(Vec<String>),
+}
+
+/// Slash commands offered by Tab completion.
+pub const SLASH_COMMANDS: &[&str] = &[
+    "/ab",
+    "/apply",
+    "/ask",
+    "/budget",
+    "/capabilities",
+    "/clear-cache",
+    "/diff",
+    "/diff-session",
+    "/env",
+    "/exec",
+    "/exit",
+    "/fix",
+    "/help",
+    "/ignore",
+    "/model",
+    "/plan",
+    "/quit",
+    "/replan",
+    "/replay",
+    "/scope",
+    "/timeline",
+    "/tool",
+    "/undo",
+    "/validate-config",
+];
+
+/// Result of completing the last word of the input.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Completion {
+    /// The input with its last word extended as far as all candidates agree.
+    pub line: String,
+    /// Every match, for display when there is more than one.
+    pub candidates: Vec<String>,
+}
+
+/// Complete a slash command when `buf` is a lone `/word`, otherwise the last
+/// word as a path under `cwd` when it follows a command or contains a `/`.
+/// `None` means Tab has nothing to complete here.
+pub fn complete(buf: &str, cwd: &Path) -> Option<Completion> {
+    let start = buf.rfind(char::is_whitespace).map_or(0, |i| i + 1);
+    let (head, word) = buf.split_at(start);
+
+    let candidates: Vec<String> = if head.is_empty() && word.starts_with('/') {
+        SLASH_COMMANDS
+            .iter()
+            .filter(|c| c.starts_with(word))
+            .map(|c| c.to_string())
+            .collect()
+    } else if (buf.starts_with('/') && !head.is_empty()) || word.contains('/') {
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => word.split_at(i + 1),
+            None => ("", word),
+        };
+        let mut found: Vec<String> = std::fs::read_dir(cwd.join(dir))
+            .ok()?
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let hidden = name.starts_with('.') && !prefix.starts_with('.');
+                if hidden || !name.starts_with(prefix) {
+                    return None;
+                }
+                let slash = if e.path().is_dir() { "/" } else { "" };
+                Some(format!("{dir}{name}{slash}"))
+            })
+            .collect();
+        found.sort();
+        found
+    } else {
+        return None;
+    };
+
+    let line = match candidates.as_slice() {
+        [] => buf.to_string(),
+        [only] if only.starts_with('/') && head.is_empty() => format!("{only} "),
+        [only] => format!("{head}{only}"),
+        [first, rest @ ..] => {
+            let mut common = first.len();
+            for c in rest {
+                let shared = first.bytes().zip(c.bytes()).take_while(|(a, b)| a == b);
+                common = common.min(shared.count());
+            }
+            while !first.is_char_boundary(common) {
+                common -= 1;
+            }
+            format!("{head}{}", &first[..common])
+        }
+    };
+    Some(Completion { line, candidates })
+}
+
+/// Edit buffer for one message, with a cursor and Up/Down recall of earlier
+/// submissions.
+#[derive(Debug, Default)]
+pub struct LineEditor {
+    pub buf: String,
+    /// Byte offset of the cursor in `buf`, always on a char boundary.
+    pub cursor: usize,
+    history: Vec<String>,
+    /// Index into `history` while browsing it; `None` on the line being typed.
+    recall: Option<usize>,
+    /// The in-progress line, restored when Down moves past the newest entry.
+    draft: String,
+}
+
+impl LineEditor {
+    pub fn new(history: Vec<String>) -> Self {
+        Self {
+            history,
+            ..Default::default()
+        }
+    }
+
+    /// Replace the whole buffer, leaving the cursor at its end.
+    fn set(&mut self, text: String) {
+        self.buf = text;
+        self.cursor = self.buf.len();
+    }
+
+    /// Insert `text` at the cursor and move past it.
+    pub fn insert(&mut self, text: &str) {
+        self.buf.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// Delete the character before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.buf[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.buf.remove(self.cursor);
+        }
+    }
+
+    /// Delete the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.buf.len() {
+            self.buf.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        if let Some(c) = self.buf[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    pub fn right(&mut self) {
+        if let Some(c) = self.buf[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    /// Move to the start of the cursor's line.
+    pub fn home(&mut self) {
+        self.cursor = self.buf[..self.cursor].rfind('\n').map_or(0, |i| i + 1);
+    }
+
+    /// Move to the end of the cursor's line.
+    pub fn end(&mut self) {
+        let rest = &self.buf[self.cursor..];
+        self.cursor += rest.find('\n').unwrap_or(rest.len());
+    }
+
+    /// Apply one terminal event to the buffer.
+    pub fn handle(&mut self, ev: Event) -> InputStep {
+        match ev {
+            Event::Key(KeyEvent { code: KeyCode::Enter, modifiers, .. }) => {
+                if modifiers.contains(KeyModifiers::SHIFT) || modifiers.contains(KeyModifiers::CONTROL) {
+                    self.insert("\n");
+                } else {
+                    return InputStep::Submit; // plain Enter submits
+                }
+            }
+            Event::Key(KeyEvent { code: KeyCode::Char(c), modifiers, .. }) => {
+                if modifiers.contains(KeyModifiers::CONTROL) {
+                    match c {
+                        'u' | 'U' => self.set(String::new()), // Ctrl+U: clear
+                        'w' | 'W' => {                        // Ctrl+W: delete word before the cursor
+                            let trimmed = self.buf[..self.cursor].trim_end_matches(|ch: char| ch.is_whitespace());
+                            let cut = trimmed.rfind(|ch: char| ch.is_whitespace()).map(|i| i + 1).unwrap_or(0);
+                            self.buf.replace_range(cut..self.cursor, "");
+                            self.cursor = cut;
+                        }
+                        'a' | 'A' => self.home(),
+                        'e' | 'E' => self.end(),
+                        _ => {}
+                    }
+                } else {
+                    self.insert(c.encode_utf8(&mut [0; 4]));
+                }
+            }
+            Event::Key(KeyEvent { code: KeyCode::Backspace, .. }) => self.backspace(),
+            Event::Key(KeyEvent { code: KeyCode::Delete, .. }) => self.delete(),
+            Event::Key(KeyEvent { code: KeyCode::Left, .. }) => self.left(),
+            Event::Key(KeyEvent { code: KeyCode::Right, .. }) => self.right(),
+            Event::Key(KeyEvent { code: KeyCode::Home, .. }) => self.home(),
+            Event::Key(KeyEvent { code: KeyCode::End, .. }) => self.end(),
+            Event::Key(KeyEvent { code: KeyCode::Tab, .. }) => {
+                let cwd = std::env::current_dir().unwrap_or_default();
+                match complete(&self.buf[..self.cursor], &cwd) {
+                    Some(done) => {
+                        let tail = self.buf.split_off(self.cursor);
+                        self.set(done.line);
+                        self.buf.push_str(&tail);
+                        if done.candidates.len() > 1 {
+                            return InputStep::Candidates(done.candidates);
+                        }
+                    }
+                    None => self.insert("\t"),
+                }
+            }
+            Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => {
+                // ESC clears current line (keeps REPL)
+                self.set(String::new());
+            }
+            Event::Key(KeyEvent { code: KeyCode::Up, .. }) => {
+                let prev = match self.recall {
+                    None if self.history.is_empty() => return InputStep::Continue,
+                    None => {
+                        self.draft = std::mem::take(&mut self.buf);
+                        self.history.len() - 1
+                    }
+                    Some(i) => i.saturating_sub(1),
+                };
+                self.recall = Some(prev);
+                self.set(self.history[prev].clone());
+            }
+            Event::Key(KeyEvent { code: KeyCode::Down, .. }) => {
+                match self.recall {
+                    Some(i) if i + 1 < self.history.len() => {
+                        self.recall = Some(i + 1);
+                        self.set(self.history[i + 1].clone());
+                    }
+                    Some(_) => {
+                        self.recall = None;
+                        let draft = std::mem::take(&mut self.draft);
+                        self.set(draft);
+                    }
+                    None => {}
+                }
+            }
+            Event::Paste(s) => self.insert(&s),
+            _ => {}
+        }
+        InputStep::Continue
+    }
+}
+
 /// Read a single message with:
 /// - Enter submits
 /// - Shift+Enter inserts newline (best effort); Ctrl+Enter as portable fallback
 /// - Bracketed paste keeps multi-line content as-is
+/// - Left/Right/Home/End move the cursor; edits happen at the cursor
+/// - Up/Down recall earlier messages (kept in `.shellcraft/history`)
+/// - Tab completes slash commands and paths
 pub fn read_message_singleline(prompt: &str) -> anyhow::Result<String> {
     let mut out = stdout();
     enable_raw_mode()?;
     // Best effort: bracketed paste makes pastes arrive as Event::Paste(String)
     execute!(out, event::EnableBracketedPaste)?;
 
-    let mut buf = String::new();
-    render_prompt(&mut out, prompt, &buf)?;
+    let mut editor = LineEditor::new(load_history());
+    let mut screen = PromptScreen::default();
+    screen.draw(&mut out, prompt, &editor)?;
 
     loop {
         if event::poll(std::time::Duration::from_millis(250))? {
-            match event::read()? {
-                Event::Key(KeyEvent { code: KeyCode::Enter, modifiers, .. }) => {
-                    if modifiers.contains(KeyModifiers::SHIFT) || modifiers.contains(KeyModifiers::CONTROL) {
-                        buf.push('\n');
-                        render_prompt(&mut out, prompt, &buf)?;
-                        continue;
-                    }
-                    break; // plain Enter submits
-                }
-                Event::Key(KeyEvent { code: KeyCode::Char(c), modifiers, .. }) => {
-                    if modifiers.contains(KeyModifiers::CONTROL) {
-                        match c {
-                            'u' | 'U' => buf.clear(),        // Ctrl+U: clear
-                            'w' | 'W' => {                   // Ctrl+W: delete word
-                                let trimmed = buf.trim_end_matches(|ch: char| ch.is_whitespace());
-                                let cut = trimmed.rfind(|ch: char| ch.is_whitespace()).map(|i| i + 1).unwrap_or(0);
-                                buf.truncate(cut);
-                            }
-                            _ => {}
-                        }
-                    } else {
-                        buf.push(c);
-                    }
-                    render_prompt(&mut out, prompt, &buf)?;
-                }
-                Event::Key(KeyEvent { code: KeyCode::Backspace, .. }) => {
-                    buf.pop();
-                    render_prompt(&mut out, prompt, &buf)?;
-                }
-                Event::Key(KeyEvent { code: KeyCode::Tab, .. }) => {
-                    buf.push('\t');
-                    render_prompt(&mut out, prompt, &buf)?;
-                }
-                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => {
-                    // ESC clears current line (keeps REPL)
-                    buf.clear();
-                    render_prompt(&mut out, prompt, &buf)?;
+            match editor.handle(event::read()?) {
+                InputStep::Submit => break,
+                InputStep::Candidates(candidates) => {
+                    screen.leave(&mut out)?;
+                    queue!(out, style::Print(candidates.join("  ") + "\r\n"))?;
                 }
-                Event::Paste(s) => {
-                    buf.push_str(&s);
-                    render_prompt(&mut out, prompt, &buf)?;
-                }
-                Event::Resize(_, _) => {
-                    render_prompt(&mut out, prompt, &buf)?;
-                }
-                _ => {}
+                InputStep::Continue => {}
             }
+            screen.draw(&mut out, prompt, &editor)?;
         }
     }
 
-    queue!(out, style::Print("\r\n"))?;
+    screen.leave(&mut out)?;
     execute!(out, event::DisableBracketedPaste)?;
     disable_raw_mode()?;
+
+    let buf = editor.buf;
+    if !buf.trim().is_empty() && editor.history.last() != Some(&buf) {
+        // History is a convenience; failing to save it shouldn't lose the message.
+        let _ = append_history(&buf);
+    }
     Ok(buf)
 }
 
-fn render_prompt<W: Write>(out: &mut W, prompt: &str, buf: &str) -> anyhow::Result<()> {
-    queue!(
-        out,
-        cursor::MoveToColumn(0),
-        Clear(ClearType::CurrentLine),
-        style::Print(prompt),
-        style::Print(" "),
-        style::Print(buf)
-    )?;
-    out.flush()?;
-    Ok(())
+/// Where the last drawn prompt left the terminal cursor, so the next draw can
+/// clear every row the prompt (and any wrapped or multi-line input) occupies.
+#[derive(Default)]
+struct PromptScreen {
+    /// Rows between the prompt's first row and the cursor.
+    cursor_row: u16,
+    /// Rows between the cursor and the prompt's last row.
+    rows_below: u16,
 }
 
-pub fn handle_help() -> anyhow::Result<String> {
-    let help_message = "/help: this help message
-/quit: quit the application
-/exit: quit the application";
-    let mut out = stdout();
-    queue!(out, style::Print(help_message))?;
-    out.flush()?;
-    Ok(String::new())
+impl PromptScreen {
+    fn draw<W: Write>(
+        &mut self,
+        out: &mut W,
+        prompt: &str,
+        editor: &LineEditor,
+    ) -> anyhow::Result<()> {
+        let width = crossterm::terminal::size().map_or(80, |(w, _)| w).max(1);
+        let start = prompt.chars().count() as u16 + 1;
+        let (end_row, _) = text_position(start, &editor.buf, width);
+        let (row, col) = text_position(start, &editor.buf[..editor.cursor], width);
+
+        if self.cursor_row > 0 {
+            queue!(out, cursor::MoveUp(self.cursor_row))?;
+        }
+        queue!(
+            out,
+            cursor::MoveToColumn(0),
+            Clear(ClearType::FromCursorDown),
+            style::Print(prompt),
+            style::Print(" "),
+            style::Print(editor.buf.replace('\n', "\r\n"))
+        )?;
+        if end_row > row {
+            queue!(out, cursor::MoveUp(end_row - row))?;
+        }
+        queue!(out, cursor::MoveToColumn(col))?;
+        out.flush()?;
+        self.cursor_row = row;
+        self.rows_below = end_row - row;
+        Ok(())
+    }
+
+    /// Move below the prompt so output that follows starts on a fresh line.
+    fn leave<W: Write>(&mut self, out: &mut W) -> anyhow::Result<()> {
+        if self.rows_below > 0 {
+            queue!(out, cursor::MoveDown(self.rows_below))?;
+        }
+        queue!(out, style::Print("\r\n"))?;
+        out.flush()?;
+        *self = Self::default();
+        Ok(())
+    }
 }
 
-pub fn handle_quit() -> anyhow::Result<String> {
-    let quit_message = "Goodbye!";
-    let mut out = stdout();
-    queue!(out, style::Print(quit_message))?;
-    out.flush()?;
-    std::process::exit(0);
+/// Row and column reached after printing `text` from column `start` on a
+/// terminal `width` columns wide.
+fn text_position(start: u16, text: &str, width: u16) -> (u16, u16) {
+    let (mut row, mut col) = (0u16, start % width);
+    for c in text.chars() {
+        if c == '\n' {
+            row += 1;
+            col = 0;
+            continue;
+        }
+        col += 1;
+        if col >= width {
+            row += 1;
+            col = 0;
+        }
+    }
+    (row, col)
 }
 
-pub fn handle_exit() -> anyhow::Result<String> {
-    let exit_message = "Goodbye!";
-    let mut out = stdout();
-    queue!(out, style::Print(exit_message))?;
-    out.flush()?;
-    std::process::exit(0);
-}
\ No newline at end of file
+/// `--color` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorChoice {
+    /// Color only when stdout is a terminal and `NO_COLOR` is unset.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            other => anyhow::bail!("--color expects auto, always or never, got `{}`", other),
+        }
+    }
+}
+
+/// Decide once whether output is colored and apply it to every
+/// `console::style` call, on stdout and stderr alike.
+pub fn init_colors(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let enabled = colors_enabled(choice, no_color, console::Term::stdout().is_term());
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// Whether `choice` colors output, given whether `NO_COLOR` is set and
+/// stdout is a terminal.
+fn colors_enabled(choice: ColorChoice, no_color: bool, is_term: bool) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !no_color && is_term,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            editor.handle(key(KeyCode::Char(c)));
+        }
+    }
+
+    #[test]
+    fn up_recalls_earlier_submissions_and_down_restores_the_draft() {
+        let mut editor = LineEditor::new(vec!["first".into(), "second".into()]);
+        type_text(&mut editor, "draft");
+
+        editor.handle(key(KeyCode::Up));
+        assert_eq!(editor.buf, "second");
+        editor.handle(key(KeyCode::Up));
+        assert_eq!(editor.buf, "first");
+        assert_eq!(editor.cursor, editor.buf.len());
+
+        editor.handle(key(KeyCode::Down));
+        assert_eq!(editor.buf, "second");
+        editor.handle(key(KeyCode::Down));
+        assert_eq!(editor.buf, "draft");
+        assert_eq!(editor.handle(key(KeyCode::Enter)), InputStep::Submit);
+    }
+
+    #[test]
+    fn edits_happen_at_the_cursor() {
+        let mut editor = LineEditor::new(Vec::new());
+        editor.insert("fn main\nbody");
+        editor.home();
+        assert_eq!(editor.cursor, "fn main\n".len());
+        editor.left();
+        editor.left();
+        editor.insert("()");
+        assert_eq!(editor.buf, "fn mai()n\nbody");
+
+        editor.delete();
+        editor.backspace();
+        assert_eq!(editor.buf, "fn mai(\nbody");
+        editor.home();
+        editor.end();
+        assert_eq!(editor.cursor, "fn mai(".len());
+        editor.right();
+        editor.end();
+        assert_eq!(editor.cursor, editor.buf.len());
+    }
+
+    #[test]
+    fn tab_completes_commands_and_lists_ambiguous_matches() {
+        let cwd = Path::new(".");
+        let model = complete("/mo", cwd).unwrap();
+        assert_eq!(model.line, "/model ");
+        assert_eq!(model.candidates, ["/model"]);
+
+        let replan = complete("/rep", cwd).unwrap();
+        assert_eq!(replan.line, "/repla");
+        assert_eq!(replan.candidates, ["/replan", "/replay"]);
+
+        assert_eq!(complete("plain words", cwd), None);
+    }
+
+    #[test]
+    fn no_color_leaves_diffs_and_status_lines_plain() {
+        assert!(colors_enabled(ColorChoice::Auto, false, true));
+        assert!(!colors_enabled(ColorChoice::Auto, true, true));
+        assert!(!colors_enabled(ColorChoice::Auto, false, false));
+        assert!(colors_enabled(ColorChoice::Always, true, false));
+
+        console::set_colors_enabled(colors_enabled(ColorChoice::Auto, true, true));
+        let diff = crate::diff::unified_colored("old\n", "new\n", "a.txt");
+        let status = console::style("Applied:").green().to_string();
+
+        assert!(diff.contains("+new"), "{diff}");
+        assert!(!diff.contains('\x1b'), "{diff:?}");
+        assert_eq!(status, "Applied:");
+    }
+}